@@ -0,0 +1,198 @@
+//! Compile-time checked expressions for rex.
+//!
+//! ```text
+//! use rex_macros::regex;
+//!
+//! let re: &'static rex::regex::RegEx = regex!("(\\d+)-(\\d+)");
+//! ```
+//!
+//! The expression is parsed while the crate using it builds, so a mistake in
+//! it is a compile error pointing at the literal. It's compiled into a
+//! `RegEx` the first time it's used, and kept for as long as the program
+//! runs.
+
+use proc_macro::Delimiter;
+use proc_macro::Group;
+use proc_macro::Ident;
+use proc_macro::Literal;
+use proc_macro::Punct;
+use proc_macro::Spacing;
+use proc_macro::Span;
+use proc_macro::TokenStream;
+use proc_macro::TokenTree;
+use rex::builder::RegExBuilder;
+use rex::diagnostic::Diagnostic;
+
+const USAGE: &str = "regex! takes one string literal";
+
+/// Check a string literal is a valid expression, and expand to a
+/// `&'static RegEx` for it that's compiled the first time it's used.
+///
+/// An invalid expression is a compile error, and nothing else:
+///
+/// ```compile_fail
+/// let re: &'static rex::regex::RegEx = rex_macros::regex!("a(b");
+/// ```
+#[proc_macro]
+pub fn regex(input: TokenStream) -> TokenStream {
+  let (literal, span) = match literal(input) {
+    Some(literal) => literal,
+    None => {
+      return errors(&[USAGE], Span::call_site());
+    },
+  };
+  let expr = match unescape(&literal) {
+    Some(expr) => expr,
+    None => {
+      return errors(&[USAGE], span);
+    },
+  };
+
+  // built the same way as by `RegEx::new`, so it can't fail when it's used
+  // (if it parses, what's wrong is with how it compiled)
+  let builder = RegExBuilder::new(&expr);
+  if let Err(error) = builder.build().map(|_| ()) {
+    let mut found = builder.check();
+    if found.is_empty() {
+      found.push(error);
+    }
+    let reports: Vec<String> = found.iter().map(|e| {
+      let report = Diagnostic::new(&expr, e).to_string();
+      // the compiler says it's an error itself
+      let report = report.strip_prefix("error: ").unwrap_or(&report);
+      return String::from(report.trim_end());
+    }).collect();
+    // the whole literal is blamed, as pointing inside it (`subspan`) isn't
+    // stable yet
+    let reports: Vec<&str> = reports.iter().map(|r| r.as_str()).collect();
+    return errors(&reports, span);
+  }
+
+  let code = format!(
+    "{{
+       static RE: ::std::sync::LazyLock<::rex::regex::RegEx> =
+         ::std::sync::LazyLock::new(|| {{
+           ::rex::regex::RegEx::new({:?}).unwrap()
+         }});
+       &*RE
+     }}", expr);
+  return code.parse().unwrap();
+}
+
+// the only token of the input if it's a literal, and where it is
+// (literals passed on by other macros come wrapped in invisible groups)
+fn literal(input: TokenStream) -> Option<(String, Span)> {
+  let mut tokens = input.into_iter();
+  let token = tokens.next()?;
+  if tokens.next().is_some() {
+    return None;
+  }
+
+  return match token {
+    TokenTree::Literal(l) => Some((l.to_string(), l.span())),
+    TokenTree::Group(g) if g.delimiter() == Delimiter::None => {
+      literal(g.stream())
+    },
+    _ => None,
+  };
+}
+
+// `{ compile_error!("message"); ... loop {} }` for each message, blamed on
+// span, which diverges so that it fits wherever the expression is used
+// (rather than being `()`, which would be another error)
+fn errors(messages: &[&str], span: Span) -> TokenStream {
+  let mut tokens: Vec<TokenTree> = vec![];
+  for m in messages {
+    let mut message = Literal::string(m);
+    message.set_span(span);
+    let mut args = Group::new(Delimiter::Parenthesis,
+                              TokenTree::Literal(message).into());
+    args.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut semi = Punct::new(';', Spacing::Alone);
+    semi.set_span(span);
+
+    tokens.push(Ident::new("compile_error", span).into());
+    tokens.push(bang.into());
+    tokens.push(args.into());
+    tokens.push(semi.into());
+  }
+  let mut body = Group::new(Delimiter::Brace, TokenStream::new());
+  body.set_span(span);
+  tokens.push(Ident::new("loop", span).into());
+  tokens.push(body.into());
+  let mut block = Group::new(Delimiter::Brace, tokens.into_iter().collect());
+  block.set_span(span);
+  return TokenTree::Group(block).into();
+}
+
+// the string a literal as written in the source (quotes and all) stands
+// for, None if it isn't a string literal
+fn unescape(literal: &str) -> Option<String> {
+  // raw strings are taken as they are
+  if let Some(raw) = literal.strip_prefix('r') {
+    let hashes = raw.len() - raw.trim_start_matches('#').len();
+    let fence = "#".repeat(hashes);
+    let text = raw.strip_prefix(&fence)?
+                  .strip_suffix(&fence)?
+                  .strip_prefix('"')?
+                  .strip_suffix('"')?;
+    return Some(String::from(text));
+  }
+
+  let text = literal.strip_prefix('"')?.strip_suffix('"')?;
+  let mut chars = text.chars().peekable();
+  let mut unescaped = String::new();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      unescaped.push(c);
+      continue;
+    }
+    match chars.next()? {
+      'n' => unescaped.push('\n'),
+      'r' => unescaped.push('\r'),
+      't' => unescaped.push('\t'),
+      '0' => unescaped.push('\0'),
+      '\\' => unescaped.push('\\'),
+      '\'' => unescaped.push('\''),
+      '"' => unescaped.push('"'),
+      'x' => {
+        let hex: String = chars.by_ref().take(2).collect();
+        unescaped.push(char::from(u8::from_str_radix(&hex, 16).ok()?));
+      },
+      'u' => {
+        let code: String = chars.by_ref()
+                                .skip(1) // '{'
+                                .take_while(|c| *c != '}')
+                                .filter(|c| *c != '_')
+                                .collect();
+        let code = u32::from_str_radix(&code, 16).ok()?;
+        unescaped.push(char::from_u32(code)?);
+      },
+      // a line continuation skips the newline and the next line's indent
+      '\n' => {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+      },
+      _ => { return None; },
+    }
+  }
+  return Some(unescaped);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn unescape_strings() {
+    assert_eq!(unescape("\"a\\\\d+\""), Some(String::from("a\\d+")));
+    assert_eq!(unescape("\"\\t\\x41\\u{e9}\\\"\""),
+               Some(String::from("\tA\u{e9}\"")));
+    assert_eq!(unescape("\"a\\\n    b\""), Some(String::from("ab")));
+    assert_eq!(unescape("r\"\\d\""), Some(String::from("\\d")));
+    assert_eq!(unescape("r#\"\"\\d\"#"), Some(String::from("\"\\d")));
+    assert_eq!(unescape("b\"a\""), None);
+    assert_eq!(unescape("'a'"), None);
+  }
+}
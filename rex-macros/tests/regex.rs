@@ -0,0 +1,28 @@
+use rex::regex::RegEx;
+use rex_macros::regex;
+
+fn dates() -> &'static RegEx {
+  return regex!(r"(\d\d\d\d)-(\d\d)-(\d\d)");
+}
+
+#[test]
+fn regex_matches() {
+  let caps = dates().captures("on 2024-02-29").unwrap();
+  assert_eq!(&caps[1], "2024");
+  assert_eq!(&caps[3], "29");
+  assert!(regex!("a\\w+").is_match("xabc"));
+}
+
+#[test]
+fn regex_is_compiled_once() {
+  assert!(std::ptr::eq(dates(), dates()));
+}
+
+macro_rules! passed_on {
+  ($expr:literal) => { regex!($expr) };
+}
+
+#[test]
+fn regex_from_other_macros() {
+  assert!(passed_on!("x+").is_match("xx"));
+}
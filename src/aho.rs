@@ -0,0 +1,325 @@
+// an Aho-Corasick automaton, for finding any of a set of literals at once
+//
+// the literals are put in a trie, and every state gets a failure link to the
+// longest suffix of its path that is also in the trie, so the haystack only
+// has to be read once however many literals there are
+
+// which match is found when several begin at the same place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchKind {
+  // the literal that was given first (like a backtracking regex's `|`)
+  LeftmostFirst,
+  // the longest literal, then the one given first (like a tree's Union)
+  LeftmostLongest,
+}
+
+// a match of literal number pattern at start..end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LiteralMatch {
+  pub pattern: usize,
+  pub start: usize,
+  pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+  next: Vec<(u8, usize)>, // sorted by byte
+  fail: usize,
+  own: Vec<usize>,        // literals that are the path to this state
+  matches: Vec<usize>,    // literals that end here, own or through fail
+}
+
+impl State {
+  fn new() -> Self {
+    return State { next: vec![], fail: 0, own: vec![], matches: vec![] };
+  }
+
+  fn goto(&self, b: u8) -> Option<usize> {
+    let i = self.next.binary_search_by_key(&b, |(c, _)| *c).ok()?;
+    return Some(self.next[i].1);
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AhoCorasick {
+  kind: MatchKind,
+  states: Vec<State>, // the root is 0
+  lens: Vec<usize>,   // length of each literal
+  max_len: usize,
+}
+
+impl AhoCorasick {
+  pub fn new(patterns: &[Vec<u8>], kind: MatchKind) -> Self {
+    let mut states = vec![State::new()];
+
+    // build the trie
+    for (p, pattern) in patterns.iter().enumerate() {
+      let mut s = 0;
+      for b in pattern {
+        s = match states[s].goto(*b) {
+          Some(t) => t,
+          None => {
+            let t = states.len();
+            states.push(State::new());
+            let next = &mut states[s].next;
+            let i = next.partition_point(|(c, _)| c < b);
+            next.insert(i, (*b, t));
+            t
+          },
+        };
+      }
+      states[s].own.push(p);
+    }
+
+    // link each state to the longest proper suffix of its path that is also
+    // in the trie, breadth first so that shorter paths are linked already
+    let mut queue = std::collections::VecDeque::from([0]);
+    while let Some(s) = queue.pop_front() {
+      // the root's own literal is empty, which is found separately
+      if s != 0 {
+        let fail = states[s].fail;
+        let mut matches = states[s].own.clone();
+        matches.extend(states[fail].matches.iter().copied());
+        states[s].matches = matches;
+      }
+
+      for i in 0..states[s].next.len() {
+        let (b, t) = states[s].next[i];
+        states[t].fail = if s == 0 { 0 } else { Self::link(&states, s, b) };
+        queue.push_back(t);
+      }
+    }
+
+    let lens: Vec<usize> = patterns.iter().map(|p| p.len()).collect();
+    let max_len = lens.iter().copied().max().unwrap_or(0);
+    return AhoCorasick { kind, states, lens, max_len };
+  }
+
+  // where a child of s along b should fail to
+  fn link(states: &[State], s: usize, b: u8) -> usize {
+    let mut f = states[s].fail;
+    loop {
+      if let Some(t) = states[f].goto(b) {
+        return t;
+      }
+      if f == 0 {
+        return 0;
+      }
+      f = states[f].fail;
+    }
+  }
+
+  // the state after s when reading b
+  fn step(&self, mut s: usize, b: u8) -> usize {
+    loop {
+      if let Some(t) = self.states[s].goto(b) {
+        return t;
+      }
+      if s == 0 {
+        return 0;
+      }
+      s = self.states[s].fail;
+    }
+  }
+
+  // whether m should be reported instead of best
+  fn better(&self, m: &LiteralMatch, best: &Option<LiteralMatch>) -> bool {
+    let best = match best {
+      Some(best) => best,
+      None => { return true; },
+    };
+    if m.start != best.start {
+      return m.start < best.start;
+    }
+    return match self.kind {
+      MatchKind::LeftmostFirst => m.pattern < best.pattern,
+      MatchKind::LeftmostLongest => {
+        m.end > best.end || (m.end == best.end && m.pattern < best.pattern)
+      },
+    };
+  }
+
+  // the leftmost match that begins at or after start
+  pub fn find(&self, haystack: &[u8], start: usize) -> Option<LiteralMatch> {
+    if start > haystack.len() {
+      return None;
+    }
+
+    let mut best = None;
+    let mut s = 0;
+    // an empty literal matches straight away
+    for p in &self.states[0].own {
+      self.keep(LiteralMatch { pattern: *p, start, end: start }, &mut best);
+    }
+
+    for j in start..haystack.len() {
+      // nothing that ends from here on can begin early enough to beat best
+      if best.is_some_and(|m: LiteralMatch| j >= m.start + self.max_len) {
+        break;
+      }
+
+      s = self.step(s, haystack[j]);
+      for p in &self.states[s].matches {
+        let m = LiteralMatch { pattern: *p, start: j + 1 - self.lens[*p],
+                               end: j + 1 };
+        self.keep(m, &mut best);
+      }
+    }
+    return best;
+  }
+
+  fn keep(&self, m: LiteralMatch, best: &mut Option<LiteralMatch>) {
+    if self.better(&m, best) {
+      *best = Some(m);
+    }
+  }
+
+  // the match that begins exactly at start, if there is one
+  pub fn find_at(&self, haystack: &[u8], start: usize)
+    -> Option<LiteralMatch> {
+    let mut best = None;
+    let mut s = 0;
+    let mut end = start;
+    loop {
+      // only the trie is followed, so every literal here began at start
+      for p in &self.states[s].own {
+        self.keep(LiteralMatch { pattern: *p, start, end }, &mut best);
+      }
+
+      let b = match haystack.get(end) {
+        Some(b) => *b,
+        None => { break; },
+      };
+      s = match self.states[s].goto(b) {
+        Some(t) => t,
+        None => { break; },
+      };
+      end += 1;
+    }
+    return best;
+  }
+
+  // every literal that begins exactly at start, in the order they were
+  // given (whatever the kind), put in found
+  pub fn all_at(&self, haystack: &[u8], start: usize,
+                found: &mut Vec<LiteralMatch>) {
+    found.clear();
+    let mut s = 0;
+    let mut end = start;
+    loop {
+      for p in &self.states[s].own {
+        found.push(LiteralMatch { pattern: *p, start, end });
+      }
+
+      let b = match haystack.get(end) {
+        Some(b) => *b,
+        None => { break; },
+      };
+      s = match self.states[s].goto(b) {
+        Some(t) => t,
+        None => { break; },
+      };
+      end += 1;
+    }
+    found.sort_by_key(|m| m.pattern);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn automaton(patterns: &[&str], kind: MatchKind) -> AhoCorasick {
+    let patterns: Vec<Vec<u8>> = patterns.iter()
+                                         .map(|p| p.as_bytes().to_vec())
+                                         .collect();
+    return AhoCorasick::new(&patterns, kind);
+  }
+
+  // every non-overlapping match, as (pattern, start, end)
+  fn find_all(patterns: &[&str], kind: MatchKind, haystack: &str)
+    -> Vec<(usize, usize, usize)> {
+    let ac = automaton(patterns, kind);
+    let mut found = vec![];
+    let mut start = 0;
+    while let Some(m) = ac.find(haystack.as_bytes(), start) {
+      found.push((m.pattern, m.start, m.end));
+      start = if m.end > m.start { m.end } else { m.end + 1 };
+    }
+    return found;
+  }
+
+  #[test]
+  fn find_words() {
+    let words = ["he", "she", "his", "hers"];
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "ushers his"),
+               vec![(1, 1, 4), (2, 7, 10)]);
+    assert_eq!(find_all(&words, MatchKind::LeftmostLongest, "ushers his"),
+               vec![(1, 1, 4), (2, 7, 10)]);
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "hers"),
+               vec![(0, 0, 2)]);
+    assert_eq!(find_all(&words, MatchKind::LeftmostLongest, "hers"),
+               vec![(3, 0, 4)]);
+  }
+
+  #[test]
+  fn find_leftmost_not_first_to_end() {
+    // "bcd" ends first, but "abcde" begins first
+    let words = ["bcd", "abcde"];
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "xabcdex"),
+               vec![(1, 1, 6)]);
+    let words = ["abcd", "bc"];
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "abcx"),
+               vec![(1, 1, 3)]);
+  }
+
+  #[test]
+  fn find_kinds_differ() {
+    let words = ["a", "ab", "abc"];
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "abcab"),
+               vec![(0, 0, 1), (0, 3, 4)]);
+    assert_eq!(find_all(&words, MatchKind::LeftmostLongest, "abcab"),
+               vec![(2, 0, 3), (1, 3, 5)]);
+  }
+
+  #[test]
+  fn find_duplicates_and_empty() {
+    assert_eq!(find_all(&["x", "x"], MatchKind::LeftmostFirst, "xx"),
+               vec![(0, 0, 1), (0, 1, 2)]);
+    assert_eq!(find_all(&["", "a"], MatchKind::LeftmostFirst, "ab"),
+               vec![(0, 0, 0), (0, 1, 1), (0, 2, 2)]);
+    assert_eq!(find_all(&["", "a"], MatchKind::LeftmostLongest, "ab"),
+               vec![(1, 0, 1), (0, 1, 1), (0, 2, 2)]);
+    assert_eq!(find_all(&[], MatchKind::LeftmostFirst, "ab"), vec![]);
+  }
+
+  #[test]
+  fn find_many_words() {
+    let words: Vec<String> = (0..2000).map(|i| format!("w{}x", i)).collect();
+    let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+    assert_eq!(find_all(&words, MatchKind::LeftmostFirst, "w12 w1999x w7x"),
+               vec![(1999, 4, 10), (7, 11, 14)]);
+  }
+
+  #[test]
+  fn find_at_anchored() {
+    let ac = automaton(&["a", "abc", "ab"], MatchKind::LeftmostLongest);
+    let m = ac.find_at(b"xabcd", 1).unwrap();
+    assert_eq!((m.pattern, m.end), (1, 4));
+    assert!(ac.find_at(b"xabcd", 0).is_none());
+    assert!(ac.find_at(b"xabcd", 9).is_none());
+
+    let ac = automaton(&["ab", "abc", "a"], MatchKind::LeftmostFirst);
+    let m = ac.find_at(b"abcd", 0).unwrap();
+    assert_eq!((m.pattern, m.end), (0, 2));
+  }
+
+  #[test]
+  fn all_at_in_given_order() {
+    let ac = automaton(&["ab", "abc", "a", "b"], MatchKind::LeftmostLongest);
+    let mut found = vec![];
+    ac.all_at(b"xabcd", 1, &mut found);
+    let found: Vec<_> = found.iter().map(|m| (m.pattern, m.end)).collect();
+    assert_eq!(found, vec![(0, 3), (1, 4), (2, 2)]);
+  }
+}
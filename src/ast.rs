@@ -0,0 +1,269 @@
+use crate::aho::AhoCorasick;
+use crate::error::Error;
+use crate::error::Span;
+use crate::parser::Parser;
+use std::sync::Arc;
+
+/// The kind of a `TreeNode`, which decides which of its fields are used.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeType {
+  /// Stands in for a part of the expression that couldn't be parsed.
+  Error,
+  /// A placeholder for nothing, these are dropped as the tree is built.
+  Empty,
+
+  /// A literal string, `image` holds its characters (or bytes).
+  Word,
+  /// One of a set of characters: `[a-z]`, `\d`, `.`; `ranges` holds the set.
+  Charset,
+  /// Any one of its `children`: `a|b`.
+  Union,
+  /// Its only child, repeated between `repeats.min` and `repeats.max` times.
+  Star,
+  /// Each of its `children` in order, without capturing them.
+  Group,
+  /// Each of its `children` in order, captured as group number `group`.
+  MatchGroup,
+  /// Matches nothing, only at the places `anchor` says: `^`, `$`.
+  Anchor,
+}
+
+/// Where an Anchor matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+  /// `^`: the start of the haystack.
+  Start,
+  /// `$`: the end of the haystack.
+  End,
+  /// `^` in multi-line mode: the start of the haystack or of a line.
+  LineStart,
+  /// `$` in multi-line mode: the end of the haystack or of a line.
+  LineEnd,
+}
+
+impl Anchor {
+  // whether the anchor matches at i, lines end at '\n' whether the haystack
+  // is a str or bytes
+  pub(crate) fn matches(self, haystack: &[u8], i: usize) -> bool {
+    return match self {
+      Anchor::Start => i == 0,
+      Anchor::End => i == haystack.len(),
+      Anchor::LineStart => i == 0 || haystack.get(i - 1) == Some(&b'\n'),
+      Anchor::LineEnd => {
+        i == haystack.len() || haystack.get(i) == Some(&b'\n')
+      },
+    };
+  }
+}
+
+/// How many times a Star repeats its child.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounds {
+  pub min: u32,
+  /// 0 means there is no maximum.
+  pub max: u32,
+}
+
+/// A range of characters (or bytes) in a Charset, `min..=max`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharRange {
+  pub min: u32,
+  pub max: u32,
+  /// Whether the range matches everything *outside* `min..=max`.
+  pub negate: bool,
+}
+
+impl CharRange {
+  pub fn new(min: u32, max: u32, negate: bool) -> Self {
+    return CharRange { min, max, negate };
+  }
+
+  // u is a code point, or a byte value when matching without unicode
+  pub fn includes(&self, u: u32) -> bool {
+    if !self.negate { u >= self.min && u <= self.max }
+    else { u < self.min || u > self.max }
+  }
+}
+
+// the highest unit there is (a char, bytes are lower)
+pub(crate) const MAX_UNIT: u32 = 0x10FFFF;
+
+// the units some ranges include, as sorted ranges that don't touch
+pub(crate) fn unit_set(ranges: &[CharRange]) -> Vec<(u32, u32)> {
+  let mut set = vec![];
+  for r in ranges {
+    if !r.negate {
+      set.push((r.min, r.max));
+      continue;
+    }
+    // everything outside the range
+    if r.min > 0 {
+      set.push((0, r.min - 1));
+    }
+    if r.max < MAX_UNIT {
+      set.push((r.max + 1, MAX_UNIT));
+    }
+  }
+  set.sort();
+
+  let mut merged: Vec<(u32, u32)> = vec![];
+  for (min, max) in set {
+    match merged.last_mut() {
+      Some(last) if min <= last.1.saturating_add(1) => {
+        last.1 = last.1.max(max);
+      },
+      _ => merged.push((min, max)),
+    }
+  }
+  return merged;
+}
+
+/// A node of a parsed expression.
+///
+/// The root of a tree is always a Group. Which fields matter depends on
+/// `n_type`, the rest are left empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeNode {
+  pub n_type: NodeType,
+  pub children: Vec<TreeNode>,
+  pub image: Vec<char>,         // used by Words
+  pub repeats: Bounds,          // used by Star-likes (?, +, etc.)
+  pub ranges: Vec<CharRange>,   // used by Charsets
+  pub unicode: bool,            // false if Words/Charsets match single bytes
+  pub group: usize,             // used by MatchGroups (numbered from 1)
+  pub name: Option<String>,     // used by named MatchGroups
+  pub anchor: Anchor,           // used by Anchors
+  pub span: Span,               // characters of the expression it came from
+  // used by Unions of many literals, see `literal::attach`
+  pub(crate) automaton: Option<Arc<AhoCorasick>>,
+}
+
+impl TreeNode {
+  pub fn new(n_type: NodeType) -> Self {
+    return TreeNode {
+      n_type,
+      children: vec![],
+      image: vec![],
+      repeats: Bounds { min: 0, max: 0 },
+      ranges: vec![],
+      unicode: true,
+      group: 0,
+      name: None,
+      anchor: Anchor::Start,
+      span: Span::default(),
+      automaton: None,
+    };
+  }
+
+  /// Visit this node and everything below it, depth first.
+  pub fn visit<V: Visitor>(&self, visitor: &mut V) {
+    visitor.visit_pre(self);
+    for n in &self.children {
+      n.visit(visitor);
+    }
+    visitor.visit_post(self);
+  }
+
+  /// Visit this node and everything below it, depth first, with the chance
+  /// to change each one.
+  pub fn visit_mut<V: VisitorMut>(&mut self, visitor: &mut V) {
+    visitor.visit_pre(self);
+    for n in &mut self.children {
+      n.visit_mut(visitor);
+    }
+    visitor.visit_post(self);
+  }
+}
+
+/// Something that walks over a tree, see `TreeNode::visit`.
+pub trait Visitor {
+  /// Called for a node before any of its children.
+  fn visit_pre(&mut self, _node: &TreeNode) {}
+  /// Called for a node after all of its children.
+  fn visit_post(&mut self, _node: &TreeNode) {}
+}
+
+/// Something that walks over a tree and may change it, see
+/// `TreeNode::visit_mut`.
+pub trait VisitorMut {
+  /// Called for a node before any of its children, changes to its children
+  /// are visited.
+  fn visit_pre(&mut self, _node: &mut TreeNode) {}
+  /// Called for a node after all of its children.
+  fn visit_post(&mut self, _node: &mut TreeNode) {}
+}
+
+/// Parse an expression, or return every problem with it.
+pub fn parse(expr: &str) -> Result<TreeNode, Vec<Error>> {
+  return Parser::new(expr).parse();
+}
+
+/// Parse an expression for `bytes::RegEx`, or return every problem with it.
+pub fn parse_bytes(expr: &str) -> Result<TreeNode, Vec<Error>> {
+  return Parser::new_bytes(expr).parse();
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Default)]
+  struct Order {
+    pre: Vec<String>,
+    post: Vec<String>,
+  }
+
+  impl Visitor for Order {
+    fn visit_pre(&mut self, node: &TreeNode) {
+      self.pre.push(format!("{:?}", node.n_type));
+    }
+
+    fn visit_post(&mut self, node: &TreeNode) {
+      self.post.push(format!("{:?}", node.n_type));
+    }
+  }
+
+  struct Upper;
+
+  impl VisitorMut for Upper {
+    fn visit_pre(&mut self, node: &mut TreeNode) {
+      for c in &mut node.image {
+        *c = c.to_ascii_uppercase();
+      }
+    }
+  }
+
+  #[test]
+  fn visit_order() {
+    let tree = parse("(a|b)*c").unwrap();
+    let mut order = Order::default();
+    tree.visit(&mut order);
+    assert_eq!(order.pre, vec!["Group", "Group", "Star", "MatchGroup", "Union",
+                               "Word", "Word", "Word"]);
+    assert_eq!(order.post, vec!["Word", "Word", "Union", "MatchGroup", "Star",
+                                "Word", "Group", "Group"]);
+  }
+
+  #[test]
+  fn visit_mut_changes_tree() {
+    let mut tree = parse("ab(c)").unwrap();
+    tree.visit_mut(&mut Upper);
+    assert_eq!(tree, parse("AB(C)").unwrap());
+  }
+
+  #[test]
+  fn parse_named_groups_and_spans() {
+    let tree = parse("x(?<n>y)").unwrap();
+    let group = &tree.children[0].children[1];
+    assert_eq!(group.n_type, NodeType::MatchGroup);
+    assert_eq!(group.name.as_deref(), Some("n"));
+    assert_eq!(group.span, Span::new(1, 8));
+  }
+
+  #[test]
+  fn parse_escaped_dash_is_literal() {
+    let tree = parse("[\\w\\-~]").unwrap();
+    assert_eq!(tree.children[0].ranges.len(), 6);
+    assert!(parse("[\\w-~]").is_err());
+  }
+}
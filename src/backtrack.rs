@@ -0,0 +1,360 @@
+use crate::aho::LiteralMatch;
+use crate::limits::Budget;
+use crate::literal::Prefilter;
+use crate::program::Inst;
+use crate::program::Program;
+use crate::regex::Haystack;
+use crate::regex::MatchPositions;
+
+// the most (instruction, position) pairs remembered, as bits (256 KiB)
+const MAX_VISITED: usize = 256 * 1024 * 8;
+
+// what's left to do when backtracking
+enum Job {
+  // try to match from instruction pc at position i
+  Explore { pc: usize, i: usize },
+  // put a capture slot back the way it was before a path that failed
+  RestoreSlot { slot: usize, old: Option<usize> },
+  // the same for the position a loop was last entered at
+  RestoreLoop { pc: usize, old: Option<usize> },
+}
+
+// the scratch space of a search, which can be used again by the next one
+// (of any program) to save allocating it
+#[derive(Default)]
+pub struct Cache {
+  visited: Vec<u64>,
+  slots: Vec<Option<usize>>,
+  loops: Vec<Option<usize>>,
+  jobs: Vec<Job>,
+  literals: Vec<LiteralMatch>,
+}
+
+impl Cache {
+  pub fn new() -> Self {
+    return Cache::default();
+  }
+}
+
+// a matcher that tries the ways through a program one at a time, preferring
+// the first of each Split like a backtracking regex does (so the first
+// branch of a union that leads to a match wins, not the longest one)
+//
+// every (instruction, position) pair that has been tried is remembered,
+// since trying it again would fail again, which keeps a search to
+// O(instructions * haystack length) however ambiguous the pattern is
+pub struct Backtracker<'p> {
+  prog: &'p Program,
+  group_count: usize,
+}
+
+impl<'p> Backtracker<'p> {
+  pub fn new(prog: &'p Program, group_count: usize) -> Self {
+    return Backtracker { prog, group_count };
+  }
+
+  // find the leftmost-first match that starts at or after start
+  // also gives whether the tried pairs could all be remembered, if not the
+  // search may have taken exponential time
+  // every instruction tried counts against the budget, once it runs out
+  // nothing is found
+  pub fn search<H: Haystack + ?Sized>(&self, haystack: &H,
+                                      prefilter: Option<&Prefilter>,
+                                      start: usize, budget: &Budget,
+                                      cache: &mut Cache)
+    -> (Option<MatchPositions>, bool) {
+    let mut search = Search::new(self.prog, self.group_count, haystack.len(),
+                                 budget, cache);
+    let memoized = search.memoized;
+
+    let mut start = start;
+    while start <= haystack.len() {
+      if let Some(p) = prefilter {
+        start = match p.find(haystack.bytes(), start) {
+          Some(start) => start,
+          None => { break; },
+        };
+      }
+
+      // what failed from an earlier start fails from this one too, so the
+      // visited pairs are kept between starts
+      if let Some(m) = search.run(haystack, start) {
+        return (Some(m), memoized);
+      }
+      start = haystack.next_start(start);
+    }
+    return (None, memoized);
+  }
+
+  // the first way to match the whole haystack, backtracking out of matches
+  // that end too early
+  pub fn full_match<H: Haystack + ?Sized>(&self, haystack: &H,
+                                          budget: &Budget, cache: &mut Cache)
+    -> Option<MatchPositions> {
+    let mut search = Search::new(self.prog, self.group_count, haystack.len(),
+                                 budget, cache);
+    search.full = true;
+    return search.run(haystack, 0);
+  }
+}
+
+struct Search<'p> {
+  prog: &'p Program,
+  group_count: usize,
+  budget: &'p Budget,
+  full: bool, // only matches that end at the end of the haystack count
+  // whether there's a bit in visited for each (instruction, position) pair,
+  // not if there are too many
+  memoized: bool,
+  positions: usize,
+  // visited, the capture slots, where each loop's Split was last reached
+  // (without memoisation this is what stops a loop that doesn't read
+  // anything from going on forever), the jobs left and the literals found
+  // by a union's automaton
+  cache: &'p mut Cache,
+}
+
+impl<'p> Search<'p> {
+  fn new(prog: &'p Program, group_count: usize, len: usize,
+         budget: &'p Budget, cache: &'p mut Cache) -> Self {
+    let positions = len + 1;
+    let bits = prog.insts.len().checked_mul(positions)
+                               .filter(|bits| *bits <= MAX_VISITED);
+    cache.visited.clear();
+    cache.visited.resize(bits.unwrap_or(0).div_ceil(64), 0);
+    cache.slots.clear();
+    cache.slots.resize(group_count * 2, None);
+    cache.loops.clear();
+    cache.loops.resize(prog.insts.len(), None);
+    return Search {
+      prog,
+      group_count,
+      budget,
+      full: false,
+      memoized: bits.is_some(),
+      positions,
+      cache,
+    };
+  }
+
+  // whether (pc, i) was tried already, marking it tried if not
+  fn seen(&mut self, pc: usize, i: usize) -> bool {
+    if !self.memoized {
+      return false;
+    }
+    let visited = &mut self.cache.visited;
+    let bit = pc * self.positions + i;
+    let (word, mask) = (bit / 64, 1 << (bit % 64));
+    if visited[word] & mask != 0 {
+      return true;
+    }
+    visited[word] |= mask;
+    return false;
+  }
+
+  // match from the start of the program at exactly start
+  fn run<H: Haystack + ?Sized>(&mut self, haystack: &H, start: usize)
+    -> Option<MatchPositions> {
+    self.cache.slots.iter_mut().for_each(|s| *s = None);
+    self.cache.loops.iter_mut().for_each(|l| *l = None);
+    self.cache.jobs.clear();
+    self.cache.jobs.push(Job::Explore { pc: self.prog.start, i: start });
+
+    while let Some(job) = self.cache.jobs.pop() {
+      match job {
+        Job::Explore { pc, i } => {
+          if let Some(end) = self.explore(haystack, pc, i) {
+            return Some(self.positions(start, end));
+          }
+        },
+        Job::RestoreSlot { slot, old } => self.cache.slots[slot] = old,
+        Job::RestoreLoop { pc, old } => self.cache.loops[pc] = old,
+      }
+    }
+    return None;
+  }
+
+  // follow the program from pc until it matches (giving the end) or fails,
+  // leaving the second choice of every Split on the way to be tried later
+  fn explore<H: Haystack + ?Sized>(&mut self, haystack: &H, mut pc: usize,
+                                   mut i: usize) -> Option<usize> {
+    loop {
+      if self.seen(pc, i) || !self.budget.step() {
+        return None;
+      }
+
+      match &self.prog.insts[pc] {
+        Inst::Char { c, unicode } => {
+          match haystack.unit_at(i, *unicode) {
+            Some((u, next)) if u == *c => { i = next; },
+            _ => { return None; },
+          }
+          pc += 1;
+        },
+        Inst::Ranges { ranges, unicode } => {
+          match haystack.unit_at(i, *unicode) {
+            Some((u, next)) if ranges.iter().any(|r| r.includes(u)) => {
+              i = next;
+            },
+            _ => { return None; },
+          }
+          pc += 1;
+        },
+        Inst::Split(..) if self.prog.literals.contains_key(&pc) => {
+          // a union of many literals goes to the end of every literal that
+          // is here at once, still trying them in the order of its branches
+          let literals = &self.prog.literals[&pc];
+          let found = &mut self.cache.literals;
+          literals.automaton.all_at(haystack.bytes(), i, found);
+          for m in found.iter().skip(1).rev() {
+            self.cache.jobs.push(Job::Explore { pc: literals.end, i: m.end });
+          }
+          i = match found.first() {
+            Some(m) => m.end,
+            None => { return None; },
+          };
+          pc = literals.end;
+        },
+        Inst::Split(a, b) => {
+          // going round a loop again without reading anything can't help
+          if self.cache.loops[pc] == Some(i) {
+            return None;
+          }
+          let old = self.cache.loops[pc];
+          self.cache.jobs.push(Job::RestoreLoop { pc, old });
+          self.cache.loops[pc] = Some(i);
+          self.cache.jobs.push(Job::Explore { pc: *b, i });
+          pc = *a;
+        },
+        Inst::Assert(anchor) => {
+          if !anchor.matches(haystack.bytes(), i) {
+            return None;
+          }
+          pc += 1;
+        },
+        Inst::Jmp(to) => { pc = *to; },
+        Inst::Save(slot) => {
+          if *slot < self.cache.slots.len() {
+            self.cache.jobs.push(Job::RestoreSlot {
+              slot: *slot,
+              old: self.cache.slots[*slot],
+            });
+            self.cache.slots[*slot] = Some(i);
+          }
+          pc += 1;
+        },
+        Inst::Match(_) if self.full && i < haystack.len() => {
+          return None;
+        },
+        Inst::Match(_) => { return Some(i); },
+        Inst::Fail => { return None; },
+      }
+    }
+  }
+
+  fn positions(&self, start: usize, end: usize) -> MatchPositions {
+    let mut m = MatchPositions::new(start, end, self.group_count);
+    m.groups[0] = Some((start, end));
+    let slots = &self.cache.slots;
+    for g in 1..self.group_count {
+      if let (Some(s), Some(e)) = (slots[g * 2], slots[g * 2 + 1]) {
+        m.groups[g] = Some((s, e));
+      }
+    }
+    return m;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::builder::Semantics;
+  use crate::literal;
+  use crate::optimize::optimize;
+  use super::*;
+
+  type Groups = Vec<Option<(usize, usize)>>;
+
+  // every group of the leftmost-first match, and whether it was memoized
+  fn search(expr: &str, haystack: &str) -> (Option<Groups>, bool) {
+    let tree = optimize(ast::parse(expr).unwrap());
+    let groups = expr.matches('(').count() + 1;
+    let prog = Program::compile_many(&[&tree]);
+    let (m, memoized) = Backtracker::new(&prog, groups)
+                                    .search(haystack, None, 0,
+                                            &Budget::unlimited(),
+                                            &mut Cache::new());
+    return (m.map(|m| m.groups), memoized);
+  }
+
+  #[test]
+  fn backtrack_first_branch_wins() {
+    assert_eq!(search("(a|ab)(c|bcd)", "abcd").0,
+               Some(vec![Some((0, 4)), Some((0, 1)), Some((1, 4))]));
+    assert_eq!(search("a*a", "aaa").0, Some(vec![Some((0, 3))]));
+    assert_eq!(search("x(a|b)*c", "zxabac").0,
+               Some(vec![Some((1, 6)), Some((4, 5))]));
+    assert_eq!(search("x(a|b)*c", "xabad").0, None);
+  }
+
+  #[test]
+  fn backtrack_full_match() {
+    let tree = optimize(ast::parse("(a|ab)(c|bcd)(d*)").unwrap());
+    let prog = Program::compile_many(&[&tree]);
+    let backtracker = Backtracker::new(&prog, 4);
+    let mut cache = Cache::new();
+    let m = backtracker.full_match("abcd", &Budget::unlimited(), &mut cache)
+                       .unwrap();
+    assert_eq!(m.groups, vec![Some((0, 4)), Some((0, 1)), Some((1, 4)),
+                              Some((4, 4))]);
+    assert!(backtracker.full_match("abcdx", &Budget::unlimited(), &mut cache)
+                       .is_none());
+  }
+
+  #[test]
+  fn backtrack_empty_loops() {
+    assert_eq!(search("(a*)*b", "aab").0,
+               Some(vec![Some((0, 3)), Some((0, 2))]));
+    // an iteration that reads nothing is given up on
+    assert_eq!(search("(|a)*", "aa").0,
+               Some(vec![Some((0, 2)), Some((1, 2))]));
+  }
+
+  #[test]
+  fn backtrack_literal_unions() {
+    let mut tree = optimize(ast::parse("(ab|a|abc)(c|d)").unwrap());
+    literal::attach(&mut tree, false, Semantics::LeftmostFirst);
+    let prog = Program::compile_many(&[&tree]);
+    assert_eq!(prog.literals.len(), 1);
+    let backtracker = Backtracker::new(&prog, 3);
+    let groups = |haystack: &str| {
+      let (m, _) = backtracker.search(haystack, None, 0, &Budget::unlimited(),
+                                      &mut Cache::new());
+      return m.map(|m| m.groups);
+    };
+    assert_eq!(groups("abc"), Some(vec![Some((0, 3)), Some((0, 2)),
+                                        Some((2, 3))]));
+    assert_eq!(groups("ad"), Some(vec![Some((0, 2)), Some((0, 1)),
+                                       Some((1, 2))]));
+    assert_eq!(groups("abcd"), Some(vec![Some((0, 3)), Some((0, 2)),
+                                         Some((2, 3))]));
+    assert_eq!(groups("abx"), None);
+  }
+
+  #[test]
+  fn backtrack_ambiguous_is_memoized() {
+    let haystack = "a".repeat(5000);
+    let (m, memoized) = search("(a|a)*b", &haystack);
+    assert!(m.is_none());
+    assert!(memoized);
+  }
+
+  #[test]
+  fn backtrack_long_haystack_not_memoized() {
+    let haystack = format!("{}x", "abcdefgh".repeat(MAX_VISITED / 8));
+    let (m, memoized) = search("(abcdefgh)*x", &haystack);
+    let end = haystack.len();
+    assert_eq!(m, Some(vec![Some((0, end)), Some((end - 9, end - 1))]));
+    assert!(!memoized);
+  }
+}
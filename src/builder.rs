@@ -0,0 +1,390 @@
+use crate::bytes;
+use crate::ast::TreeNode;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Span;
+use crate::limits::Limits;
+use crate::parser::Parser;
+use crate::program::Program;
+use crate::redos;
+use crate::redos::Vulnerability;
+use crate::regex;
+use crate::scanner::Flags;
+use std::time::Duration;
+
+/// Which match is found when several begin at the same place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Semantics {
+  /// A union takes its longest branch, and repetitions take as much as they
+  /// can without giving any back (the default).
+  LeftmostLongest,
+  /// Like Perl: a union takes its first branch that leads to a match, and
+  /// repetitions give back what the rest of the expression needs.
+  LeftmostFirst,
+}
+
+/// Which engine does the matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+  /// Whichever suits the semantics and the expression best.
+  Auto,
+  /// Walk the syntax tree, which only gives leftmost-longest matches.
+  Interpreter,
+  /// Backtrack through the compiled expression (remembering what was
+  /// tried), which only gives leftmost-first matches.
+  Backtracker,
+  /// Like `Auto`, but full matches are always made in one pass, which only
+  /// works for expressions where the next character decides the way on.
+  OnePass,
+}
+
+/// Options for compiling an expression, which are given one at a time:
+///
+/// ```text
+/// let re = RegExBuilder::new("hello  # greeting")
+///            .case_insensitive(true)
+///            .extended(true)
+///            .build()?;
+/// ```
+///
+/// The flags set here are what the expression starts with, inline flags
+/// like `(?-i)` still change them from there on.
+#[derive(Debug, Clone)]
+pub struct RegExBuilder {
+  pub(crate) expr: String,
+  pub(crate) flags: Flags,
+  pub(crate) semantics: Semantics,
+  pub(crate) engine: Engine,
+  pub(crate) limits: Limits,
+}
+
+impl RegExBuilder {
+  pub fn new(expr: &str) -> Self {
+    return RegExBuilder {
+      expr: String::from(expr),
+      flags: Flags::default(),
+      semantics: Semantics::LeftmostLongest,
+      engine: Engine::Auto,
+      limits: Limits::default(),
+    };
+  }
+
+  /// Match letters in either case (`i`).
+  pub fn case_insensitive(mut self, yes: bool) -> Self {
+    self.flags.case_insensitive = yes;
+    return self;
+  }
+
+  /// Let `.` match `\n` (`s`).
+  pub fn dot_all(mut self, yes: bool) -> Self {
+    self.flags.dot_all = yes;
+    return self;
+  }
+
+  /// Let `^` and `$` match at the start and end of every line (`m`).
+  pub fn multi_line(mut self, yes: bool) -> Self {
+    self.flags.multi_line = yes;
+    return self;
+  }
+
+  /// Ignore whitespace and `#` comments outside of classes (`x`).
+  pub fn extended(mut self, yes: bool) -> Self {
+    self.flags.extended = yes;
+    return self;
+  }
+
+  /// Match characters rather than single bytes (`u`, on by default).
+  pub fn unicode(mut self, yes: bool) -> Self {
+    self.flags.unicode = yes;
+    return self;
+  }
+
+  pub fn semantics(mut self, semantics: Semantics) -> Self {
+    self.semantics = semantics;
+    return self;
+  }
+
+  /// Choosing an engine that can't match the expression with the other
+  /// options makes `build` fail with `ErrorKind::UnsupportedEngine`.
+  pub fn engine(mut self, engine: Engine) -> Self {
+    self.engine = engine;
+    return self;
+  }
+
+  /// Every limit at once, see `Limits`.
+  pub fn limits(mut self, limits: Limits) -> Self {
+    self.limits = limits;
+    return self;
+  }
+
+  /// The most instructions the expression may compile to.
+  pub fn size_limit(mut self, max: usize) -> Self {
+    self.limits.max_size = Some(max);
+    return self;
+  }
+
+  /// The most groups that may be open inside each other.
+  pub fn nest_limit(mut self, max: usize) -> Self {
+    self.limits.max_depth = max;
+    return self;
+  }
+
+  /// The most steps one search may take.
+  pub fn step_limit(mut self, max: u64) -> Self {
+    self.limits.max_steps = Some(max);
+    return self;
+  }
+
+  /// The longest one search may take.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.limits.timeout = Some(timeout);
+    return self;
+  }
+
+  /// Compile the expression, or return the first problem with it.
+  pub fn build(&self) -> Result<regex::RegEx, Error> {
+    return regex::RegEx::build(self);
+  }
+
+  /// Compile the expression for matching bytes, see `bytes::RegEx`.
+  pub fn build_bytes(&self) -> Result<bytes::RegEx, Error> {
+    return bytes::RegEx::build(self);
+  }
+
+  /// Every problem parsing the expression, in the order they appear.
+  pub fn check(&self) -> Vec<Error> {
+    return Self::errors(self.parser(false));
+  }
+
+  /// Like `check`, for `build_bytes`.
+  pub fn check_bytes(&self) -> Vec<Error> {
+    return Self::errors(self.parser(true));
+  }
+
+  /// The parts of the expression a backtracking matcher could take far too
+  /// long on (see `redos::analyze`), or every problem parsing it.
+  pub fn analyze(&self) -> Result<Vec<Vulnerability>, Vec<Error>> {
+    let tree = self.parser(false).parse()?;
+    return Ok(redos::analyze(&tree));
+  }
+
+  pub(crate) fn parser(&self, bytes: bool) -> Parser {
+    let parser = if bytes { Parser::new_bytes(&self.expr) }
+                 else { Parser::new(&self.expr) };
+    return parser.flags(&self.flags).max_depth(self.limits.max_depth);
+  }
+
+  // whether full matches may be made in one pass
+  pub(crate) fn allows_onepass(&self) -> bool {
+    return matches!(self.engine, Engine::Auto | Engine::OnePass);
+  }
+
+  // the program for tree, unless it's bigger than the size limit (which is
+  // blamed on all of the expression)
+  pub(crate) fn compile(&self, tree: &TreeNode) -> Result<Program, Error> {
+    let max = self.limits.max_size.unwrap_or(usize::MAX);
+    return Program::compile_within(&[tree], max).ok_or_else(|| {
+      Error::new(ErrorKind::TooBig(max), self.whole())
+    });
+  }
+
+  // problems with the compiled expression rather than how it's written,
+  // which are blamed on all of it
+  pub(crate) fn check_compiled(&self, onepass: bool) -> Result<(), Error> {
+    let supported = match self.engine {
+      Engine::Auto => true,
+      Engine::Interpreter => self.semantics == Semantics::LeftmostLongest,
+      Engine::Backtracker => self.semantics == Semantics::LeftmostFirst,
+      Engine::OnePass => onepass,
+    };
+    if !supported {
+      return Err(Error::new(ErrorKind::UnsupportedEngine, self.whole()));
+    }
+    return Ok(());
+  }
+
+  fn whole(&self) -> Span {
+    return Span::new(0, self.expr.chars().count());
+  }
+
+  fn errors(mut parser: Parser) -> Vec<Error> {
+    return match parser.parse() {
+      Ok(_) => vec![],
+      Err(errors) => errors,
+    };
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::error::ErrorKind;
+  use crate::limits::LimitExceeded;
+  use crate::limits::DEFAULT_MAX_SIZE;
+  use super::*;
+
+  fn matches(builder: RegExBuilder, haystack: &str) -> Vec<String> {
+    return builder.build().unwrap()
+                  .find_iter(haystack)
+                  .map(|m| String::from(m.as_str()))
+                  .collect();
+  }
+
+  fn error(builder: RegExBuilder) -> ErrorKind {
+    return builder.build().err().unwrap().kind;
+  }
+
+  #[test]
+  fn build_case_insensitive() {
+    let b = RegExBuilder::new("hello [a-c]+").case_insensitive(true);
+    assert_eq!(matches(b, "HeLLo aBCd"), vec!["HeLLo aBC"]);
+    let b = RegExBuilder::new("[^a]x(?-i)x").case_insensitive(true);
+    assert_eq!(matches(b, "AXx aXX bXx"), vec!["bXx"]);
+    let b = RegExBuilder::new("(?i)\u{e9}");
+    assert_eq!(matches(b, "\u{c9}"), vec!["\u{c9}"]);
+  }
+
+  #[test]
+  fn build_dot_all() {
+    assert_eq!(matches(RegExBuilder::new("a.b"), "a\nb"), Vec::<String>::new());
+    assert_eq!(matches(RegExBuilder::new("a.b").dot_all(true), "a\nb"),
+               vec!["a\nb"]);
+    assert_eq!(matches(RegExBuilder::new("a(?s).b"), "a\nb"), vec!["a\nb"]);
+  }
+
+  #[test]
+  fn build_dot_astral() {
+    let astral = "\u{1f600}";
+    for flags in 0..8 {
+      let b = RegExBuilder::new(".").dot_all(flags & 1 != 0)
+                                    .case_insensitive(flags & 2 != 0)
+                                    .unicode(flags & 4 != 0);
+      let text = b.build().unwrap().find(astral).map(|m| m.as_str());
+      assert_eq!(text, Some(astral), "flags {}", flags);
+      // without unicode a dot is a single byte of a byte haystack
+      let bytes = b.build_bytes().unwrap().find(astral.as_bytes())
+                   .map(|m| m.as_bytes());
+      let expected = if flags & 4 != 0 { astral.as_bytes() } else { b"\xF0" };
+      assert_eq!(bytes, Some(expected), "flags {}", flags);
+    }
+  }
+
+  #[test]
+  fn build_multi_line() {
+    let text = "ab\nb\nab";
+    assert_eq!(matches(RegExBuilder::new("^a?b$"), text), Vec::<String>::new());
+    assert_eq!(matches(RegExBuilder::new("^ab|b$"), text), vec!["ab", "b"]);
+    assert_eq!(matches(RegExBuilder::new("^a?b$").multi_line(true), text),
+               vec!["ab", "b", "ab"]);
+    assert_eq!(matches(RegExBuilder::new("(?m)^b(?-m)|b$"), text),
+               vec!["b", "b"]);
+    assert_eq!(matches(RegExBuilder::new("^$").multi_line(true), "a\n\nb"),
+               vec![""]);
+  }
+
+  #[test]
+  fn build_extended() {
+    let b = RegExBuilder::new("a b # comment\n c\\ d[ ]").extended(true);
+    assert_eq!(matches(b, "abc d "), vec!["abc d "]);
+    let b = RegExBuilder::new("a (?-x) b").extended(true);
+    assert_eq!(matches(b, "ab a b"), vec!["a b"]);
+  }
+
+  #[test]
+  fn build_unicode_off() {
+    let re = RegExBuilder::new(".").unicode(false).build_bytes().unwrap();
+    assert_eq!(re.find(b"\xFF").unwrap().as_bytes(), b"\xFF");
+    let re = RegExBuilder::new(".").build_bytes().unwrap();
+    assert!(re.find(b"\xFF").is_none());
+  }
+
+  #[test]
+  fn build_semantics() {
+    let b = RegExBuilder::new("foo|foobar");
+    assert_eq!(matches(b, "foobar"), vec!["foobar"]);
+    let b = RegExBuilder::new("foo|foobar").semantics(Semantics::LeftmostFirst);
+    assert_eq!(matches(b, "foobar"), vec!["foo"]);
+
+    let re = RegExBuilder::new("(a*)a").semantics(Semantics::LeftmostFirst)
+                                       .build().unwrap();
+    let c = re.full_captures("aaa").unwrap();
+    assert_eq!(&c[1], "aa");
+  }
+
+  #[test]
+  fn build_semantics_literal_unions() {
+    let b = RegExBuilder::new("foo|foobar|bar");
+    assert_eq!(matches(b.clone(), "foobar"), vec!["foobar"]);
+    let b = b.semantics(Semantics::LeftmostFirst);
+    assert_eq!(matches(b, "foobar"), vec!["foo", "bar"]);
+
+    // the later literals are still tried if the first doesn't lead to a match
+    let re = RegExBuilder::new("(foo|foobar|bar)x")
+                         .semantics(Semantics::LeftmostFirst)
+                         .build().unwrap();
+    assert_eq!(&re.captures("foobarx").unwrap()[1], "foobar");
+
+    // without trying every branch of the union at every place
+    let words: Vec<String> = (0..1000).map(|i| format!("w{}x", i)).collect();
+    let re = RegExBuilder::new(&format!("({})y", words.join("|")))
+                         .semantics(Semantics::LeftmostFirst)
+                         .step_limit(10_000)
+                         .build().unwrap();
+    assert!(re.try_captures_at(&"w999x".repeat(100), 0).unwrap().is_none());
+  }
+
+  #[test]
+  fn build_engines() {
+    let first = RegExBuilder::new("a").semantics(Semantics::LeftmostFirst);
+    assert!(first.clone().engine(Engine::Backtracker).build().is_ok());
+    assert_eq!(error(first.engine(Engine::Interpreter)),
+               ErrorKind::UnsupportedEngine);
+    assert_eq!(error(RegExBuilder::new("a").engine(Engine::Backtracker)),
+               ErrorKind::UnsupportedEngine);
+
+    let re = RegExBuilder::new("(\\d+)-(\\d+)").engine(Engine::OnePass)
+                                              .build().unwrap();
+    assert_eq!(&re.full_captures("12-345").unwrap()[2], "345");
+    assert_eq!(error(RegExBuilder::new("a*a").engine(Engine::OnePass)),
+               ErrorKind::UnsupportedEngine);
+  }
+
+  #[test]
+  fn build_limits() {
+    assert_eq!(error(RegExBuilder::new("abc").size_limit(3)),
+               ErrorKind::TooBig(3));
+    assert!(RegExBuilder::new("abc").size_limit(4).build().is_ok());
+    assert_eq!(error(RegExBuilder::new("((a))").nest_limit(1)),
+               ErrorKind::NestedTooDeep(1));
+    // nested repetitions double the program at each level, which is
+    // stopped by the default size limit before it gets far
+    let nested = format!("{}a{}", "(".repeat(100), ")+".repeat(100));
+    assert_eq!(error(RegExBuilder::new(&nested)),
+               ErrorKind::TooBig(DEFAULT_MAX_SIZE));
+    assert_eq!(error(RegExBuilder::new(&nested).size_limit(100)),
+               ErrorKind::TooBig(100));
+
+    let re = RegExBuilder::new("(a|a)*b").semantics(Semantics::LeftmostFirst)
+                                         .step_limit(100)
+                                         .build().unwrap();
+    assert_eq!(re.try_captures_at(&"a".repeat(100), 0).err(),
+               Some(LimitExceeded::Steps(100)));
+  }
+
+  #[test]
+  fn build_check() {
+    let b = RegExBuilder::new("(a # (\n)").extended(true);
+    assert_eq!(b.check(), vec![]);
+    assert_eq!(b.check_bytes(), vec![]);
+    assert_eq!(RegExBuilder::new("(a # (\n)").check().len(), 1);
+  }
+
+  #[test]
+  fn build_analyze() {
+    assert_eq!(RegExBuilder::new("(a|A)*").analyze(), Ok(vec![]));
+    let found = RegExBuilder::new("(a|A)*").case_insensitive(true)
+                                          .analyze().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].span, Span::new(0, 6));
+    assert_eq!(RegExBuilder::new("(a").analyze().err().unwrap().len(), 1);
+  }
+}
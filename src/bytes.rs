@@ -0,0 +1,461 @@
+use crate::builder::RegExBuilder;
+use crate::error::Error;
+use crate::limits::LimitExceeded;
+use crate::limits::Limits;
+use crate::literal::Required;
+use crate::parser::Parser;
+use crate::serialize;
+use crate::serialize::Kind;
+use crate::serialize::LoadError;
+use crate::serialize::Loaded;
+use crate::serialize::Parts;
+use crate::regex;
+use crate::regex::Haystack;
+use crate::regex::RegExImpl;
+use std::ops::Index;
+use std::ops::Range;
+
+/// A single match (or matching group) in a byte haystack.
+pub type Match<'h> = regex::Match<'h, [u8]>;
+
+/// The overall match (group 0) and every matching group of a single match.
+pub type Captures<'h> = regex::Captures<'h, [u8]>;
+
+/// The result of `RegEx::backtrack_at`.
+pub type Backtracked<'h> = regex::Backtracked<'h, [u8]>;
+
+/// Iterator over the captures of successive matches, see
+/// `RegEx::captures_iter`.
+///
+/// An empty match is followed by a search from the next byte.
+pub type CaptureMatches<'r, 'h> = regex::CaptureMatches<'r, 'h, [u8]>;
+
+/// Iterator over successive matches, see `RegEx::find_iter`.
+pub type Matches<'r, 'h> = regex::Matches<'r, 'h, [u8]>;
+
+impl<'h> Match<'h> {
+  pub fn range(&self) -> Range<usize> {
+    return self.start()..self.end();
+  }
+
+  /// The matched bytes.
+  pub fn as_bytes(&self) -> &'h [u8] {
+    return &self.haystack()[self.range()];
+  }
+}
+
+impl<'h> CaptureMatches<'_, 'h> {
+  /// Like `next`, but gives an error if the search goes over the limits
+  /// given to `RegEx::with_limits` rather than panicking.
+  pub fn try_next(&mut self) -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.re.next_captures(self.haystack, &mut self.start);
+  }
+}
+
+impl<'h> Matches<'_, 'h> {
+  /// Like `next`, but gives an error if the search goes over the limits
+  /// given to `RegEx::with_limits` rather than panicking.
+  pub fn try_next(&mut self) -> Result<Option<Match<'h>>, LimitExceeded> {
+    return Ok(self.captures.try_next()?.map(|c| c.get_match()));
+  }
+}
+
+impl<'h> Index<usize> for Captures<'h> {
+  type Output = [u8];
+
+  // panics if the group didn't participate in the match
+  fn index(&self, i: usize) -> &[u8] {
+    return match self.get(i) {
+      Some(m) => m.as_bytes(),
+      None => panic!("no match for group {}", i),
+    };
+  }
+}
+
+// length of the utf-8 sequence that starts with b (0 if b can't start one)
+fn utf8_len(b: u8) -> usize {
+  match b {
+    0x00..=0x7F => 1,
+    0xC0..=0xDF => 2,
+    0xE0..=0xEF => 3,
+    0xF0..=0xF7 => 4,
+    _ => 0,
+  }
+}
+
+impl Haystack for [u8] {
+  const BYTES: bool = true;
+
+  fn unit_at(&self, i: usize, unicode: bool) -> Option<(u32, usize)> {
+    let b = *self.get(i)?;
+    if !unicode {
+      return Some((b as u32, i + 1));
+    }
+
+    // decode a utf-8 character, invalid sequences match no unicode unit
+    let len = utf8_len(b);
+    if len == 0 || i + len > self.len() {
+      return None;
+    }
+    let c = std::str::from_utf8(&self[i..i + len]).ok()?.chars().next()?;
+    return Some((c as u32, i + len));
+  }
+
+  fn next_start(&self, i: usize) -> usize {
+    // any byte can start a search
+    return i + 1;
+  }
+
+  fn len(&self) -> usize {
+    return <[u8]>::len(self);
+  }
+
+  fn bytes(&self) -> &[u8] {
+    return self;
+  }
+}
+
+// a RegEx that matches over arbitrary bytes rather than utf-8 text
+// by default characters in the expression match their utf-8 encoding, but
+// `\xNN` always matches a single byte and after `(?-u)` so do `.` and classes
+pub struct RegEx {
+  pub expr: String,
+  inner: RegExImpl<[u8]>,
+}
+
+impl RegEx {
+  /// Parse an expression, or return the first problem with it.
+  pub fn new(expr: &str) -> Result<Self, Error> {
+    return RegExBuilder::new(expr).build_bytes();
+  }
+
+  /// Like `new`, but searches give up once they go over the limits, and the
+  /// expression can't nest groups deeper than `limits.max_depth`.
+  ///
+  /// A search that gives up is an error from the `try_` methods, and a panic
+  /// from the others.
+  pub fn with_limits(expr: &str, limits: Limits) -> Result<Self, Error> {
+    return RegExBuilder::new(expr).limits(limits).build_bytes();
+  }
+
+  pub(crate) fn build(options: &RegExBuilder) -> Result<Self, Error> {
+    let inner = RegExImpl::build(options)?;
+    return Ok(RegEx { expr: options.expr.clone(), inner });
+  }
+
+  /// The compiled expression (with its options) as bytes, which
+  /// `from_bytes` loads again without parsing it. See
+  /// `serialize::FORMAT_VERSION`.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    return serialize::save(Kind::Bytes, |w| w.regex(&self.parts()));
+  }
+
+  /// Load an expression saved by `to_bytes`, checking the bytes are valid.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+    let mut r = serialize::Reader::new(bytes, Kind::Bytes)?;
+    let loaded = r.regex()?;
+    r.finish()?;
+    return Self::load(loaded);
+  }
+
+  pub(crate) fn parts(&self) -> Parts<'_> {
+    return self.inner.parts(&self.expr);
+  }
+
+  pub(crate) fn load(mut loaded: Loaded) -> Result<Self, LoadError> {
+    let expr = std::mem::take(&mut loaded.expr);
+    return Ok(RegEx { expr, inner: RegExImpl::load(loaded)? });
+  }
+
+  /// Every problem with an expression, in the order they appear. Empty if
+  /// the expression is valid.
+  pub fn check(expr: &str) -> Vec<Error> {
+    return match Parser::new_bytes(expr).parse() {
+      Ok(_) => vec![],
+      Err(errors) => errors,
+    };
+  }
+
+  /// The longest literal that every match contains, if there is one.
+  pub fn required(&self) -> Option<&Required> {
+    return self.inner.required();
+  }
+
+  /// Number of groups in the expression, including group 0.
+  pub fn captures_len(&self) -> usize {
+    return self.inner.captures_len();
+  }
+
+  /// The name of every group (if it has one) by group number.
+  pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+    return self.inner.capture_names();
+  }
+
+  /// Whether the expression matches anywhere in the haystack.
+  pub fn is_match(&self, haystack: &[u8]) -> bool {
+    return self.find(haystack).is_some();
+  }
+
+  /// Like `is_match`, but gives an error if the search goes over the limits
+  /// given to `with_limits` rather than panicking.
+  pub fn try_is_match(&self, haystack: &[u8]) -> Result<bool, LimitExceeded> {
+    return Ok(self.try_find(haystack)?.is_some());
+  }
+
+  /// Whether the expression matches the entire haystack.
+  pub fn is_full_match(&self, haystack: &[u8]) -> bool {
+    return self.full_captures(haystack).is_some();
+  }
+
+  /// Like `is_full_match`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_is_full_match(&self, haystack: &[u8])
+    -> Result<bool, LimitExceeded> {
+    return Ok(self.try_full_captures(haystack)?.is_some());
+  }
+
+  /// The match of the entire haystack, along with its groups.
+  ///
+  /// Patterns where the next unit always decides the only way to go on
+  /// (like `(\d\d\d\d)-(\d\d)-(\d\d)`) are matched in a single scan.
+  pub fn full_captures<'h>(&self, haystack: &'h [u8])
+    -> Option<Captures<'h>> {
+    return self.inner.full_captures(haystack);
+  }
+
+  /// Like `full_captures`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_full_captures<'h>(&self, haystack: &'h [u8])
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.inner.try_full_captures(haystack);
+  }
+
+  /// The leftmost match in the haystack.
+  pub fn find<'h>(&self, haystack: &'h [u8]) -> Option<Match<'h>> {
+    return self.find_at(haystack, 0);
+  }
+
+  /// Like `find`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_find<'h>(&self, haystack: &'h [u8])
+    -> Result<Option<Match<'h>>, LimitExceeded> {
+    return self.try_find_at(haystack, 0);
+  }
+
+  /// The leftmost match that begins at or after the offset start.
+  /// Offsets are still relative to the whole haystack.
+  pub fn find_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Option<Match<'h>> {
+    return self.inner.find_at(haystack, start);
+  }
+
+  /// Like `find_at`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_find_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Result<Option<Match<'h>>, LimitExceeded> {
+    return self.inner.try_find_at(haystack, start);
+  }
+
+  /// The leftmost match in the haystack, along with its groups.
+  pub fn captures<'h>(&self, haystack: &'h [u8]) -> Option<Captures<'h>> {
+    return self.captures_at(haystack, 0);
+  }
+
+  /// Like `captures`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_captures<'h>(&self, haystack: &'h [u8])
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.try_captures_at(haystack, 0);
+  }
+
+  /// Like `captures`, but begins searching at the offset start.
+  pub fn captures_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Option<Captures<'h>> {
+    return self.inner.captures_at(haystack, start);
+  }
+
+  /// Like `captures_at`, but gives an error if the search goes over the
+  /// limits given to `with_limits` rather than panicking.
+  pub fn try_captures_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.inner.try_captures_at(haystack, start);
+  }
+
+  /// Like `captures_at`, but matched by backtracking, see
+  /// `regex::RegEx::backtrack_at`.
+  pub fn backtrack_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Backtracked<'h> {
+    return self.inner.backtrack_at(haystack, start);
+  }
+
+  /// Like `backtrack_at`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_backtrack_at<'h>(&self, haystack: &'h [u8], start: usize)
+    -> Result<Backtracked<'h>, LimitExceeded> {
+    return self.inner.try_backtrack_at(haystack, start);
+  }
+
+  /// Every successive non-overlapping match in the haystack, found lazily.
+  pub fn find_iter<'r, 'h>(&'r self, haystack: &'h [u8]) -> Matches<'r, 'h> {
+    return self.inner.find_iter(haystack);
+  }
+
+  /// Every successive non-overlapping match in the haystack along with its
+  /// groups, found lazily.
+  pub fn captures_iter<'r, 'h>(&'r self, haystack: &'h [u8])
+    -> CaptureMatches<'r, 'h> {
+    return self.captures_iter_at(haystack, 0);
+  }
+
+  /// Like `captures_iter`, but begins searching at the offset start.
+  pub fn captures_iter_at<'r, 'h>(&'r self, haystack: &'h [u8], start: usize)
+    -> CaptureMatches<'r, 'h> {
+    return self.inner.captures_iter_at(haystack, start);
+  }
+
+  pub fn match_all<'h>(&self, s: &'h [u8]) -> Vec<Captures<'h>> {
+    return self.captures_iter(s).collect();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn match_invalid_utf8() {
+    let r = RegEx::new("b+").unwrap();
+    let m = r.find(b"\xFF\xFEbb\xFF");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+    assert_eq!(mu.start(), 2);
+    assert_eq!(mu.end(), 4);
+    assert_eq!(mu.as_bytes(), b"bb");
+  }
+
+  #[test]
+  fn match_anchors_around_bytes() {
+    let r = RegEx::new("(?m)^\\xFF+$").unwrap();
+    let m = r.match_all(b"\xFF\xFFa\n\xFF\n\xFE\xFF");
+    assert_eq!(m.len(), 1);
+    assert_eq!(m[0].get_match().start(), 4);
+    assert_eq!(m[0].get_match().end(), 5);
+  }
+
+  #[test]
+  fn limits_try_methods() {
+    let limits = Limits { max_steps: Some(1000), ..Limits::default() };
+    let r = RegEx::with_limits("x|a*c", limits).unwrap();
+    let a = "a".repeat(100);
+    assert_eq!(r.try_is_match(b"\xFFx"), Ok(true));
+    assert!(r.try_is_match(a.as_bytes()).is_err());
+    assert!(r.try_find(a.as_bytes()).is_err());
+    let full = RegEx::with_limits("(a|a)*", limits).unwrap();
+    assert_eq!(full.try_is_full_match(a.as_bytes()), Ok(true));
+    assert!(full.try_is_full_match("a".repeat(2000).as_bytes()).is_err());
+    assert!(r.find_iter(a.as_bytes()).try_next().is_err());
+  }
+
+  #[test]
+  fn match_hex_escape_as_byte() {
+    let r = RegEx::new("a\\xFF\\x00").unwrap();
+    let m = r.find(b"_a\xFF\x00_");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+    assert_eq!(mu.start(), 1);
+    assert_eq!(mu.end(), 4);
+  }
+
+  #[test]
+  fn match_byte_charset() {
+    let r = RegEx::new("[\\x80-\\xFF]+").unwrap();
+    let m = r.match_all(b"a\x80\x90b\xFF");
+
+    assert_eq!(m.len(), 2);
+    assert_eq!(m[0].get_match().start(), 1);
+    assert_eq!(m[0].get_match().end(), 3);
+    assert_eq!(m[1].get_match().start(), 4);
+    assert_eq!(m[1].get_match().end(), 5);
+  }
+
+  #[test]
+  fn match_unicode_character_as_utf8() {
+    let r = RegEx::new("\\u2603.").unwrap();
+    let m = r.find("a\u{2603}\u{e9}".as_bytes());
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+    assert_eq!(mu.start(), 1);
+    assert_eq!(mu.end(), 6);
+  }
+
+  #[test]
+  fn miss_invalid_utf8_with_unicode_wildcard() {
+    let r = RegEx::new(".").unwrap();
+    let m = r.match_all(b"\xFFa\xFE");
+
+    assert_eq!(m.len(), 1);
+    assert_eq!(m[0].get_match().start(), 1);
+    assert_eq!(m[0].get_match().end(), 2);
+  }
+
+  #[test]
+  fn match_any_byte_without_unicode() {
+    let r = RegEx::new("(?-u)a.b").unwrap();
+    let m = r.find(b"a\xFFb");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 3);
+  }
+
+  #[test]
+  fn match_single_byte_of_character_without_unicode() {
+    let r = RegEx::new("(?-u)..").unwrap();
+    let m = r.find("\u{e9}".as_bytes());
+    assert!(m.is_some());
+
+    // é is two bytes long
+    let mu = m.unwrap();
+    assert_eq!(mu.end(), 2);
+  }
+
+  #[test]
+  fn match_unicode_flag_restored() {
+    let r = RegEx::new("(?-u).(?u).").unwrap();
+    let m = r.find(b"\xFF\xFF");
+    assert!(m.is_none());
+
+    let m = r.find("\x01\u{e9}".as_bytes());
+    assert!(m.is_some());
+    assert_eq!(m.unwrap().end(), 3);
+  }
+
+  #[test]
+  fn find_at_and_full_match_bytes() {
+    let r = RegEx::new("(?-u)\\xFF.").unwrap();
+    assert!(r.is_full_match(b"\xFF\x00"));
+    assert!(!r.is_full_match(b"\xFF\x00\x00"));
+    let c = RegEx::new("(?-u)(\\xFF+)(.)").unwrap()
+                  .full_captures(b"\xFF\xFFa").unwrap();
+    assert_eq!((&c[1], &c[2]), (&b"\xFF\xFF"[..], &b"a"[..]));
+
+    let m = r.find_at(b"\xFFa\xFFb", 1);
+    assert!(m.is_some());
+    assert_eq!(m.unwrap().range(), 2..4);
+  }
+
+  #[test]
+  fn to_bytes_round_trip() {
+    let re = RegEx::new("(?-u)(\\xFF.)+|caf\\u00e9").unwrap();
+    let loaded = RegEx::from_bytes(&re.to_bytes()).unwrap();
+    assert_eq!(loaded.find(b"a\xFF\x00\xFF\x01").unwrap().as_bytes(),
+               b"\xFF\x00\xFF\x01");
+    assert!(loaded.is_match("caf\u{e9}".as_bytes()));
+    assert_eq!(crate::regex::RegEx::from_bytes(&re.to_bytes()).err(),
+               Some(LoadError::WrongKind));
+  }
+}
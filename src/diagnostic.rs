@@ -0,0 +1,236 @@
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Span;
+use crate::redos::Vulnerability;
+use crate::tui::Color;
+use crate::tui::TextStyle;
+use std::fmt;
+
+const ERROR_STYLE: TextStyle = TextStyle {
+  foreground: Color::RED,
+  bold: true,
+};
+const WARNING_STYLE: TextStyle = TextStyle {
+  foreground: Color::YELLOW,
+  bold: true,
+};
+const HINT_STYLE: TextStyle = TextStyle {
+  foreground: Color::CYAN,
+  bold: true,
+};
+
+/// A report of an `Error` for a person to read, which shows the expression
+/// with the part at fault underlined:
+///
+/// ```text
+/// error: a class can't be the start or end of a range
+///   [\w-~]
+///      ^
+/// hint: did you mean `\-`?
+/// ```
+///
+/// or, at a warning level, of a `Vulnerability` found by `redos::analyze`.
+pub struct Diagnostic<'a> {
+  expr: &'a str,
+  report: Report<'a>,
+  color: bool,
+}
+
+// what is being reported, which decides the level
+enum Report<'a> {
+  Error(&'a Error),
+  Warning(&'a Vulnerability),
+}
+
+impl<'a> Diagnostic<'a> {
+  pub fn new(expr: &'a str, error: &'a Error) -> Self {
+    return Diagnostic { expr, report: Report::Error(error), color: false };
+  }
+
+  /// A warning about an expression that is valid, but can backtrack
+  /// catastrophically.
+  pub fn warning(expr: &'a str, vulnerability: &'a Vulnerability) -> Self {
+    return Diagnostic {
+      expr,
+      report: Report::Warning(vulnerability),
+      color: false,
+    };
+  }
+
+  /// Style the report with terminal escape codes.
+  pub fn color(mut self, color: bool) -> Self {
+    self.color = color;
+    return self;
+  }
+
+  /// A suggestion for fixing the expression.
+  pub fn hint(&self) -> String {
+    let error = match self.report {
+      Report::Error(error) => error,
+      Report::Warning(v) => {
+        return format!("an input like {:?} takes it the longest",
+                       v.attack(20));
+      },
+    };
+    // the character the error begins at
+    let c = self.expr.chars().nth(error.span.start).unwrap_or('\0');
+
+    return match &error.kind {
+      ErrorKind::UnclosedGroup => {
+        String::from("add a ')', or match a literal '(' with `\\(`")
+      },
+      ErrorKind::UnclosedClass => {
+        String::from("add a ']', or match a literal '[' with `\\[`")
+      },
+      ErrorKind::EmptyClass => {
+        String::from("add a character to the class, or match a literal ']' \
+                      with `\\]`")
+      },
+      ErrorKind::UnopenedGroup | ErrorKind::UnopenedClass |
+      ErrorKind::BadRepetition | ErrorKind::Unexpected(_) => {
+        format!("did you mean `\\{}`?", c)
+      },
+      ErrorKind::TrailingBackslash => String::from("did you mean `\\\\`?"),
+      ErrorKind::InvalidEscape(_) | ErrorKind::IncompleteEscape => {
+        String::from("`\\u` takes exactly four hex digits (e.g.: `\\u00e9`)")
+      },
+      ErrorKind::InvalidCodePoint(_) => {
+        String::from("surrogates (`\\ud800` to `\\udfff`) aren't characters")
+      },
+      ErrorKind::InvalidRange(min, max) => {
+        format!("did you mean `{}-{}`?", max, min)
+      },
+      ErrorKind::InvalidRangeBound => String::from("did you mean `\\-`?"),
+      ErrorKind::InvalidGroupName => {
+        String::from("names are letters, digits and '_', \
+                      and can't start with a digit")
+      },
+      ErrorKind::NestedTooDeep(_) => {
+        String::from("remove groups that don't change what matches")
+      },
+      ErrorKind::TooBig(_) => {
+        String::from("split the expression up, or raise the size limit")
+      },
+      ErrorKind::UnsupportedEngine => {
+        String::from("let the engine be chosen with `Engine::Auto`")
+      },
+    };
+  }
+
+  fn span(&self) -> Span {
+    return match self.report {
+      Report::Error(error) => error.span,
+      Report::Warning(v) => v.span,
+    };
+  }
+
+  fn style(&self) -> &TextStyle<'static> {
+    return match self.report {
+      Report::Error(_) => &ERROR_STYLE,
+      Report::Warning(_) => &WARNING_STYLE,
+    };
+  }
+
+  fn paint(&self, style: &TextStyle<'static>, text: &str) -> String {
+    if self.color {
+      return style.paint(text);
+    }
+    return String::from(text);
+  }
+
+  // a line of '^~~~' under the span, lined up with the expression
+  fn underline(&self) -> String {
+    let span = self.span();
+    let mut line = String::new();
+    for c in self.expr.chars().take(span.start) {
+      // keep tabs so everything after them still lines up
+      line.push(if c == '\t' { '\t' } else { ' ' });
+    }
+
+    let width = span.end.saturating_sub(span.start).max(1);
+    let mut marks = String::from("^");
+    for _ in 1..width {
+      marks.push('~');
+    }
+    line.push_str(&self.paint(self.style(), &marks));
+    return line;
+  }
+}
+
+impl fmt::Display for Diagnostic<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let hint = self.paint(&HINT_STYLE, "hint");
+    match self.report {
+      Report::Error(error) => {
+        writeln!(f, "{}: {}", self.paint(self.style(), "error"), error.kind)?;
+      },
+      Report::Warning(v) => {
+        writeln!(f, "{}: {}", self.paint(self.style(), "warning"), v)?;
+      },
+    }
+    writeln!(f, "  {}", self.expr)?;
+    writeln!(f, "  {}", self.underline())?;
+    return writeln!(f, "{}: {}", hint, self.hint());
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::builder::RegExBuilder;
+  use crate::regex::RegEx;
+  use super::*;
+
+  fn report(expr: &str) -> String {
+    let e = RegEx::new(expr).err().unwrap();
+    return Diagnostic::new(expr, &e).to_string();
+  }
+
+  #[test]
+  fn report_underlines_span() {
+    assert_eq!(report("[az-a]"),
+               "error: invalid range, 'z' comes after 'a'\n\
+                \x20 [az-a]\n\
+                \x20   ^~~\n\
+                hint: did you mean `a-z`?\n");
+  }
+
+  #[test]
+  fn report_hints() {
+    assert!(report("[\\w-~]").ends_with("hint: did you mean `\\-`?\n"));
+    assert!(report("a+*").ends_with("hint: did you mean `\\*`?\n"));
+    assert!(report("a)").ends_with("hint: did you mean `\\)`?\n"));
+  }
+
+  #[test]
+  fn report_span_to_end() {
+    let r = report("ab\\u12");
+    assert!(r.contains("\n  ab\\u12\n    ^~~~\n"));
+  }
+
+  #[test]
+  fn report_keeps_tabs_aligned() {
+    let r = report("\ta)");
+    assert!(r.contains("\n  \ta)\n  \t ^\n"));
+  }
+
+  #[test]
+  fn report_color() {
+    let e = RegEx::new("(").err().unwrap();
+    let r = Diagnostic::new("(", &e).color(true).to_string();
+    assert!(r.starts_with("\x1b[1;31merror\x1b[0m: "));
+    assert!(r.contains("\x1b[1;31m^\x1b[0m"));
+  }
+
+  #[test]
+  fn report_warning() {
+    let found = RegExBuilder::new("x(a+)+").analyze().unwrap();
+    let r = Diagnostic::warning("x(a+)+", &found[0]).to_string();
+    assert!(r.starts_with("warning: exponential backtracking at position 1"));
+    assert!(r.contains("\n  x(a+)+\n   ^~~~~\n"));
+    assert!(r.ends_with(&format!("hint: an input like {:?} takes it the \
+                                  longest\n", found[0].attack(20))));
+
+    let r = Diagnostic::warning("x(a+)+", &found[0]).color(true).to_string();
+    assert!(r.starts_with("\x1b[1;33mwarning\x1b[0m: "));
+  }
+}
@@ -0,0 +1,263 @@
+use std::error;
+use std::fmt;
+
+/// A range of characters in an expression, `start..end`.
+///
+/// Positions count characters, not bytes, so they can be used to point at
+/// the expression when it is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Self {
+    return Span { start, end };
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+  /// `(` without a matching `)`.
+  UnclosedGroup,
+  /// `)` without a matching `(`.
+  UnopenedGroup,
+  /// `[` without a matching `]`.
+  UnclosedClass,
+  /// `]` without a matching `[`.
+  UnopenedClass,
+  /// `[]`, which can never match anything.
+  EmptyClass,
+  /// A backslash at the very end of the expression.
+  TrailingBackslash,
+  /// A `\u` escape with a character that isn't a hex digit.
+  InvalidEscape(char),
+  /// A `\u` escape that ends before its four hex digits.
+  IncompleteEscape,
+  /// An escape code that isn't a valid character.
+  InvalidCodePoint(u32),
+  /// A range in a character class that ends before it starts: `[z-a]`.
+  InvalidRange(char, char),
+  /// A range in a character class that starts or ends with a class: `[\d-z]`.
+  InvalidRangeBound,
+  /// `*`, `+` or `?` with nothing to repeat.
+  BadRepetition,
+  /// A group name that is empty, unclosed or not made of word characters.
+  InvalidGroupName,
+  /// A character that isn't allowed where it appears.
+  Unexpected(char),
+  /// A group inside more groups than the limit allows (see `Limits`).
+  NestedTooDeep(usize),
+  /// An expression that compiles to more instructions than the limit.
+  TooBig(usize),
+  /// An engine that can't match the expression with the options given (see
+  /// `RegExBuilder::engine`).
+  UnsupportedEngine,
+}
+
+/// A problem with an expression, found while parsing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+  pub kind: ErrorKind,
+  /// Characters of the expression that are at fault.
+  pub span: Span,
+}
+
+impl Error {
+  pub fn new(kind: ErrorKind, span: Span) -> Self {
+    return Error { kind, span };
+  }
+}
+
+impl fmt::Display for ErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ErrorKind::UnclosedGroup => {
+        write!(f, "missing ')' to close '('")?;
+      },
+      ErrorKind::UnopenedGroup => {
+        write!(f, "')' without a matching '('")?;
+      },
+      ErrorKind::UnclosedClass => {
+        write!(f, "missing ']' to close '['")?;
+      },
+      ErrorKind::UnopenedClass => {
+        write!(f, "']' without a matching '['")?;
+      },
+      ErrorKind::EmptyClass => {
+        write!(f, "empty class")?;
+      },
+      ErrorKind::TrailingBackslash => {
+        write!(f, "expression ends with '\\'")?;
+      },
+      ErrorKind::InvalidEscape(c) => {
+        write!(f, "invalid escape, saw '{}' instead of a hex digit", c)?;
+      },
+      ErrorKind::IncompleteEscape => {
+        write!(f, "expression ends in the middle of an escape")?;
+      },
+      ErrorKind::InvalidCodePoint(u) => {
+        write!(f, "escape code {:#x} isn't a valid character", u)?;
+      },
+      ErrorKind::InvalidRange(min, max) => {
+        write!(f, "invalid range, '{}' comes after '{}'", min, max)?;
+      },
+      ErrorKind::InvalidRangeBound => {
+        write!(f, "a class can't be the start or end of a range")?;
+      },
+      ErrorKind::BadRepetition => {
+        write!(f, "repetition with nothing to repeat")?;
+      },
+      ErrorKind::InvalidGroupName => {
+        write!(f, "invalid group name")?;
+      },
+      ErrorKind::Unexpected(c) => {
+        write!(f, "unexpected '{}'", c)?;
+      },
+      ErrorKind::NestedTooDeep(max) => {
+        write!(f, "groups are nested more than {} deep", max)?;
+      },
+      ErrorKind::TooBig(max) => {
+        write!(f, "expression is bigger than {} instructions", max)?;
+      },
+      ErrorKind::UnsupportedEngine => {
+        write!(f, "the engine chosen can't match this expression")?;
+      },
+    }
+    return Ok(());
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return write!(f, "{} at position {}", self.kind, self.span.start);
+  }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod test {
+  use crate::regex::RegEx;
+  use super::*;
+
+  fn error(expr: &str) -> (ErrorKind, usize, usize) {
+    let e = RegEx::new(expr).err().unwrap();
+    return (e.kind, e.span.start, e.span.end);
+  }
+
+  #[test]
+  fn error_unbalanced_groups() {
+    assert_eq!(error("ab(c"), (ErrorKind::UnclosedGroup, 2, 3));
+    assert_eq!(error("(a(b)"), (ErrorKind::UnclosedGroup, 0, 1));
+    assert_eq!(error("ab)c"), (ErrorKind::UnopenedGroup, 2, 3));
+  }
+
+  #[test]
+  fn error_unbalanced_classes() {
+    assert_eq!(error("a[bc"), (ErrorKind::UnclosedClass, 1, 2));
+    assert_eq!(error("a[^"), (ErrorKind::UnclosedClass, 1, 2));
+    assert_eq!(error("a]"), (ErrorKind::UnopenedClass, 1, 2));
+  }
+
+  #[test]
+  fn error_bad_escapes() {
+    assert_eq!(error("ab\\"), (ErrorKind::TrailingBackslash, 2, 3));
+    assert_eq!(error("\\u12g4"), (ErrorKind::InvalidEscape('g'), 0, 5));
+    assert_eq!(error("a\\u12"), (ErrorKind::IncompleteEscape, 1, 5));
+    assert_eq!(error("\\ud800"), (ErrorKind::InvalidCodePoint(0xd800), 0, 6));
+  }
+
+  #[test]
+  fn error_bad_ranges() {
+    assert_eq!(error("[az-a]"), (ErrorKind::InvalidRange('z', 'a'), 2, 5));
+    assert_eq!(error("[\\d-z]"), (ErrorKind::InvalidRangeBound, 3, 4));
+    assert_eq!(error("[a-\\d]"), (ErrorKind::InvalidRangeBound, 3, 5));
+  }
+
+  #[test]
+  fn error_bad_repetition() {
+    assert_eq!(error("*a"), (ErrorKind::BadRepetition, 0, 1));
+    assert_eq!(error("a**"), (ErrorKind::BadRepetition, 2, 3));
+    assert_eq!(error("a(+)"), (ErrorKind::BadRepetition, 2, 3));
+    assert_eq!(error("a|?"), (ErrorKind::BadRepetition, 2, 3));
+  }
+
+  #[test]
+  fn error_other() {
+    assert_eq!(error("(?<1a>x)"), (ErrorKind::InvalidGroupName, 0, 4));
+    assert_eq!(error("[a^]"), (ErrorKind::Unexpected('^'), 2, 3));
+  }
+
+  #[test]
+  fn error_nested_too_deep() {
+    let deep = |n| format!("{}a{}", "(".repeat(n), ")".repeat(n));
+    assert!(RegEx::new(&deep(250)).is_ok());
+    assert_eq!(error(&deep(251)), (ErrorKind::NestedTooDeep(250), 250, 251));
+    assert_eq!(errors(&format!("{}|b", deep(251))),
+               vec![(ErrorKind::NestedTooDeep(250), 250)]);
+  }
+
+  #[test]
+  fn error_first_wins() {
+    assert_eq!(error("a*+(b\\"), (ErrorKind::BadRepetition, 2, 3));
+  }
+
+  fn errors(expr: &str) -> Vec<(ErrorKind, usize)> {
+    return RegEx::check(expr).into_iter()
+                             .map(|e| (e.kind, e.span.start))
+                             .collect();
+  }
+
+  #[test]
+  fn errors_none() {
+    assert_eq!(errors("(a|b)*[^c-d]\\u00e9"), vec![]);
+  }
+
+  #[test]
+  fn errors_every_repetition() {
+    assert_eq!(errors("*a**b+?+"), vec![(ErrorKind::BadRepetition, 0),
+                                       (ErrorKind::BadRepetition, 3),
+                                       (ErrorKind::BadRepetition, 6),
+                                       (ErrorKind::BadRepetition, 7)]);
+  }
+
+  #[test]
+  fn errors_sync_at_union_and_group() {
+    assert_eq!(errors("a]bc|d]e|(f]g)h*"),
+               vec![(ErrorKind::UnopenedClass, 1),
+                    (ErrorKind::UnopenedClass, 6),
+                    (ErrorKind::UnopenedClass, 11)]);
+  }
+
+  #[test]
+  fn errors_sync_at_charset_end() {
+    assert_eq!(errors("[z-a*]+[\\d-x(]*[]"),
+               vec![(ErrorKind::InvalidRange('z', 'a'), 1),
+                    (ErrorKind::InvalidRangeBound, 10),
+                    (ErrorKind::EmptyClass, 16)]);
+  }
+
+  #[test]
+  fn errors_unbalanced() {
+    assert_eq!(errors("a)b(c"), vec![(ErrorKind::UnopenedGroup, 1),
+                                    (ErrorKind::UnclosedGroup, 3)]);
+    assert_eq!(errors("((a)|[b"), vec![(ErrorKind::UnclosedGroup, 0),
+                                      (ErrorKind::UnclosedClass, 5)]);
+  }
+
+  #[test]
+  fn errors_scanner_and_parser() {
+    assert_eq!(errors("(?<1>a)+*\\u12"),
+               vec![(ErrorKind::InvalidGroupName, 0),
+                    (ErrorKind::BadRepetition, 8),
+                    (ErrorKind::IncompleteEscape, 9)]);
+  }
+
+  #[test]
+  fn error_display() {
+    let e = RegEx::new("a[z-a]").err().unwrap();
+    assert_eq!(e.to_string(), "invalid range, 'z' comes after 'a' at position 2");
+  }
+}
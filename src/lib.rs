@@ -0,0 +1,24 @@
+pub mod regex;
+pub mod ast;
+pub mod printer;
+pub mod error;
+pub mod diagnostic;
+pub mod bytes;
+pub mod replace;
+pub mod template;
+pub mod set;
+pub mod tui;
+mod scanner;
+mod parser;
+mod optimize;
+pub mod literal;
+pub mod limits;
+pub mod builder;
+mod aho;
+mod program;
+mod pikevm;
+mod onepass;
+mod backtrack;
+mod pool;
+pub mod redos;
+pub mod serialize;
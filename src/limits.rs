@@ -0,0 +1,152 @@
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How deeply groups can be nested by default.
+pub const DEFAULT_MAX_DEPTH: usize = 250;
+
+/// The most instructions an expression may compile to by default.
+pub const DEFAULT_MAX_SIZE: usize = 1 << 20;
+
+// how many steps are taken between looking at the clock
+const STEPS_PER_CLOCK: u64 = 1024;
+
+/// Bounds on the work an expression is allowed to do, for expressions that
+/// can't be trusted (e.g.: ones typed in by users).
+///
+/// `max_steps` and `timeout` apply to each search separately, and `max_depth`
+/// and `max_size` to compiling the expression. The default only limits the
+/// depth and the size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+  /// The most nodes the interpreter may try during one search.
+  pub max_steps: Option<u64>,
+  /// The longest one search may take.
+  pub timeout: Option<Duration>,
+  /// The most groups that may be open inside each other.
+  pub max_depth: usize,
+  /// The most instructions the expression may compile to.
+  pub max_size: Option<usize>,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    return Limits {
+      max_steps: None,
+      timeout: None,
+      max_depth: DEFAULT_MAX_DEPTH,
+      max_size: Some(DEFAULT_MAX_SIZE),
+    };
+  }
+}
+
+/// A search that was given up on because it went over one of its `Limits`.
+/// Whether the expression would have matched isn't known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+  /// The search tried more than this many nodes.
+  Steps(u64),
+  /// The search took longer than this.
+  Timeout(Duration),
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return match self {
+      LimitExceeded::Steps(max) => {
+        write!(f, "search gave up after {} steps", max)
+      },
+      LimitExceeded::Timeout(timeout) => {
+        write!(f, "search gave up after {:.2?}", timeout)
+      },
+    };
+  }
+}
+
+impl error::Error for LimitExceeded {}
+
+// the work one search has done so far, against its limits
+pub(crate) struct Budget {
+  max_steps: Option<u64>,
+  timeout: Option<Duration>,
+  deadline: Option<Instant>,
+  steps: Cell<u64>,
+  exceeded: Cell<Option<LimitExceeded>>,
+}
+
+impl Budget {
+  // the clock starts now
+  pub fn new(limits: &Limits) -> Self {
+    return Budget {
+      max_steps: limits.max_steps,
+      timeout: limits.timeout,
+      deadline: limits.timeout.map(|t| Instant::now() + t),
+      steps: Cell::new(0),
+      exceeded: Cell::new(None),
+    };
+  }
+
+  pub fn unlimited() -> Self {
+    return Budget::new(&Limits::default());
+  }
+
+  // count a step, false once a limit has been gone over
+  pub fn step(&self) -> bool {
+    if self.exceeded.get().is_some() {
+      return false;
+    }
+    let steps = self.steps.get() + 1;
+    self.steps.set(steps);
+
+    if let Some(max) = self.max_steps {
+      if steps > max {
+        self.exceeded.set(Some(LimitExceeded::Steps(max)));
+        return false;
+      }
+    }
+    if let (Some(deadline), Some(timeout)) = (self.deadline, self.timeout) {
+      if steps.is_multiple_of(STEPS_PER_CLOCK) &&
+         Instant::now() >= deadline {
+        self.exceeded.set(Some(LimitExceeded::Timeout(timeout)));
+        return false;
+      }
+    }
+    return true;
+  }
+
+  // the limit that was gone over, if one was
+  pub fn exceeded(&self) -> Option<LimitExceeded> {
+    return self.exceeded.get();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn budget_steps() {
+    let limits = Limits { max_steps: Some(3), ..Limits::default() };
+    let budget = Budget::new(&limits);
+    assert!(budget.step() && budget.step() && budget.step());
+    assert_eq!(budget.exceeded(), None);
+    assert!(!budget.step());
+    assert_eq!(budget.exceeded(), Some(LimitExceeded::Steps(3)));
+    assert!(!budget.step());
+  }
+
+  #[test]
+  fn budget_timeout() {
+    let limits = Limits {
+      timeout: Some(Duration::ZERO),
+      ..Limits::default()
+    };
+    let budget = Budget::new(&limits);
+    let steps = (0..STEPS_PER_CLOCK).take_while(|_| budget.step()).count();
+    assert_eq!(steps as u64, STEPS_PER_CLOCK - 1);
+    assert_eq!(budget.exceeded(),
+               Some(LimitExceeded::Timeout(Duration::ZERO)));
+  }
+}
@@ -0,0 +1,466 @@
+use crate::aho::AhoCorasick;
+use crate::aho::MatchKind;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::ast::VisitorMut;
+use crate::builder::Semantics;
+use std::ops::Range;
+use std::sync::Arc;
+
+// fewer literals than this are quicker to try one at a time (or to merge into
+// a Charset)
+const MIN_WORDS: usize = 3;
+
+// the literals that every match has to begin with one of, as the bytes they
+// are encoded as in a haystack (bytes is true for `bytes::RegEx`, where units
+// that aren't unicode are single bytes)
+//
+// only looks as far as the first Word of each branch, e.g.: `(ab)+c|d` gives
+// "ab" and "d"; if any branch can begin with something else there are none
+pub(crate) fn prefixes(tree: &TreeNode, bytes: bool) -> Vec<Vec<u8>> {
+  let mut node = tree;
+  loop {
+    match node.n_type {
+      NodeType::Word => { return vec![encode(node, bytes)]; },
+      // anchors read nothing, what comes after them begins the match
+      NodeType::Group | NodeType::MatchGroup => {
+        let mut children = node.children.iter();
+        match children.find(|n| n.n_type != NodeType::Anchor) {
+          Some(n) => { node = n; },
+          None => { return vec![]; },
+        }
+      },
+      // the first repetition has to be there
+      NodeType::Star if node.repeats.min > 0 => {
+        node = &node.children[0];
+      },
+      NodeType::Union => {
+        let mut all = vec![];
+        for n in &node.children {
+          let found = prefixes(n, bytes);
+          if found.is_empty() || found.iter().any(|p| p.is_empty()) {
+            return vec![];
+          }
+          all.extend(found);
+        }
+        return all;
+      },
+      _ => { return vec![]; },
+    }
+  }
+}
+
+// whether a node is a Union of enough literals to be worth an automaton
+pub(crate) fn is_literal_union(node: &TreeNode) -> bool {
+  let words = &node.children;
+  return node.n_type == NodeType::Union && words.len() >= MIN_WORDS &&
+         words.iter().all(|n| {
+           n.n_type == NodeType::Word && !n.image.is_empty() &&
+           n.unicode == words[0].unicode
+         }) &&
+         words.iter().any(|n| n.image.len() > 1);
+}
+
+struct Attach {
+  bytes: bool,
+  kind: MatchKind,
+}
+
+impl VisitorMut for Attach {
+  fn visit_pre(&mut self, node: &mut TreeNode) {
+    if is_literal_union(node) {
+      let words: Vec<Vec<u8>> = node.children.iter()
+                                             .map(|n| encode(n, self.bytes))
+                                             .collect();
+      let ac = AhoCorasick::new(&words, self.kind);
+      node.automaton = Some(Arc::new(ac));
+    }
+  }
+}
+
+// give every Union of many literals an automaton to match them all at once,
+// which prefers the same literal as the Union does with semantics
+pub(crate) fn attach(tree: &mut TreeNode, bytes: bool, semantics: Semantics) {
+  let kind = match semantics {
+    Semantics::LeftmostLongest => MatchKind::LeftmostLongest,
+    Semantics::LeftmostFirst => MatchKind::LeftmostFirst,
+  };
+  tree.visit_mut(&mut Attach { bytes, kind });
+}
+
+/// A literal that every match of an expression contains, which can be looked
+/// for to rule out haystacks (or lines of one) before matching.
+///
+/// Offsets are in bytes, as are positions in a haystack.
+#[derive(Debug, Clone)]
+pub struct Required {
+  literal: Vec<u8>,
+  min_offset: usize,         // fewest bytes of a match before the literal
+  max_offset: Option<usize>, // most bytes before it, None if there's no limit
+  finder: Prefilter,
+}
+
+impl Required {
+  fn new(literal: Vec<u8>, offset: Length) -> Self {
+    let finder = Prefilter::new(vec![literal.clone()]).unwrap();
+    return Required {
+      literal,
+      min_offset: offset.min,
+      max_offset: offset.max,
+      finder,
+    };
+  }
+
+  /// The literal, as the bytes it is encoded as in a haystack.
+  pub fn literal(&self) -> &[u8] {
+    return &self.literal;
+  }
+
+  /// The fewest and most bytes of a match that can come before the literal,
+  /// the most is None if there's no limit.
+  pub fn offsets(&self) -> (usize, Option<usize>) {
+    return (self.min_offset, self.max_offset);
+  }
+
+  /// The first place the literal occurs at or after start.
+  pub fn find(&self, haystack: &[u8], start: usize) -> Option<usize> {
+    return self.finder.find(haystack, start);
+  }
+
+  /// Where a match that contains the literal found at `at` could begin.
+  ///
+  /// No match can begin before the start of the range for the first place
+  /// the literal occurs, since every match has to contain it.
+  pub fn starts(&self, at: usize) -> Range<usize> {
+    let first = match self.max_offset {
+      Some(max) => at.saturating_sub(max),
+      None => 0,
+    };
+    if at < self.min_offset {
+      return first..first;
+    }
+    return first..at - self.min_offset + 1;
+  }
+}
+
+// the fewest and most bytes something can match
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Length {
+  min: usize,
+  max: Option<usize>, // None if there is no limit
+}
+
+impl Length {
+  fn exactly(len: usize) -> Self {
+    return Length { min: len, max: Some(len) };
+  }
+
+  // one thing and then the other
+  fn then(self, other: Length) -> Self {
+    let max = match (self.max, other.max) {
+      (Some(a), Some(b)) => a.checked_add(b),
+      _ => None,
+    };
+    return Length { min: self.min.saturating_add(other.min), max };
+  }
+}
+
+fn length(node: &TreeNode, bytes: bool) -> Length {
+  match node.n_type {
+    NodeType::Word => Length::exactly(encode(node, bytes).len()),
+    NodeType::Charset => {
+      let ascii = node.ranges.iter().all(|r| !r.negate && r.max < 0x80);
+      // a byte, or a character that is 1 to 4 bytes long
+      if ascii || (bytes && !node.unicode) {
+        return Length::exactly(1);
+      }
+      return Length { min: 1, max: Some(4) };
+    },
+    NodeType::Group | NodeType::MatchGroup => {
+      return node.children.iter()
+                          .fold(Length::exactly(0),
+                                |len, n| len.then(length(n, bytes)));
+    },
+    NodeType::Union => {
+      let lens: Vec<Length> = node.children.iter()
+                                           .map(|n| length(n, bytes))
+                                           .collect();
+      let min = lens.iter().map(|l| l.min).min().unwrap_or(0);
+      let max = lens.iter().try_fold(0, |max, l| Some(l.max?.max(max)));
+      return Length { min, max };
+    },
+    NodeType::Star => {
+      let child = length(&node.children[0], bytes);
+      let min = child.min.saturating_mul(node.repeats.min as usize);
+      let max = match (child.max, node.repeats.max) {
+        (Some(0), _) => Some(0),
+        (Some(len), max) if max > 0 => len.checked_mul(max as usize),
+        _ => None,
+      };
+      return Length { min, max };
+    },
+    NodeType::Anchor | NodeType::Empty |
+    NodeType::Error => Length::exactly(0),
+  }
+}
+
+// every literal that a match of node has to contain, given how many bytes
+// of the match can come before node
+fn required_in(node: &TreeNode, bytes: bool, before: Length,
+               out: &mut Vec<Required>) {
+  match node.n_type {
+    NodeType::Word if !node.image.is_empty() => {
+      out.push(Required::new(encode(node, bytes), before));
+    },
+    NodeType::Group | NodeType::MatchGroup => {
+      let mut before = before;
+      for n in &node.children {
+        required_in(n, bytes, before, out);
+        before = before.then(length(n, bytes));
+      }
+    },
+    // the first repetition has to be there
+    NodeType::Star if node.repeats.min > 0 => {
+      required_in(&node.children[0], bytes, before, out);
+    },
+    _ => {},
+  }
+}
+
+// the longest literal that every match has to contain (the first, if there
+// are several as long)
+pub(crate) fn required(tree: &TreeNode, bytes: bool) -> Option<Required> {
+  let mut found = vec![];
+  required_in(tree, bytes, Length::exactly(0), &mut found);
+  let mut best: Option<Required> = None;
+  for r in found {
+    if best.as_ref().is_none_or(|b| r.literal.len() > b.literal.len()) {
+      best = Some(r);
+    }
+  }
+  return best;
+}
+
+fn encode(word: &TreeNode, bytes: bool) -> Vec<u8> {
+  let mut out = vec![];
+  for c in &word.image {
+    if bytes && !word.unicode {
+      out.push(*c as u8);
+    }
+    else {
+      let mut buf = [0; 4];
+      out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+  }
+  return out;
+}
+
+// finds where any of a set of literals occurs, so the matcher only has to be
+// tried there
+#[derive(Debug, Clone)]
+pub(crate) enum Prefilter {
+  Literal {
+    needle: Vec<u8>,
+    // how far the needle can move along when the haystack byte lined up
+    // with its last byte is b (Boyer-Moore-Horspool)
+    skip: Vec<usize>,
+  },
+  Literals(AhoCorasick),
+}
+
+impl Prefilter {
+  // nothing is gained if a literal is empty
+  pub fn new(mut needles: Vec<Vec<u8>>) -> Option<Self> {
+    if needles.is_empty() || needles.iter().any(|n| n.is_empty()) {
+      return None;
+    }
+    if needles.len() > 1 {
+      let ac = AhoCorasick::new(&needles, MatchKind::LeftmostFirst);
+      return Some(Prefilter::Literals(ac));
+    }
+
+    let needle = needles.pop().unwrap();
+    let mut skip = vec![needle.len(); 256];
+    for (i, b) in needle[..needle.len() - 1].iter().enumerate() {
+      skip[*b as usize] = needle.len() - 1 - i;
+    }
+    return Some(Prefilter::Literal { needle, skip });
+  }
+
+  // the first position at or after start that a literal occurs at
+  pub fn find(&self, haystack: &[u8], start: usize) -> Option<usize> {
+    let (needle, skip) = match self {
+      Prefilter::Literal { needle, skip } => (needle, skip),
+      Prefilter::Literals(ac) => {
+        return ac.find(haystack, start).map(|m| m.start);
+      },
+    };
+
+    let rest = haystack.get(start..)?;
+    if needle.len() == 1 {
+      return find_byte(needle[0], rest).map(|i| start + i);
+    }
+
+    let last = needle.len() - 1;
+    let mut i = 0;
+    while i + last < rest.len() {
+      let b = rest[i + last];
+      if b == needle[last] && rest[i..i + last] == needle[..last] {
+        return Some(start + i);
+      }
+      i += skip[b as usize];
+    }
+    return None;
+  }
+}
+
+// like memchr, compares a word at a time until a chunk might hold the byte
+fn find_byte(needle: u8, haystack: &[u8]) -> Option<usize> {
+  const LO: u64 = 0x0101_0101_0101_0101;
+  const HI: u64 = 0x8080_8080_8080_8080;
+  let repeated = LO * needle as u64;
+
+  let mut offset = 0;
+  for chunk in haystack.chunks_exact(8) {
+    let x = u64::from_le_bytes(chunk.try_into().unwrap()) ^ repeated;
+    // a byte of x is zero where the chunk has the needle
+    if (x.wrapping_sub(LO) & !x & HI) != 0 {
+      break;
+    }
+    offset += 8;
+  }
+  return haystack[offset..].iter().position(|b| *b == needle)
+                           .map(|i| offset + i);
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::optimize::optimize;
+  use super::*;
+
+  fn find_all(needles: &[&str], haystack: &str) -> Vec<usize> {
+    let needles = needles.iter().map(|n| n.as_bytes().to_vec()).collect();
+    let p = Prefilter::new(needles).unwrap();
+    let mut found = vec![];
+    let mut start = 0;
+    while let Some(i) = p.find(haystack.as_bytes(), start) {
+      found.push(i);
+      start = i + 1;
+    }
+    return found;
+  }
+
+  fn prefixes_of(expr: &str) -> Vec<String> {
+    let tree = ast::parse(expr).unwrap();
+    return prefixes(&tree, false).into_iter()
+                                 .map(|p| String::from_utf8(p).unwrap())
+                                 .collect();
+  }
+
+  #[test]
+  fn prefixes_of_tree() {
+    assert_eq!(prefixes_of("ERROR: \\w+"), vec!["ERROR: "]);
+    assert_eq!(prefixes_of("(ab)+c"), vec!["ab"]);
+    assert_eq!(prefixes_of("\u{e9}x"), vec!["\u{e9}x"]);
+    assert_eq!(prefixes_of("(ab|c)d|e+f"), vec!["ab", "c", "e"]);
+    assert!(prefixes_of("(ab|c)d|e*f").is_empty());
+    assert!(prefixes_of("((\u{e9}x)?)").is_empty());
+    assert!(prefixes_of("a|").is_empty());
+    assert!(prefixes_of("[ab]c").is_empty());
+    assert!(prefixes_of("").is_empty());
+    assert_eq!(prefixes_of("^(?m)^ab|$c"), vec!["ab", "c"]);
+    assert!(prefixes_of("^$").is_empty());
+  }
+
+  #[test]
+  fn prefixes_of_bytes_tree() {
+    let prefixes_of = |expr| prefixes(&ast::parse_bytes(expr).unwrap(), true);
+    assert_eq!(prefixes_of("\\x41\\xFFb"), vec![b"A\xFF".to_vec()]);
+    assert_eq!(prefixes_of("\u{e9}\\xFF"), vec![b"\xC3\xA9".to_vec()]);
+  }
+
+  fn required_of(expr: &str) -> Option<(String, usize, Option<usize>)> {
+    let tree = optimize(ast::parse(expr).unwrap());
+    return required(&tree, false).map(|r| {
+      let literal = String::from_utf8(r.literal().to_vec()).unwrap();
+      (literal, r.min_offset, r.max_offset)
+    });
+  }
+
+  #[test]
+  fn required_literals() {
+    assert_eq!(required_of("\\w+@example\\.com"),
+               Some((String::from("@example.com"), 1, None)));
+    assert_eq!(required_of("x\\d\\d-(abc|d)ef+"),
+               Some((String::from("x"), 0, Some(0))));
+    assert_eq!(required_of("[ab]?.(cd)+(e|f)gh"),
+               Some((String::from("cd"), 1, Some(5))));
+    assert_eq!(required_of("\u{e9}?abc"),
+               Some((String::from("abc"), 0, Some(2))));
+    assert_eq!(required_of("a*|b"), None);
+    assert_eq!(required_of("(ab)?c*"), None);
+  }
+
+  #[test]
+  fn required_bytes_offsets() {
+    let tree = optimize(ast::parse_bytes("(?-u).\\xFFa(?u).abc").unwrap());
+    let r = required(&tree, true).unwrap();
+    assert_eq!(r.literal(), b"abc");
+    assert_eq!(r.offsets(), (4, Some(7)));
+  }
+
+  #[test]
+  fn required_starts() {
+    let r = required(&ast::parse("a.?bcd").unwrap(), false).unwrap();
+    assert_eq!(r.offsets(), (1, Some(5)));
+    assert_eq!(r.find(b"xxabcd", 0), Some(3));
+    assert_eq!(r.starts(3), 0..3);
+    assert_eq!(r.starts(9), 4..9);
+    assert_eq!(r.starts(0), 0..0);
+  }
+
+  #[test]
+  fn literal_unions() {
+    let union = |expr| optimize(ast::parse(expr).unwrap()).children.remove(0);
+    assert!(is_literal_union(&union("foo|bar|baz")));
+    assert!(!is_literal_union(&union("foo|bar")));
+    assert!(!is_literal_union(&union("a|b|c")));
+    assert!(!is_literal_union(&union("foo|bar|")));
+    assert!(!is_literal_union(&union("foo|bar|b(a)z")));
+  }
+
+  #[test]
+  fn find_one_byte() {
+    assert_eq!(find_all(&["x"], "x..x.........x...............x"),
+               vec![0, 3, 13, 29]);
+    assert_eq!(find_all(&["x"], "................"), vec![]);
+    assert_eq!(find_all(&["\u{80}"], "\u{100}\u{80}"), vec![2]);
+  }
+
+  #[test]
+  fn find_many_bytes() {
+    assert_eq!(find_all(&["abab"], "abababcabab"), vec![0, 2, 7]);
+    assert_eq!(find_all(&["ERROR:"], "ok\nERROR: a\nERR\nERROR:"),
+               vec![3, 16]);
+    assert_eq!(find_all(&["abc"], "ab"), vec![]);
+    assert_eq!(find_all(&["aab"], "aaab"), vec![1]);
+  }
+
+  #[test]
+  fn find_any_literal() {
+    assert_eq!(find_all(&["ERROR", "WARN"], "ok WARN x ERROR"), vec![3, 10]);
+    assert_eq!(find_all(&["bc", "abcd"], "abc abcd"), vec![1, 4, 5]);
+  }
+
+  #[test]
+  fn find_past_end() {
+    let p = Prefilter::new(vec![b"a".to_vec()]).unwrap();
+    assert_eq!(p.find(b"a", 1), None);
+    assert_eq!(p.find(b"a", 2), None);
+    let p = Prefilter::new(vec![b"a".to_vec(), b"b".to_vec()]).unwrap();
+    assert_eq!(p.find(b"a", 2), None);
+    assert!(Prefilter::new(vec![]).is_none());
+    assert!(Prefilter::new(vec![b"a".to_vec(), vec![]]).is_none());
+  }
+}
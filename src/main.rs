@@ -1,17 +1,48 @@
-mod regex;
-mod scanner;
-mod parser;
-mod tui;
+use rex::builder::Engine;
+use rex::builder::RegExBuilder;
+use rex::builder::Semantics;
+use rex::bytes;
+use rex::diagnostic::Diagnostic;
+use rex::error;
+use rex::limits::LimitExceeded;
+use rex::limits::Limits;
+use rex::literal::Required;
+use rex::regex::RegEx;
 
 use std::env;
 use std::fs;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
 use std::time::Instant;
 
 struct ExecOptions {
   no_groups: bool,  // don't print matching groups
   benchmark: bool,  // print a benchmark for execution time
+  redos: bool,      // only check the expression for catastrophic backtracking
+  jobs: usize,      // files searched at once, 0 for one per core
+  // the rest are passed to the RegExBuilder method of the same name
+  case_insensitive: bool,
+  dot_all: bool,
+  multi_line: bool,
+  extended: bool,
+  unicode: bool,
+  semantics: Semantics,
+  engine: Engine,
+  limits: Limits,
 }
 
 impl ExecOptions {
@@ -19,45 +50,370 @@ impl ExecOptions {
     return ExecOptions {
       no_groups: false,
       benchmark: false,
+      redos: false,
+      jobs: 0,
+      case_insensitive: false,
+      dot_all: false,
+      multi_line: false,
+      extended: false,
+      unicode: true,
+      semantics: Semantics::LeftmostLongest,
+      engine: Engine::Auto,
+      limits: Limits::default(),
     };
   }
+
+  fn builder(&self, expr: &str) -> RegExBuilder {
+    return RegExBuilder::new(expr).case_insensitive(self.case_insensitive)
+                                  .dot_all(self.dot_all)
+                                  .multi_line(self.multi_line)
+                                  .extended(self.extended)
+                                  .unicode(self.unicode)
+                                  .semantics(self.semantics)
+                                  .engine(self.engine)
+                                  .limits(self.limits);
+  }
+}
+
+// why a file's matches couldn't be printed
+enum Failure {
+  Unread(io::Error),     // the file couldn't be read
+  Build(error::Error),   // the expression can't match bytes
+  Limit(LimitExceeded),  // a search went over the limits
+}
+
+// the order files are printed in, whichever order they're searched in
+struct Output<'a> {
+  printing: AtomicUsize, // the first file that hasn't been printed
+  state: Mutex<Printed>,
+  turn: Condvar, // woken each time a file has been printed
+  window: usize, // how many files from printing on may be searched at once
+  files: &'a [PathBuf],
+  expr: &'a str,
+  builder: &'a RegExBuilder,
+}
+
+struct Printed {
+  // the files after the one being printed that are finished, with what
+  // they print
+  finished: BTreeMap<usize, (Vec<u8>, Result<(), Failure>)>,
+  unread: bool, // a file couldn't be read
+}
+
+impl Output<'_> {
+  // wait until file is close enough to the one being printed to search it,
+  // so only so much output is held back
+  fn wait_turn(&self, file: usize) {
+    let mut state = self.state.lock().unwrap();
+    while file >= self.printing.load(Ordering::Acquire) + self.window {
+      state = self.turn.wait(state).unwrap();
+    }
+  }
+
+  // print file, once the files before it have been, along with any after it
+  // that were waiting on it
+  fn finish(&self, file: usize, held: Vec<u8>, searched: Result<(), Failure>) {
+    let mut state = self.state.lock().unwrap();
+    state.finished.insert(file, (held, searched));
+
+    let mut out = io::stdout().lock();
+    let mut printing = self.printing.load(Ordering::Acquire);
+    while let Some((held, searched)) = state.finished.remove(&printing) {
+      out.write_all(&held).unwrap();
+      // the expression failing stops the search where it failed, so it ends
+      // the same way however the files were shared out
+      match searched {
+        Ok(()) => {
+          // Empty
+        },
+        Err(Failure::Unread(e)) => {
+          let path = self.files[printing].display();
+          eprintln!("error: can't read {}: {}", path, e);
+          state.unread = true;
+        },
+        Err(Failure::Build(e)) => {
+          out.flush().unwrap();
+          exit_with_errors(self.expr, e, self.builder.check_bytes());
+        },
+        Err(Failure::Limit(e)) => {
+          out.flush().unwrap();
+          exit_with_limit(e);
+        },
+      }
+      printing += 1;
+      self.printing.store(printing, Ordering::Release);
+    }
+    self.turn.notify_all();
+  }
+}
+
+// where a file's matches are written: straight to stdout once every file
+// before it has been printed, and held back until then
+struct FileOut<'o, 'a> {
+  file: usize,
+  output: &'o Output<'a>,
+  held: Vec<u8>,
 }
 
-fn execute_headless(filename: String, expr: String, options: &ExecOptions) {
-  // load file from args
-  // TODO: better handling for load failed
-  let file_text = fs::read_to_string(&filename)
-                      .expect("Failed to load file");
-  let file_lines = file_text.split("\n");
+impl Write for FileOut<'_, '_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    // nothing else is printed while it's this file's turn
+    if self.output.printing.load(Ordering::Acquire) == self.file {
+      let mut out = io::stdout().lock();
+      out.write_all(&self.held)?;
+      self.held.clear();
+      return out.write(buf);
+    }
+    self.held.extend_from_slice(buf);
+    return Ok(buf.len());
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    return io::stdout().flush();
+  }
+}
+
+fn execute_headless(paths: Vec<String>, expr: String, options: &ExecOptions) {
+  let mut files = vec![];
+  for p in &paths {
+    collect_files(Path::new(p), true, &mut files);
+  }
+  // say which file each match is from when there could be more than one
+  let named = files.len() > 1 || paths.iter().any(|p| Path::new(p).is_dir());
+
+  let builder = options.builder(&expr);
+  let re = builder.build()
+                  .unwrap_or_else(|e| exit_with_errors(&expr, e,
+                                                       builder.check()));
+  // only built if a file isn't valid utf-8
+  let re_bytes = OnceLock::new();
 
   let start_time = Instant::now();
 
-  let re = regex::RegEx::new(&expr);
+  let jobs = match options.jobs {
+    0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    jobs => jobs,
+  };
+  let next = AtomicUsize::new(0);
+  let output = Output {
+    printing: AtomicUsize::new(0),
+    state: Mutex::new(Printed { finished: BTreeMap::new(), unread: false }),
+    turn: Condvar::new(),
+    // with one job the file searched is always the one being printed
+    window: jobs,
+    expr: &expr,
+    builder: &builder,
+    files: &files,
+  };
+  thread::scope(|s| {
+    for _ in 0..jobs.min(files.len()) {
+      let (files, next, re, re_bytes) = (&files, &next, &re, &re_bytes);
+      let output = &output;
+      let expr = expr.as_str();
+      s.spawn(move || {
+        // each worker takes whichever file is next until there are none
+        loop {
+          let i = next.fetch_add(1, Ordering::Relaxed);
+          if i >= files.len() {
+            break;
+          }
+          output.wait_turn(i);
+          let mut out = FileOut { file: i, output, held: vec![] };
+          let searched = search_file(&files[i], named, re, re_bytes, expr,
+                                     options, &mut out);
+          output.finish(i, out.held, searched);
+        }
+      });
+    }
+  });
+
+  let elapsed_time = start_time.elapsed();
+  if options.benchmark {
+    println!("Execution time: {:.2?}", elapsed_time);
+  }
+  if output.state.lock().unwrap().unread {
+    process::exit(1);
+  }
+}
+
+// every file at path, in name order so the output always comes in the same
+// order (links to directories inside directories aren't followed, so there
+// are no loops)
+fn collect_files(path: &Path, given: bool, files: &mut Vec<PathBuf>) {
+  let meta = if given { fs::metadata(path) }
+             else { fs::symlink_metadata(path) };
+  let is_dir = meta.map(|m| m.is_dir()).unwrap_or(false);
+  if !is_dir {
+    if given || !path.is_dir() {
+      files.push(path.to_path_buf());
+    }
+    return;
+  }
+
+  let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+    Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+    Err(e) => {
+      eprintln!("error: can't read {}: {}", path.display(), e);
+      return;
+    },
+  };
+  entries.sort();
+  for e in entries {
+    collect_files(&e, false, files);
+  }
+}
+
+// print a file's matches to out, starting each with the file's name if
+// named
+fn search_file(path: &Path, named: bool, re: &RegEx,
+               re_bytes: &OnceLock<Result<bytes::RegEx, error::Error>>,
+               expr: &str, options: &ExecOptions, out: &mut impl Write)
+  -> Result<(), Failure> {
+  let file_bytes = fs::read(path).map_err(Failure::Unread)?;
+  let name = if named { format!("{}:", path.display()) } else { String::new() };
+
+  // fall back to matching bytes if the file isn't valid utf-8
+  let searched = match String::from_utf8(file_bytes) {
+    Ok(file_text) => search_text(&file_text, re, &name, options, out),
+    Err(e) => {
+      let re = re_bytes.get_or_init(|| options.builder(expr).build_bytes())
+                       .as_ref().map_err(|e| Failure::Build(e.clone()))?;
+      search_bytes(e.as_bytes(), re, &name, options, out)
+    },
+  };
+  return searched.map_err(Failure::Limit);
+}
+
+fn search_text(file_text: &str, re: &RegEx, name: &str,
+               options: &ExecOptions, out: &mut impl Write)
+  -> Result<(), LimitExceeded> {
+  let file_lines = file_text.split('\n');
+
   for l in file_lines {
-    let match_data = re.match_all(String::from(l));
+    let start = match search_start(re.required(), l.as_bytes()) {
+      Some(start) => start,
+      None => { continue; },
+    };
+
+    let mut matches = re.captures_iter_at(l, start);
+    while let Some(m) = matches.try_next()? {
+      // don't print if benchmarking -- waste of time
+      if options.benchmark {
+        continue;
+      }
 
-    // don't print if benchmarking -- waste of time
-    if options.benchmark {
-      continue;
+      writeln!(out, "{}{}", name, m.get_match().as_str()).unwrap();
+      if !options.no_groups {
+        // groups that didn't participate in the match aren't printed
+        for g in 1..m.len() {
+          if let Some(group) = m.get(g) {
+            writeln!(out, "  {}: {}", g, group.as_str()).unwrap();
+          }
+        }
+      }
     }
+  }
+  return Ok(());
+}
+
+fn search_bytes(file_bytes: &[u8], re: &bytes::RegEx, name: &str,
+                options: &ExecOptions, out: &mut impl Write)
+  -> Result<(), LimitExceeded> {
+  let file_lines = file_bytes.split(|b| *b == b'\n');
 
-    for m in match_data {
-      println!("{}", m.groups[0].string);
+  for l in file_lines {
+    let start = match search_start(re.required(), l) {
+      Some(start) => start,
+      None => { continue; },
+    };
+
+    // print matches as raw bytes, like the input they came from
+    let mut matches = re.captures_iter_at(l, start);
+    while let Some(m) = matches.try_next()? {
+      // don't print if benchmarking -- waste of time
+      if options.benchmark {
+        continue;
+      }
+
+      out.write_all(name.as_bytes()).unwrap();
+      out.write_all(m.get_match().as_bytes()).unwrap();
+      out.write_all(b"\n").unwrap();
       if !options.no_groups {
-        for g in 1..m.groups.len() {
-          println!("  {}: {}", g, m.groups[g].string);
+        for g in 1..m.len() {
+          if let Some(group) = m.get(g) {
+            write!(out, "  {}: ", g).unwrap();
+            out.write_all(group.as_bytes()).unwrap();
+            out.write_all(b"\n").unwrap();
+          }
         }
       }
     }
   }
+  return Ok(());
+}
 
-  let elapsed_time = start_time.elapsed();
-  if options.benchmark {
-    println!("Execution time: {:.2?}", elapsed_time);
+// where to start looking for matches in a line, or None if it can't have any
+// because it doesn't contain the literal that every match does
+fn search_start(required: Option<&Required>, line: &[u8]) -> Option<usize> {
+  return match required {
+    // no match can begin before the first one that could contain it
+    Some(req) => req.find(line, 0).map(|at| req.starts(at).start),
+    None => Some(0),
+  };
+}
+
+// print everything wrong with the expression and where, then give up
+// (if it parses, what's wrong is with how it compiled)
+fn exit_with_errors(expr: &str, error: error::Error,
+                    errors: Vec<error::Error>) -> ! {
+  let errors = if errors.is_empty() { vec![error] } else { errors };
+  let color = io::stderr().is_terminal();
+  for e in &errors {
+    eprint!("{}", Diagnostic::new(expr, e).color(color));
   }
+  process::exit(1);
+}
+
+// a search went over the limits, so its line can't be said to match or not
+fn exit_with_limit(e: LimitExceeded) -> ! {
+  eprintln!("error: {}", e);
+  process::exit(1);
+}
+
+// the number after a flag, or give up if there isn't one
+fn number<T: FromStr>(args: &mut VecDeque<String>, flag: &str) -> T {
+  return match args.pop_front().and_then(|n| n.parse().ok()) {
+    Some(n) => n,
+    None => exit_with_usage(&format!("{} needs a number", flag)),
+  };
+}
+
+fn exit_with_usage(message: &str) -> ! {
+  eprintln!("{}", message);
+  process::exit(1);
 }
 
-fn execute_interactive(filename: String, options: &ExecOptions) {
+// warn about every part of the expression that could backtrack too much,
+// failing if there are any
+fn execute_redos(expr: &str, options: &ExecOptions) {
+  let found = match options.builder(expr).analyze() {
+    Ok(found) => found,
+    Err(mut errors) => {
+      let error = errors.remove(0);
+      exit_with_errors(expr, error, errors);
+    },
+  };
+
+  let color = io::stderr().is_terminal();
+  for v in &found {
+    eprint!("{}", Diagnostic::warning(expr, v).color(color));
+  }
+  if !found.is_empty() {
+    process::exit(1);
+  }
+}
+
+fn execute_interactive(_filename: String, _options: &ExecOptions) {
   println!("TODO: interactive");
 }
 
@@ -65,46 +421,72 @@ fn parse_args() {
   let mut args: VecDeque<String> = env::args().collect();
 
   let mut expr: Option<String> = None;
-  let mut filename: Option<String> = None;
+  let mut paths: Vec<String> = vec![];
   let mut options = ExecOptions::new();
 
   args.pop_front(); // skip first arg (executable path)
 
-  while args.len() > 0 {
+  while !args.is_empty() {
     let a = args.pop_front().unwrap();
 
     // check for flags
-    if &a == "-ng" || &a == "--no-groups" {
-      options.no_groups = true;
-    }
-    if &a == "-b" || &a == "--benchmark" {
-      options.benchmark = true;
-    }
-
-    // if no filename, try to find one
-    if matches!(filename, None) {
-      let potential_path = Path::new(&a);
-      // is a filename set it
-      if potential_path.is_file() {
-        filename = Some(a);
-        continue;
-      }
-    }
-
-    // otherwise, assume its an expression
-    if matches!(expr, None) {
-      expr = Some(a);
-      continue;
+    match a.as_str() {
+      "-ng" | "--no-groups" => { options.no_groups = true; },
+      "-b" | "--benchmark" => { options.benchmark = true; },
+      "--redos" => { options.redos = true; },
+      "-j" | "--jobs" => { options.jobs = number(&mut args, &a); },
+      // options for the expression
+      "-i" | "--ignore-case" => { options.case_insensitive = true; },
+      "-s" | "--dot-all" => { options.dot_all = true; },
+      "-m" | "--multi-line" => { options.multi_line = true; },
+      "-x" | "--extended" => { options.extended = true; },
+      "--no-unicode" => { options.unicode = false; },
+      "--first" => { options.semantics = Semantics::LeftmostFirst; },
+      "--engine" => {
+        options.engine = match args.pop_front().as_deref() {
+          Some("auto") => Engine::Auto,
+          Some("interpreter") => Engine::Interpreter,
+          Some("backtracker") => Engine::Backtracker,
+          Some("onepass") => Engine::OnePass,
+          _ => exit_with_usage("--engine needs one of auto, interpreter, \
+                                backtracker or onepass"),
+        };
+      },
+      "--max-steps" => {
+        options.limits.max_steps = Some(number(&mut args, &a));
+      },
+      "--max-depth" => { options.limits.max_depth = number(&mut args, &a); },
+      "--size-limit" => {
+        options.limits.max_size = Some(number(&mut args, &a));
+      },
+      // the longest a search of one line may take, in milliseconds
+      "-t" | "--timeout" => {
+        let ms = number(&mut args, &a);
+        options.limits.timeout = Some(Duration::from_millis(ms));
+      },
+      _ => {
+        // not a flag, so a file or directory that exists, or the
+        // expression, after which everything is a path
+        if expr.is_some() || Path::new(&a).exists() {
+          paths.push(a);
+        }
+        else {
+          expr = Some(a);
+        }
+      },
     }
   }
 
   // choose mode based on provided args
-  match (filename, expr) {
-    (Some(f), Some(e)) => {
-      execute_headless(f, e, &options);
+  match (paths.is_empty(), expr) {
+    (_, Some(e)) if options.redos => {
+      execute_redos(&e, &options);
+    },
+    (false, Some(e)) => {
+      execute_headless(paths, e, &options);
     },
-    (Some(f), None) => {
-      execute_interactive(f, &options);
+    (false, None) => {
+      execute_interactive(paths.remove(0), &options);
     },
     _ => {
       println!("invalid argument set");
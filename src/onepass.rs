@@ -0,0 +1,266 @@
+use crate::ast::unit_set;
+use crate::program::Inst;
+use crate::program::Program;
+use crate::regex::Haystack;
+use crate::regex::MatchPositions;
+
+// where a step through the program can go without reading anything
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+  Consume(usize), // the instruction that reads the next unit
+  Match,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+  target: Target,
+  saves: Vec<usize>, // capture slots to set to the current position
+}
+
+// a matcher for programs that never have to choose between two ways to go
+// on: at each position the next unit (or the end of the haystack) decides
+// the only way forward, so one scan fills in the groups without backtracking
+// or keeping track of more than one thread
+//
+// only used for full matches, which have to reach the end of the haystack
+#[derive(Debug, Clone)]
+pub struct OnePass {
+  insts: Vec<Inst>,
+  // everywhere the program can go from the start, and after each
+  // instruction that reads a unit (by its index)
+  start: Vec<Step>,
+  after: Vec<Vec<Step>>,
+  group_count: usize,
+}
+
+impl OnePass {
+  // None if the program is ambiguous anywhere (bytes is true for
+  // `bytes::RegEx`, where units that aren't unicode are single bytes)
+  pub fn new(prog: &Program, group_count: usize, bytes: bool)
+    -> Option<Self> {
+    let start = closure(prog, prog.start, bytes)?;
+    let mut after = vec![vec![]; prog.insts.len()];
+    for (pc, inst) in prog.insts.iter().enumerate() {
+      if matches!(inst, Inst::Char { .. } | Inst::Ranges { .. }) {
+        after[pc] = closure(prog, pc + 1, bytes)?;
+      }
+    }
+
+    return Some(OnePass {
+      insts: prog.insts.clone(),
+      start,
+      after,
+      group_count,
+    });
+  }
+
+  // match the whole haystack in one pass, or return None
+  pub fn full_match<H: Haystack + ?Sized>(&self, haystack: &H)
+    -> Option<MatchPositions> {
+    let mut slots = vec![None; self.group_count * 2];
+    let mut steps = &self.start;
+    let mut i = 0;
+    loop {
+      // the only way to finish is to match
+      if i == haystack.len() {
+        let step = steps.iter().find(|s| s.target == Target::Match)?;
+        for slot in &step.saves {
+          slots[*slot] = Some(i);
+        }
+        break;
+      }
+
+      // at most one instruction here can read the next unit
+      let (step, pc, next) = steps.iter().find_map(|s| {
+        let pc = match s.target {
+          Target::Consume(pc) => pc,
+          Target::Match => { return None; },
+        };
+        return self.read(pc, haystack, i).map(|next| (s, pc, next));
+      })?;
+      for slot in &step.saves {
+        slots[*slot] = Some(i);
+      }
+      steps = &self.after[pc];
+      i = next;
+    }
+
+    let mut m = MatchPositions::new(0, i, self.group_count);
+    m.groups[0] = Some((0, i));
+    for g in 1..self.group_count {
+      if let (Some(s), Some(e)) = (slots[g * 2], slots[g * 2 + 1]) {
+        m.groups[g] = Some((s, e));
+      }
+    }
+    return Some(m);
+  }
+
+  // the index after the unit at i, if the instruction at pc reads it
+  fn read<H: Haystack + ?Sized>(&self, pc: usize, haystack: &H, i: usize)
+    -> Option<usize> {
+    return match &self.insts[pc] {
+      Inst::Char { c, unicode } => {
+        let (u, next) = haystack.unit_at(i, *unicode)?;
+        if u == *c { Some(next) } else { None }
+      },
+      Inst::Ranges { ranges, unicode } => {
+        let (u, next) = haystack.unit_at(i, *unicode)?;
+        if ranges.iter().any(|r| r.includes(u)) { Some(next) } else { None }
+      },
+      _ => None,
+    };
+  }
+}
+
+// everywhere the program can get to from pc without reading anything
+// None if it can get somewhere two ways, or to two instructions that could
+// read the same unit, since then the next unit doesn't decide where to go
+fn closure(prog: &Program, pc: usize, bytes: bool) -> Option<Vec<Step>> {
+  let mut steps = vec![];
+  let mut seen = vec![false; prog.insts.len()];
+  follow(prog, pc, vec![], &mut seen, &mut steps)?;
+
+  // the units each step reads, which have to be apart
+  let mut sets: Vec<Vec<(u32, u32)>> = vec![];
+  let mut unicode = None;
+  for s in &steps {
+    let pc = match s.target {
+      Target::Consume(pc) => pc,
+      Target::Match => { continue; },
+    };
+    let (set, u) = match &prog.insts[pc] {
+      Inst::Char { c, unicode } => (vec![(*c, *c)], *unicode),
+      Inst::Ranges { ranges, unicode } => (unit_set(ranges), *unicode),
+      _ => unreachable!(),
+    };
+    // a byte and a character can't be compared
+    if bytes && unicode.is_some_and(|other| other != u) {
+      return None;
+    }
+    unicode = Some(u);
+    if sets.iter().any(|other| overlap(other, &set)) {
+      return None;
+    }
+    sets.push(set);
+  }
+
+  if steps.iter().filter(|s| s.target == Target::Match).count() > 1 {
+    return None;
+  }
+  return Some(steps);
+}
+
+fn follow(prog: &Program, pc: usize, saves: Vec<usize>, seen: &mut [bool],
+          steps: &mut Vec<Step>) -> Option<()> {
+  if seen[pc] {
+    return None;
+  }
+  seen[pc] = true;
+
+  match &prog.insts[pc] {
+    Inst::Char { .. } | Inst::Ranges { .. } => {
+      steps.push(Step { target: Target::Consume(pc), saves });
+    },
+    Inst::Match(_) => {
+      steps.push(Step { target: Target::Match, saves });
+    },
+    Inst::Split(a, b) => {
+      follow(prog, *a, saves.clone(), seen, steps)?;
+      follow(prog, *b, saves, seen, steps)?;
+    },
+    Inst::Jmp(to) => follow(prog, *to, saves, seen, steps)?,
+    Inst::Save(slot) => {
+      let mut saves = saves;
+      saves.push(*slot);
+      follow(prog, pc + 1, saves, seen, steps)?;
+    },
+    Inst::Fail => {},
+    // whether an anchor holds depends on where it is, which the steps
+    // don't keep track of
+    Inst::Assert(_) => { return None; },
+  }
+  return Some(());
+}
+
+fn overlap(a: &[(u32, u32)], b: &[(u32, u32)]) -> bool {
+  let (mut i, mut j) = (0, 0);
+  while i < a.len() && j < b.len() {
+    if a[i].1 < b[j].0 {
+      i += 1;
+    }
+    else if b[j].1 < a[i].0 {
+      j += 1;
+    }
+    else {
+      return true;
+    }
+  }
+  return false;
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::ast::CharRange;
+  use crate::ast::MAX_UNIT;
+  use crate::optimize::optimize;
+  use crate::regex::RegExEnv;
+  use super::*;
+
+  fn one_pass(expr: &str) -> Option<OnePass> {
+    let tree = optimize(ast::parse(expr).unwrap());
+    let groups = expr.matches('(').count() + 1;
+    return OnePass::new(&Program::compile_many(&[&tree]), groups, false);
+  }
+
+  #[test]
+  fn one_pass_patterns() {
+    for expr in ["(\\d\\d\\d\\d)-(\\d\\d)-(\\d\\d)", "a*b", "(a|b)*c",
+                 "x(|y)", "[a-c]+[^a-c]", "(\\w+)@(\\w+)\\.com", ""] {
+      assert!(one_pass(expr).is_some(), "{}", expr);
+    }
+  }
+
+  #[test]
+  fn not_one_pass_patterns() {
+    for expr in ["a*a", "(a|b)*a", "(a*)*", "\\w+\\d", "(|b)b", "[^a]*b",
+                 "a?a", "^a$"] {
+      assert!(one_pass(expr).is_none(), "{}", expr);
+    }
+  }
+
+  #[test]
+  fn unit_sets() {
+    let ranges = [CharRange::new(5, 9, false), CharRange::new(0, 3, false),
+                  CharRange::new(4, 4, false)];
+    assert_eq!(unit_set(&ranges), vec![(0, 9)]);
+    let ranges = [CharRange::new(1, 2, true)];
+    assert_eq!(unit_set(&ranges), vec![(0, 0), (3, MAX_UNIT)]);
+    assert!(overlap(&[(0, 3), (8, 9)], &[(4, 5), (9, 12)]));
+    assert!(!overlap(&[(0, 3), (8, 9)], &[(4, 7), (10, 12)]));
+  }
+
+  // the one pass matcher finds the same full matches as the interpreter
+  fn same_matches(expr: &str, haystacks: &[&str]) {
+    let tree = optimize(ast::parse(expr).unwrap());
+    let groups = expr.matches('(').count() + 1;
+    let op = one_pass(expr).unwrap();
+    for h in haystacks {
+      let env = RegExEnv::new(*h, groups);
+      let a = env.match_at(&tree, 0).filter(|m| m.end == h.len())
+                                     .map(|m| m.groups);
+      let b = op.full_match(*h).map(|m| m.groups);
+      assert_eq!(a, b, "{} on {:?}", expr, h);
+    }
+  }
+
+  #[test]
+  fn one_pass_full_matches() {
+    same_matches("(\\d\\d\\d\\d)-(\\d\\d)-(\\d\\d)",
+                 &["2024-01-31", "2024-1-31", "2024-01-31x", ""]);
+    same_matches("((a)|b)*c", &["abac", "bbc", "c", "ab", "abcc"]);
+    same_matches("x(|y)(z)?", &["x", "xy", "xz", "xyz", "xzy"]);
+    same_matches("(\\w+)@(\\w+)\\.com", &["al@mail.com", "al@mail.co"]);
+    same_matches("(\u{e9}+)(\\d)", &["\u{e9}\u{e9}1", "\u{e9}"]);
+  }
+}
@@ -0,0 +1,364 @@
+use crate::ast::CharRange;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::error::Span;
+use crate::literal;
+
+// simplify a parsed tree so it is quicker to match
+//
+// the result matches exactly the same things (with the same groups) as the
+// tree it came from, with either engine:
+// - adjacent Words are merged, and Groups that don't capture are flattened
+//   into the sequence they are in (or replaced by their only child)
+// - literal prefixes shared by adjacent branches of a Union are factored out:
+//   `abc|abd` -> `ab(c|d)` (without the capture)
+// - adjacent branches that match a single character become one Charset:
+//   `a|[0-9]|c` -> `[a0-9c]`
+//
+// Repetitions are never collapsed into each other: one can only be written
+// inside another with a capture between them (`(a+)*`), and the capture
+// has to keep the text of the last iteration.
+// Unions of many literals are left alone to be matched by an automaton (see
+// `literal::attach`), and only *adjacent* branches are combined, so that the
+// order that branches are preferred in doesn't change
+pub fn optimize(mut tree: TreeNode) -> TreeNode {
+  // the root is always a Group, even if it only has one child
+  let children = std::mem::take(&mut tree.children);
+  tree.children = sequence(children.into_iter().map(simplify).collect());
+  return tree;
+}
+
+fn simplify(mut node: TreeNode) -> TreeNode {
+  let mut children = std::mem::take(&mut node.children);
+  // `a|b|c` is parsed as `a|(b|c)`, all of the branches have to be seen at
+  // once to tell if it is a Union of many literals
+  if node.n_type == NodeType::Union {
+    children = branches(children);
+  }
+  let children: Vec<TreeNode> = children.into_iter().map(simplify).collect();
+
+  match node.n_type {
+    NodeType::Group => {
+      node.children = sequence(children);
+      return match node.children.len() {
+        0 => empty(node.span),
+        1 => node.children.pop().unwrap(),
+        _ => node,
+      };
+    },
+    NodeType::MatchGroup => {
+      node.children = sequence(children);
+      return node;
+    },
+    NodeType::Union => {
+      node.children = children;
+      return simplify_union(node);
+    },
+    _ => {
+      node.children = children;
+      return node;
+    },
+  }
+}
+
+// a Word that matches nothing (which is how the parser writes `(|a)`)
+fn empty(span: Span) -> TreeNode {
+  let mut node = TreeNode::new(NodeType::Word);
+  node.span = span;
+  return node;
+}
+
+// the children of a Group or MatchGroup, which are matched one after another
+fn sequence(children: Vec<TreeNode>) -> Vec<TreeNode> {
+  let mut seq: Vec<TreeNode> = vec![];
+  for n in children {
+    match n.n_type {
+      // a Group in a sequence is just more of the sequence
+      NodeType::Group => {
+        for c in n.children {
+          push_seq(&mut seq, c);
+        }
+      },
+      _ => push_seq(&mut seq, n),
+    }
+  }
+  return seq;
+}
+
+fn push_seq(seq: &mut Vec<TreeNode>, n: TreeNode) {
+  if matches!(n.n_type, NodeType::Word) {
+    // matching nothing in a sequence does nothing
+    if n.image.is_empty() {
+      return;
+    }
+    // `a` then `b` is `ab`
+    if let Some(last) = seq.last_mut() {
+      if matches!(last.n_type, NodeType::Word) && last.unicode == n.unicode {
+        last.image.extend(n.image);
+        last.span.end = n.span.end;
+        return;
+      }
+    }
+  }
+  seq.push(n);
+}
+
+// a Union in a Union is just more branches
+fn branches(children: Vec<TreeNode>) -> Vec<TreeNode> {
+  let mut out = vec![];
+  for n in children {
+    match n.n_type {
+      NodeType::Union => out.extend(branches(n.children)),
+      _ => out.push(n),
+    }
+  }
+  return out;
+}
+
+fn simplify_union(mut node: TreeNode) -> TreeNode {
+  node.children = branches(std::mem::take(&mut node.children));
+
+  // many literals are matched quicker by an automaton than bit by bit
+  if literal::is_literal_union(&node) {
+    return node;
+  }
+
+  let branches = factor_prefixes(std::mem::take(&mut node.children));
+  node.children = merge_charsets(branches);
+
+  if node.children.len() == 1 {
+    return node.children.pop().unwrap();
+  }
+  return node;
+}
+
+// the literal a branch begins with, if it begins with one
+fn prefix(branch: &TreeNode) -> Option<&TreeNode> {
+  let word = match branch.n_type {
+    NodeType::Word => branch,
+    NodeType::Group => branch.children.first()?,
+    _ => { return None; },
+  };
+  if !matches!(word.n_type, NodeType::Word) || word.image.is_empty() {
+    return None;
+  }
+  return Some(word);
+}
+
+// length of the prefix that two literals share
+fn common_len(a: &TreeNode, b: &TreeNode) -> usize {
+  if a.unicode != b.unicode {
+    return 0;
+  }
+  return a.image.iter().zip(&b.image).take_while(|(x, y)| x == y).count();
+}
+
+// what is left of a branch once the first len units of its prefix are gone
+fn strip_prefix(mut branch: TreeNode, len: usize) -> TreeNode {
+  if matches!(branch.n_type, NodeType::Word) {
+    branch.image.drain(..len);
+    return branch;
+  }
+
+  // a Group that begins with a Word
+  let word = &mut branch.children[0];
+  word.image.drain(..len);
+  return simplify(branch);
+}
+
+// `ab|ac|d` -> `a(b|c)|d`
+fn factor_prefixes(branches: Vec<TreeNode>) -> Vec<TreeNode> {
+  let mut out = vec![];
+  let mut branches = branches.into_iter().peekable();
+  while let Some(first) = branches.next() {
+    // gather the following branches that share a prefix with this one
+    let mut run = vec![];
+    let mut len = prefix(&first).map_or(0, |w| w.image.len());
+    while len > 0 {
+      let shared = match branches.peek().and_then(prefix) {
+        Some(w) => common_len(prefix(&first).unwrap(), w).min(len),
+        None => 0,
+      };
+      if shared == 0 {
+        break;
+      }
+      len = shared;
+      run.push(branches.next().unwrap());
+    }
+
+    if run.is_empty() {
+      out.push(first);
+      continue;
+    }
+
+    // the shared prefix, then whichever branch matches after it
+    let mut word = prefix(&first).unwrap().clone();
+    word.image.truncate(len);
+    let mut union = TreeNode::new(NodeType::Union);
+    union.span = Span::new(first.span.start, run.last().unwrap().span.end);
+    union.children.push(strip_prefix(first, len));
+    for n in run {
+      union.children.push(strip_prefix(n, len));
+    }
+
+    let mut group = TreeNode::new(NodeType::Group);
+    group.span = union.span;
+    group.children.push(word);
+    group.children.push(simplify_union(union));
+    out.push(simplify(group));
+  }
+  return out;
+}
+
+// the ranges of a branch that always matches exactly one unit
+fn unit_ranges(branch: &TreeNode) -> Option<Vec<CharRange>> {
+  return match branch.n_type {
+    NodeType::Charset => Some(branch.ranges.clone()),
+    NodeType::Word if branch.image.len() == 1 => {
+      let u = branch.image[0] as u32;
+      Some(vec![CharRange::new(u, u, false)])
+    },
+    _ => None,
+  };
+}
+
+// `a|b|[0-9]` -> `[ab0-9]`
+fn merge_charsets(branches: Vec<TreeNode>) -> Vec<TreeNode> {
+  let mut out: Vec<TreeNode> = vec![];
+  for n in branches {
+    let last_unit = out.last().and_then(|last| {
+      if last.unicode != n.unicode { None } else { unit_ranges(last) }
+    });
+    match (last_unit, unit_ranges(&n)) {
+      (Some(mut ranges), Some(more)) => {
+        let last = out.last_mut().unwrap();
+        ranges.extend(more);
+        let mut set = TreeNode::new(NodeType::Charset);
+        set.ranges = ranges;
+        set.unicode = n.unicode;
+        set.span = Span::new(last.span.start, n.span.end);
+        *last = set;
+      },
+      _ => out.push(n),
+    }
+  }
+  return out;
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::printer;
+  use crate::regex::RegExEnv;
+  use super::*;
+
+  // like printing, but `(?:...)` shows a Group that isn't the root
+  fn show(node: &TreeNode) -> String {
+    let mut out = String::new();
+    show_seq(node, &mut out);
+    return out;
+  }
+
+  // a Union that is part of a sequence needs brackets around it
+  fn show_seq(node: &TreeNode, out: &mut String) {
+    for n in &node.children {
+      let bracket = n.n_type == NodeType::Union && node.children.len() > 1;
+      if bracket {
+        out.push_str("(?:");
+      }
+      show_node(n, out);
+      if bracket {
+        out.push(')');
+      }
+    }
+  }
+
+  fn show_node(node: &TreeNode, out: &mut String) {
+    match node.n_type {
+      NodeType::Group | NodeType::MatchGroup => {
+        out.push_str(if node.n_type == NodeType::Group { "(?:" } else { "(" });
+        show_seq(node, out);
+        out.push(')');
+      },
+      NodeType::Union => {
+        for (i, n) in node.children.iter().enumerate() {
+          if i > 0 {
+            out.push('|');
+          }
+          show_node(n, out);
+        }
+      },
+      NodeType::Star => {
+        show_node(&node.children[0], out);
+        match (node.repeats.min, node.repeats.max) {
+          (0, 0) => out.push('*'),
+          (1, 0) => out.push('+'),
+          (0, 1) => out.push('?'),
+          (min, max) => out.push_str(&format!("{{{},{}}}", min, max)),
+        }
+      },
+      _ => {
+        let mut root = TreeNode::new(NodeType::Group);
+        root.children.push(node.clone());
+        out.push_str(&printer::print(&root));
+      },
+    }
+  }
+
+  fn optimized(expr: &str) -> String {
+    return show(&optimize(ast::parse(expr).unwrap()));
+  }
+
+  #[test]
+  fn optimize_words_and_groups() {
+    assert_eq!(optimized("abc"), "abc");
+    assert_eq!(optimized("a(b)c"), "a(b)c");
+    assert_eq!(optimized("(a|)b"), "(a|)b");
+    assert_eq!(optimized("x(|a)"), "x(|a)");
+  }
+
+  #[test]
+  fn optimize_prefixes() {
+    assert_eq!(optimized("abc|abd"), "ab[cd]");
+    assert_eq!(optimized("abc|abd|x*"), "(?:ab[cd])|x*");
+    assert_eq!(optimized("ab|abc"), "ab(?:|c)");
+    assert_eq!(optimized("ab|ac|b*"), "(?:a[bc])|b*");
+    assert_eq!(optimized("abc|abd|ae+"), "a(?:(?:b[cd])|e+)");
+    assert_eq!(optimized("ab(c)|ab(d)"), "ab(?:(c)|(d))");
+    assert_eq!(optimized("ab|x*|ac"), "ab|x*|ac");
+    // left for an automaton
+    assert_eq!(optimized("abc|abd|x"), "abc|abd|x");
+  }
+
+  #[test]
+  fn optimize_charsets() {
+    assert_eq!(optimized("a|b|c"), "[abc]");
+    assert_eq!(optimized("a|\\d|bc|d|e"), "[a\\d]|bc|[de]");
+    assert_eq!(optimized("(a|b)"), "([ab])");
+  }
+
+  // matching the tree before and after optimizing gives the same results
+  fn same_matches(expr: &str, haystacks: &[&str]) {
+    let tree = ast::parse(expr).unwrap();
+    let groups = expr.matches('(').count() + 1;
+    let opt = optimize(tree.clone());
+    for h in haystacks {
+      for start in 0..=h.len() {
+        let env = RegExEnv::new(*h, groups);
+        let a = env.search(&tree, None, start).map(|m| m.groups);
+        let b = env.search(&opt, None, start).map(|m| m.groups);
+        assert_eq!(a, b, "{} on {:?} from {}", expr, h, start);
+      }
+    }
+  }
+
+  #[test]
+  fn optimize_keeps_matches() {
+    same_matches("abc|abd|abe", &["xabdy", "abc", "ab"]);
+    same_matches("ab|abc|x", &["abc", "abx", "x"]);
+    same_matches("a(b)c|a(b)d|a(e)", &["abd", "ae", "abe"]);
+    same_matches("(a|b|c)+d", &["xabcabd", "d"]);
+    same_matches("x(y|)z|xy", &["xyz", "xz", "xy"]);
+    same_matches("(a|bc|d)*(e|e)", &["abcdde", "bce"]);
+  }
+}
@@ -1,45 +1,21 @@
+use crate::ast::Anchor;
+use crate::ast::CharRange;
+use crate::ast::MAX_UNIT;
+use crate::ast::unit_set;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Span;
+use crate::limits::DEFAULT_MAX_DEPTH;
+use crate::scanner::Flags;
 use crate::scanner::TokenType;
 use crate::scanner::Token;
-use crate::scanner::CharRange;
 use crate::scanner::Scanner;
+use std::sync::OnceLock;
 
-#[derive(Debug)]
-pub enum NodeType {
-  Error,
-  Empty,
-
-  Word,
-  Charset,
-  Union,
-  Star,
-  Group,
-  MatchGroup,
-}
-
-pub struct Bounds {
-  pub min: u32,
-  pub max: u32,
-}
-
-pub struct TreeNode {
-  pub n_type: NodeType,
-  pub children: Vec<TreeNode>,
-  pub image: Vec<char>,         // used by Words
-  pub repeats: Bounds,          // used by Star-likes (?, +, etc.)
-  pub ranges: Vec<CharRange>,   // used by Charsets
-}
-
+// helpers for building trees while parsing
 impl TreeNode {
-  fn new(n_type: NodeType) -> Self {
-    return TreeNode {
-      n_type,
-      children: vec![],
-      image: vec![],
-      repeats: Bounds { min: 0, max: 0 },
-      ranges: vec![],
-    };
-  }
-
   fn add_child(&mut self, child: TreeNode) {
     if !matches!(child.n_type, NodeType::Empty) {
       self.children.push(child);
@@ -55,178 +31,324 @@ impl TreeNode {
   fn make_group(children: Vec<TreeNode>, group_type: NodeType) -> TreeNode {
     let mut group = TreeNode::new(group_type);
     group.add_children(children);
+    group.span_children();
     return group;
   }
 
-  pub fn image_to_str(&self) -> String {
-    let mut s = String::new();
-    for i in 0..self.image.len() {
-      s.push(self.image[i]);
+  // span everything from the first child to the last
+  fn span_children(&mut self) {
+    if let (Some(first), Some(last)) = (self.children.first(),
+                                        self.children.last()) {
+      self.span = Span::new(first.span.start, last.span.end);
     }
-    return s;
   }
+
 }
 
 pub struct Parser {
   scanner: Scanner,
   next_token: Token,
+  group_names: Vec<Option<String>>, // by group number, group 0 has no name
+  errors: Vec<Error>,
+  depth: usize,     // groups open around the next token
+  max_depth: usize, // parsing recurses into groups, so this bounds the stack
 }
 
 impl Parser {
-  pub fn new(input: &String) -> Self {
+  pub fn new(input: &str) -> Self {
     let scanner = Scanner::new(input);
 
     return Parser {
       scanner,
       next_token: Token::new(TokenType::Error, '\0'),
+      group_names: vec![None],
+      errors: vec![],
+      depth: 0,
+      max_depth: DEFAULT_MAX_DEPTH,
+    };
+  }
+
+  pub fn new_bytes(input: &str) -> Self {
+    let scanner = Scanner::new_bytes(input);
+
+    return Parser {
+      scanner,
+      next_token: Token::new(TokenType::Error, '\0'),
+      group_names: vec![None],
+      errors: vec![],
+      depth: 0,
+      max_depth: DEFAULT_MAX_DEPTH,
     };
   }
 
-  pub fn parse(&mut self) -> TreeNode {
+  // the flags the expression starts with, instead of the defaults
+  pub fn flags(mut self, flags: &Flags) -> Self {
+    self.scanner = self.scanner.flags(flags);
+    return self;
+  }
+
+  // how many groups can be open inside each other
+  pub fn max_depth(mut self, max_depth: usize) -> Self {
+    self.max_depth = max_depth;
+    return self;
+  }
+
+  // parse the expression, or return every problem with it (in order)
+  pub fn parse(&mut self) -> Result<TreeNode, Vec<Error>> {
     // point to first character
     self.next_token = self.scanner.scan_next();
     // parse
     let tree = self.parse_root();
-    // print_node(&tree, 0);
-    return tree;
+
+    let mut errors = self.scanner.take_errors();
+    errors.append(&mut self.errors);
+    if errors.is_empty() {
+      return Ok(tree);
+    }
+    errors.sort_by_key(|e| e.span.start);
+    return Err(errors);
   }
 
-  fn eat(&mut self, expected: TokenType) {
-    let t = &self.next_token;
+  // number of matching groups seen so far (not counting group 0)
+  pub fn group_count(&self) -> usize {
+    return self.group_names.len() - 1;
+  }
 
-    if !matches!(&t.t_type, expected) { // TODO: why is this a warning?
-      println!("syntax error: expected {:?}, saw {:?}",
-               t.t_type,
-               expected
-               );
+  // the name of every group (if it has one) by group number
+  pub fn group_names(&self) -> Vec<Option<String>> {
+    return self.group_names.clone();
+  }
+
+  fn error(&mut self, kind: ErrorKind, span: Span) {
+    // one mistake can be seen by several rules, only report it once
+    if self.errors.last().is_some_and(|e| e.span.start == span.start) {
+      return;
     }
-    else {
-      self.next_token = self.scanner.scan_next();
+    self.errors.push(Error::new(kind, span));
+  }
+
+  // report the next token as being out of place
+  fn unexpected(&mut self) {
+    let kind = match self.next_token.t_type {
+      // the scanner has already reported this one
+      TokenType::Error => { return; },
+      TokenType::Star | TokenType::Plus |
+      TokenType::Question => ErrorKind::BadRepetition,
+      TokenType::RParen => ErrorKind::UnopenedGroup,
+      TokenType::RBracket => ErrorKind::UnopenedClass,
+      _ => ErrorKind::Unexpected(self.next_token.image),
+    };
+    self.error(kind, self.next_token.span);
+  }
+
+  fn advance(&mut self) {
+    self.next_token = self.scanner.scan_next();
+  }
+
+  fn eat(&mut self, expected: TokenType) {
+    if self.next_token.t_type != expected {
+      self.unexpected();
+      return;
     }
+    self.advance();
   }
 
-  fn parse_root(&mut self) -> TreeNode {
-    match self.next_token.t_type {
-      // total -> expr eof
-      TokenType::Character | TokenType::Range |
-      TokenType::LBracket | TokenType::LParen |
-      TokenType::Union | TokenType::RParen |
-      TokenType::EOF => {
-        // println!("total -> expr eof");
-        // create root node
-        let mut root_node = TreeNode::new(NodeType::Group);
+  // report the next token, then skip past the mistake so that parsing can
+  // carry on and find any other problems
+  fn recover(&mut self) {
+    let repetition = matches!(self.next_token.t_type,
+                              TokenType::Star | TokenType::Plus |
+                              TokenType::Question);
+    self.unexpected();
+    self.advance();
+
+    // a stray repetition doesn't affect what comes after it
+    if !repetition {
+      self.sync();
+    }
+  }
 
-        // continue parsing
-        root_node.add_children(self.parse_expr());
-        self.eat(TokenType::EOF);
+  // skip up to the next ')', '|' or ']' that isn't nested in something
+  // opened after the mistake
+  fn sync(&mut self) {
+    let mut depth = 0;
+    loop {
+      match self.next_token.t_type {
+        TokenType::EOF => { return; },
+        TokenType::RParen | TokenType::RBracket |
+        TokenType::Union if depth == 0 => { return; },
+        TokenType::LParen | TokenType::LBracket => { depth += 1; },
+        TokenType::RParen | TokenType::RBracket => { depth -= 1; },
+        _ => {
+          // Empty
+        },
+      }
+      self.advance();
+    }
+  }
 
-        return root_node;
-      },
-      _ => {
-        println!("syntax error: saw {:?} while parsing total",
-                 self.next_token.t_type);
-        return TreeNode::new(NodeType::Error);
-      },
+  // skip a group without parsing what's inside it, up to and including its
+  // ')' (or to the end if it hasn't got one)
+  fn skip_group(&mut self) {
+    let mut depth = 0;
+    loop {
+      match self.next_token.t_type {
+        TokenType::LParen => { depth += 1; },
+        TokenType::RParen => { depth -= 1; },
+        TokenType::EOF => { return; },
+        _ => {
+          // Empty
+        },
+      }
+      self.advance();
+      if depth == 0 {
+        return;
+      }
     }
   }
 
-  fn parse_expr(&mut self) -> Vec<TreeNode> {
-    match self.next_token.t_type {
-      // expr -> seq union expr
-      TokenType::Character | TokenType::Range |
-      TokenType::LBracket | TokenType::LParen |
-      TokenType::Union => {
-        // println!("expr -> seq union expr");
-        let mut child_vec = vec![];
+  // skip to the end of a charset
+  fn sync_charset(&mut self) {
+    while !matches!(self.next_token.t_type,
+                    TokenType::RBracket | TokenType::EOF) {
+      self.advance();
+    }
+  }
 
-        // continue parsing
-        let mut sequence = self.parse_seq(TreeNode::new(NodeType::Empty));
+  fn parse_root(&mut self) -> TreeNode {
+    // total -> expr eof
+    // create root node
+    let mut root_node = TreeNode::new(NodeType::Group);
+
+    // continue parsing
+    root_node.add_children(self.parse_expr());
+
+    // the expression stops early at a ')' that doesn't close anything
+    while matches!(self.next_token.t_type, TokenType::RParen) {
+      self.unexpected();
+      self.advance();
+      root_node.add_children(self.parse_expr());
+    }
 
-        let union_node;
-        if sequence.len() == 1 {
-          let first = sequence.pop().unwrap(); // pop to move [0] out of vec
-          union_node = self.parse_union(first);
-        }
-        else {
-          union_node = self.parse_union(TreeNode::make_group(sequence,
-                                                             NodeType::Group));
-        }
+    root_node.span = Span::new(0, self.next_token.span.end);
+    self.eat(TokenType::EOF);
 
-        child_vec.push(union_node);
-        for n in self.parse_expr() {
-          child_vec.push(n);
-        }
+    return root_node;
+  }
 
-        return child_vec;
-      },
-      // expr -> ε
-      TokenType::RParen | TokenType::EOF => {
-        // println!("expr -> ε");
-        return vec![];
-      },
-      _ => {
-        println!("syntax error: saw {:?} while parsing expr",
-                 self.next_token.t_type);
-        return vec![TreeNode::new(NodeType::Error)];
-      },
+  fn parse_expr(&mut self) -> Vec<TreeNode> {
+    // each rule that ends in itself is a loop rather than a call, so that
+    // the stack only grows with the groups open
+    let mut child_vec = vec![];
+    loop {
+      match self.next_token.t_type {
+        // expr -> seq union expr
+        // (tokens that can't begin a seq are reported by seq)
+        TokenType::Character | TokenType::Range |
+        TokenType::LBracket | TokenType::LParen |
+        TokenType::Anchor | TokenType::Union | TokenType::Star |
+        TokenType::Question | TokenType::Plus |
+        TokenType::RBracket | TokenType::Caret |
+        TokenType::Error => {
+          // println!("expr -> seq union expr");
+          // continue parsing
+          let mut sequence = self.parse_seq(TreeNode::new(NodeType::Empty));
+
+          let union_node = if sequence.len() == 1 {
+            let first = sequence.pop().unwrap(); // pop to move [0] out of vec
+            self.parse_union(first)
+          }
+          else {
+            self.parse_union(TreeNode::make_group(sequence, NodeType::Group))
+          };
+
+          child_vec.push(union_node);
+        },
+        // expr -> ε
+        TokenType::RParen | TokenType::EOF => {
+          // println!("expr -> ε");
+          return child_vec;
+        },
+      }
     }
   }
 
   fn parse_seq(&mut self, mut prev: TreeNode) -> Vec<TreeNode> {
-    match self.next_token.t_type {
-      // seq -> atom star seq
-      TokenType::Character | TokenType::Range |
-      TokenType::LBracket | TokenType::LParen => {
-        // println!("seq -> atom star seq");
-        // continue parsing
-        let atom_node = self.parse_atom();
-        let star_node = self.parse_star(atom_node);
-
-        // if previous node is word and star node remains a word
-        // then instead of pushing a new node just expant that node's image
-        if matches!(prev.n_type, NodeType::Word) &&
-           matches!(star_node.n_type, NodeType::Word) {
-          prev.image.push(star_node.image[0]);
-          return self.parse_seq(prev);
-        }
-        // otherwise act like normal
-
-        // create child vector
-        let mut child_vec = vec![];
-        // add previous and next nodes in sequence
-        // only add a node to the vec if it isn't Empty
-        if !matches!(prev.n_type, NodeType::Empty) {
-          child_vec.push(prev);
-        }
-        for n in self.parse_seq(star_node) {
-          if !matches!(n.n_type, NodeType::Empty) {
-            child_vec.push(n);
+    let mut child_vec = vec![];
+    let mut first = true; // whether prev is the one seq was called with
+    loop {
+      match self.next_token.t_type {
+        // seq -> atom star seq
+        TokenType::Character | TokenType::Range |
+        TokenType::LBracket | TokenType::LParen |
+        TokenType::Anchor => {
+          // println!("seq -> atom star seq");
+          // continue parsing
+          let atom_node = self.parse_atom();
+          let star_node = self.parse_star(atom_node);
+
+          // if previous node is word and star node remains a word
+          // then instead of pushing a new node just expant that node's image
+          // (as long as both match the same kind of unit)
+          if matches!(prev.n_type, NodeType::Word) &&
+             matches!(star_node.n_type, NodeType::Word) &&
+             prev.unicode == star_node.unicode {
+            prev.image.push(star_node.image[0]);
+            prev.span.end = star_node.span.end;
+            continue;
           }
-        }
+          // otherwise act like normal
 
-        return child_vec;
-      },
-      // seq -> ε
-      TokenType::Union | TokenType::RParen |
-      TokenType::EOF => {
-        // println!("seq -> ε");
-        return vec![prev]; // return previous without modifying it
-      },
-      _ => {
-        println!("syntax error: saw {:?} while parsing sequence",
-                 self.next_token.t_type);
-        return vec![TreeNode::new(NodeType::Error)];
-      },
+          // add the previous node to the sequence, and go on from the next
+          // only add a node to the vec if it isn't Empty
+          if !matches!(prev.n_type, NodeType::Empty) {
+            child_vec.push(prev);
+          }
+          prev = star_node;
+          first = false;
+        },
+        // seq -> ε
+        TokenType::Union | TokenType::RParen |
+        TokenType::EOF => {
+          // println!("seq -> ε");
+          // the node seq was called with is given back even if it's Empty
+          if first || !matches!(prev.n_type, NodeType::Empty) {
+            child_vec.push(prev);
+          }
+          return child_vec;
+        },
+        // a token that can't be part of a sequence
+        TokenType::Star | TokenType::Question |
+        TokenType::Plus | TokenType::RBracket |
+        TokenType::Caret | TokenType::Error => {
+          self.recover();
+        },
+      }
     }
   }
 
   fn parse_atom(&mut self) -> TreeNode {
     match self.next_token.t_type {
       // atom -> charater
+      // atom -> character (in any case)
+      TokenType::Character if self.next_token.caseless &&
+                              has_cases(self.next_token.image as u32,
+                                        self.next_token.unicode) => {
+        let c = self.next_token.image as u32;
+        let mut charset_node = TreeNode::new(NodeType::Charset);
+        charset_node.unicode = self.next_token.unicode;
+        charset_node.span = self.next_token.span;
+        charset_node.ranges = fold_case(&[CharRange::new(c, c, false)],
+                                        charset_node.unicode);
+        self.eat(TokenType::Character);
+        return charset_node;
+      },
       TokenType::Character => {
         // create word node
         let mut word_node = TreeNode::new(NodeType::Word);
         word_node.image.push(self.next_token.image);
+        word_node.unicode = self.next_token.unicode;
+        word_node.span = self.next_token.span;
 
         // continue parsing
         self.eat(TokenType::Character);
@@ -237,8 +359,14 @@ impl Parser {
       TokenType::Range => {
         // create charset node
         let mut charset_node = TreeNode::new(NodeType::Charset);
-        while self.next_token.range.len() > 0 {
-          charset_node.ranges.push(self.next_token.range.pop().unwrap());
+        charset_node.unicode = self.next_token.unicode;
+        charset_node.span = self.next_token.span;
+        while let Some(r) = self.next_token.range.pop() {
+          charset_node.ranges.push(r);
+        }
+        if self.next_token.caseless {
+          charset_node.ranges = fold_case(&charset_node.ranges,
+                                          charset_node.unicode);
         }
 
         // continue parsing
@@ -246,27 +374,81 @@ impl Parser {
 
         return charset_node;
       },
+      // atom -> anchor
+      TokenType::Anchor => {
+        let mut anchor_node = TreeNode::new(NodeType::Anchor);
+        anchor_node.anchor = match (self.next_token.image,
+                                    self.next_token.multi_line) {
+          ('^', false) => Anchor::Start,
+          ('^', true) => Anchor::LineStart,
+          (_, false) => Anchor::End,
+          (_, true) => Anchor::LineEnd,
+        };
+        anchor_node.span = self.next_token.span;
+
+        // continue parsing
+        self.eat(TokenType::Anchor);
+
+        return anchor_node;
+      },
       // atom -> ( expr )
       TokenType::LParen => {
         // println!("atom -> ( expr )");
+        let name = self.next_token.name.take();
+        let open = self.next_token.span;
+        if self.depth >= self.max_depth {
+          self.error(ErrorKind::NestedTooDeep(self.max_depth), open);
+          self.skip_group();
+          return TreeNode::new(NodeType::Error);
+        }
         self.eat(TokenType::LParen);
+        self.depth += 1;
+        // groups are numbered in the order they are opened
+        self.group_names.push(name.clone());
+        let group = self.group_count();
         let expr_node = self.parse_expr();
-        self.eat(TokenType::RParen);
+        self.depth -= 1;
+        let close = self.next_token.span;
+        if matches!(self.next_token.t_type, TokenType::EOF) {
+          self.error(ErrorKind::UnclosedGroup, open);
+        }
+        else {
+          self.eat(TokenType::RParen);
+        }
 
-        return TreeNode::make_group(expr_node, NodeType::MatchGroup);
+        let mut group_node = TreeNode::make_group(expr_node,
+                                                  NodeType::MatchGroup);
+        group_node.group = group;
+        group_node.name = name;
+        group_node.span = Span::new(open.start, close.end);
+        return group_node;
       },
       // atom -> [ neg charset ]
       TokenType::LBracket => {
         // println!("atom -> [ neg charset ]");
+        let open = self.next_token.span;
         self.eat(TokenType::LBracket);
         let neg_node = self.parse_neg();
-        self.eat(TokenType::RBracket);
+        // anything else in a charset is a mistake, skip to its end
+        if !matches!(self.next_token.t_type,
+                     TokenType::RBracket | TokenType::EOF) {
+          self.unexpected();
+          self.sync_charset();
+        }
+        let close = self.next_token.span;
+        if matches!(self.next_token.t_type, TokenType::EOF) {
+          self.error(ErrorKind::UnclosedClass, open);
+        }
+        else {
+          self.eat(TokenType::RBracket);
+        }
 
+        let mut neg_node = neg_node;
+        neg_node.span = Span::new(open.start, close.end);
         return neg_node;
       },
       _ => {
-        println!("syntax error: saw {:?} while parsing atom",
-                 self.next_token.t_type);
+        self.unexpected();
         return TreeNode::new(NodeType::Error);
       },
     }
@@ -279,6 +461,7 @@ impl Parser {
         // println!("star -> *");
         // create star node
         let mut star_node = TreeNode::new(NodeType::Star);
+        star_node.span = Span::new(lhs.span.start, self.next_token.span.end);
         star_node.add_child(lhs);
 
         // continue parsing
@@ -290,6 +473,7 @@ impl Parser {
       TokenType::Question => {
         // create star node with repeat count
         let mut star_node = TreeNode::new(NodeType::Star);
+        star_node.span = Span::new(lhs.span.start, self.next_token.span.end);
         star_node.add_child(lhs);
         star_node.repeats.min = 0;
         star_node.repeats.max = 1;
@@ -303,6 +487,7 @@ impl Parser {
       TokenType::Plus => {
         // create star node with repeat count
         let mut star_node = TreeNode::new(NodeType::Star);
+        star_node.span = Span::new(lhs.span.start, self.next_token.span.end);
         star_node.add_child(lhs);
         star_node.repeats.min = 1;
         star_node.repeats.max = 0; // no maximum
@@ -313,18 +498,15 @@ impl Parser {
         return star_node;
       },
       // star -> ε
+      // (anything out of place is reported by seq)
       TokenType::Character | TokenType::Range |
       TokenType::LBracket | TokenType::LParen |
-      TokenType::Union | TokenType::RParen |
-      TokenType::EOF => {
+      TokenType::Anchor | TokenType::Union | TokenType::RParen |
+      TokenType::RBracket | TokenType::Caret |
+      TokenType::Error | TokenType::EOF => {
         // println!("star -> ε");
         return lhs; // return lhs unmodified
       },
-      _ => {
-        println!("syntax error: saw {:?} while parsing star",
-                 self.next_token.t_type);
-        return TreeNode::new(NodeType::Error);
-      },
     }
   }
 
@@ -337,39 +519,51 @@ impl Parser {
         let mut union_node = TreeNode::new(NodeType::Union);
         // if lhs is empty replace it with a 0-length word
         if matches!(lhs.n_type, NodeType::Empty) {
-          union_node.add_child(TreeNode::new(NodeType::Word));
+          let mut empty = TreeNode::new(NodeType::Word);
+          let at = self.next_token.span.start;
+          empty.span = Span::new(at, at);
+          union_node.add_child(empty);
         }
         // if not empty, be normal
         else {
           union_node.add_child(lhs);
         }
 
-        // continue parsing
-        self.eat(TokenType::Union);
-        let expr_node = self.parse_expr();
-
-        // if expression is empty replace it with a 0-length word
-        // otherwise it will be culled and you won't be able to match (a|b|)
-        if expr_node.len() == 0 {
-          union_node.add_child(TreeNode::new(NodeType::Word));
-        }
-        // if not empty, be normal
-        else {
-          union_node.add_children(expr_node);
+        // every branch goes in the same union, rather than nesting a union
+        // for each '|' (which would be as deep as the list of branches)
+        while matches!(self.next_token.t_type, TokenType::Union) {
+          let at = self.next_token.span.end;
+          self.eat(TokenType::Union);
+          let mut sequence = self.parse_seq(TreeNode::new(NodeType::Empty));
+
+          let branch = if sequence.len() == 1 { sequence.pop().unwrap() }
+                       else { TreeNode::make_group(sequence, NodeType::Group) };
+
+          // if the branch is empty replace it with a 0-length word
+          // otherwise it will be culled and you won't be able to match (a|b|)
+          if matches!(branch.n_type, NodeType::Empty) {
+            let mut empty = TreeNode::new(NodeType::Word);
+            empty.span = Span::new(at, at);
+            union_node.add_child(empty);
+          }
+          // if not empty, be normal
+          else {
+            union_node.add_child(branch);
+          }
         }
 
+        union_node.span_children();
         return union_node;
       },
       // union -> ε
       TokenType::Character | TokenType::Range |
       TokenType::LBracket | TokenType::LParen |
-      TokenType::RParen | TokenType::EOF => {
+      TokenType::Anchor | TokenType::RParen | TokenType::EOF => {
         // println!("union -> ε");
         return lhs; // return lhs unmodified
       },
       _ => {
-        println!("syntax error: saw {:?} while parsing union",
-                 self.next_token.t_type);
+        self.unexpected();
         return TreeNode::new(NodeType::Error);
       },
     }
@@ -387,9 +581,12 @@ impl Parser {
       TokenType::Character | TokenType::Range => {
         return self.parse_charset(false);
       },
+      TokenType::RBracket => {
+        self.error(ErrorKind::EmptyClass, self.next_token.span);
+        return TreeNode::new(NodeType::Error);
+      },
+      // anything else (or EOF) is reported by atom
       _ => {
-        println!("syntax error: saw {:?} while parsing neg",
-                 self.next_token.t_type);
         return TreeNode::new(NodeType::Error);
       },
     }
@@ -401,12 +598,15 @@ impl Parser {
       TokenType::Character | TokenType::Range => {
         // create new charset node
         let mut charset_node = TreeNode::new(NodeType::Charset);
+        let mut caseless = false;
 
         let mut last_type = TokenType::Error; // Error is just a default
-        let mut last_char = 0x0000;
+        let mut last_start = 0; // where the last character began
+        let mut join_start = 0; // where the character before a '-' began
         let mut try_join = false; // if we see a '-', mark a potential range
         // if we see a range and then a '-' be ready to throw an error
         let mut try_throw_bad_join = false;
+        let mut bad_join = Span::default(); // the '-' after a range
 
         // parse as many characters/ranges as possible
         loop {
@@ -414,12 +614,16 @@ impl Parser {
             TokenType::Character => {
               // stop right away if a bad join is in progress
               if try_throw_bad_join {
-                println!("syntax error: invalid '-' in charset");
+                self.error(ErrorKind::InvalidRangeBound, bad_join);
+                self.sync_charset();
                 return TreeNode::new(NodeType::Error);
               }
 
               // get next character
               let c = self.next_token.image;
+              let span = self.next_token.span;
+              charset_node.unicode &= self.next_token.unicode;
+              caseless |= self.next_token.caseless;
               self.eat(TokenType::Character);
 
               // either add a new discrete character to the set
@@ -427,6 +631,7 @@ impl Parser {
               let mut did_join = false;
               if try_join {
                 // perform join
+                try_join = false;
                 charset_node.ranges.pop(); // remove '-' discrete char
                 // turn preceding discrete character into range
                 let mut prev_range = charset_node.ranges.pop().unwrap();
@@ -434,8 +639,10 @@ impl Parser {
 
                 // make sure ordering is correct
                 if prev_range.min > prev_range.max {
-                  println!("syntax error: invalid range, {} > {}",
-                           prev_range.min, prev_range.max);
+                  let min = char::from_u32(prev_range.min).unwrap_or('\0');
+                  self.error(ErrorKind::InvalidRange(min, c),
+                             Span::new(join_start, span.end));
+                  self.sync_charset();
                   return TreeNode::new(NodeType::Error);
                 }
 
@@ -448,25 +655,30 @@ impl Parser {
                 charset_node.ranges.push(
                   CharRange::new(c as u32, c as u32, negated)
                   );
-                last_char = c as u32;
               }
 
+              // an escaped '-' (`\-`) is always a literal
+              let dash = c == '-' && span.end - span.start == 1;
+
               // if you see a '-' and the previous token before was a character
               // then it may be possible to join that character with the next
               // character to create a range
-              if c == '-' && !did_join &&
+              if dash && !did_join &&
                  matches!(last_type, TokenType::Character) {
                 try_join = true;
+                join_start = last_start;
               }
               // its possible that an illegal join is being attempted
-              else if c == '-' && !did_join &&
+              else if dash && !did_join &&
                  matches!(last_type, TokenType::Range) {
                 try_throw_bad_join = true;
+                bad_join = span;
               }
 
               // keep track of last type
               if !did_join {
                 last_type = TokenType::Character;
+                last_start = span.start;
               }
               // if we just performed a join, pretend the last token wasn't
               // a character to prevent chaining joins together
@@ -479,18 +691,22 @@ impl Parser {
             TokenType::Range => {
               // stop right away if a bad join is in progress
               if try_throw_bad_join {
-                println!("syntax error: invalid '-' in charset");
+                self.error(ErrorKind::InvalidRangeBound, bad_join);
+                self.sync_charset();
                 return TreeNode::new(NodeType::Error);
               }
 
               // add everything in this range to the charset
-              while self.next_token.range.len() > 0 {
-                charset_node.ranges.push(self.next_token.range.pop().unwrap());
+              charset_node.unicode &= self.next_token.unicode;
+              caseless |= self.next_token.caseless;
+              while let Some(r) = self.next_token.range.pop() {
+                charset_node.ranges.push(r);
               }
 
               // you can never join a range with '-', throw error
               if try_join {
-                println!("syntax error: invalid '-' in charset");
+                self.error(ErrorKind::InvalidRangeBound, self.next_token.span);
+                self.sync_charset();
                 return TreeNode::new(NodeType::Error);
               }
 
@@ -507,6 +723,19 @@ impl Parser {
           }
         }
 
+        if caseless && negated {
+          // everything but the characters in any case, which isn't the same
+          // as any case of everything but the characters
+          let ranges: Vec<CharRange> = charset_node.ranges.iter()
+            .map(|r| CharRange::new(r.min, r.max, false))
+            .collect();
+          charset_node.ranges = complement(&fold_case(&ranges,
+                                                      charset_node.unicode));
+        }
+        else if caseless {
+          charset_node.ranges = fold_case(&charset_node.ranges,
+                                          charset_node.unicode);
+        }
         return charset_node;
       },
       // charset -> ε
@@ -516,39 +745,149 @@ impl Parser {
         //   "not rbracket" and throws a syntax error for missing rbracket
         return TreeNode::new(NodeType::Charset);
       },
+      // anything else (or EOF) is reported by atom
       _ => {
-        println!("syntax error: saw {:?} while parsing charset",
-                 self.next_token.t_type);
         return TreeNode::new(NodeType::Error);
       },
     }
   }
 }
 
-// DEBUG
-fn print_node(node: &TreeNode, depth: i32) {
-  let mut depth_str = String::new();
-  for _ in 0..depth {
-    depth_str.push_str("  ");
+// the highest unit that has another case
+const MAX_CASED: u32 = 0x1FFFF;
+
+// the other cases of unit u (e.g.: 'A' for 'a'), without unicode only ASCII
+// letters have them since other units are bytes
+fn other_cases(u: u32, unicode: bool) -> Vec<u32> {
+  let c = match char::from_u32(u) {
+    Some(c) if unicode || c.is_ascii() => c,
+    _ => { return vec![]; },
+  };
+
+  // a character that changes into several (e.g.: 'ß' into "SS") can't
+  // match a single character in its other case
+  let lower = only(c.to_lowercase());
+  let upper = only(c.to_uppercase());
+  return [lower, upper].into_iter()
+                       .flatten()
+                       .filter(|o| *o != c)
+                       .map(|o| o as u32)
+                       .collect();
+}
+
+fn only(mut chars: impl Iterator<Item = char>) -> Option<char> {
+  return match (chars.next(), chars.next()) {
+    (Some(c), None) => Some(c),
+    _ => None,
+  };
+}
+
+fn has_cases(u: u32, unicode: bool) -> bool {
+  return !other_cases(u, unicode).is_empty();
+}
+
+// every unit with another case and that case, in order (worked out once,
+// since it means looking at every unit up to MAX_CASED)
+fn case_pairs() -> &'static [(u32, u32)] {
+  static PAIRS: OnceLock<Vec<(u32, u32)>> = OnceLock::new();
+  return PAIRS.get_or_init(|| {
+    let mut pairs = vec![];
+    for u in 0..=MAX_CASED {
+      for o in other_cases(u, true) {
+        pairs.push((u, o));
+      }
+    }
+    return pairs;
+  });
+}
+
+// ranges that match everything the given ones do in any case, none of them
+// negated since the negations are worked out first
+fn fold_case(ranges: &[CharRange], unicode: bool) -> Vec<CharRange> {
+  // without unicode only ASCII letters have other cases
+  let last = if unicode { MAX_CASED } else { 0x7F };
+  let pairs = case_pairs();
+  let mut folded = vec![];
+  for (min, max) in unit_set(ranges) {
+    folded.push(CharRange::new(min, max, false));
+    let first = pairs.partition_point(|(u, _)| *u < min);
+    for (u, o) in &pairs[first..] {
+      if *u > max.min(last) {
+        break;
+      }
+      // most cases of a wide range are in it already
+      if *o < min || *o > max {
+        folded.push(CharRange::new(*o, *o, false));
+      }
+    }
+  }
+
+  // unit_set sorts them and merges them back together
+  return unit_set(&folded).into_iter()
+                          .map(|(min, max)| CharRange::new(min, max, false))
+                          .collect();
+}
+
+// ranges that match everything the given ones (which aren't negated and
+// don't overlap, in order) don't
+fn complement(ranges: &[CharRange]) -> Vec<CharRange> {
+  let mut gaps = vec![];
+  let mut next = 0;
+  for r in ranges {
+    if r.min > next {
+      gaps.push(CharRange::new(next, r.min - 1, false));
+    }
+    next = r.max.saturating_add(1);
+  }
+  if next <= MAX_UNIT {
+    gaps.push(CharRange::new(next, MAX_UNIT, false));
   }
+  return gaps;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
 
-  print!("{}", depth_str);
-  print!("[{:?}", node.n_type);
-  if node.image.len() > 0 {
-    print!("(");
-    for c in &node.image {
-      print!("{}", c);
+  fn spans(node: &TreeNode, out: &mut Vec<(usize, usize)>) {
+    out.push((node.span.start, node.span.end));
+    for n in &node.children {
+      spans(n, out);
     }
-    print!(")");
   }
-  else if matches!(node.n_type, NodeType::Word) {
-    print!("(ε)");
+
+  #[test]
+  fn parse_spans() {
+    let tree = Parser::new("ab(c|d)*[xy]").parse().unwrap();
+    let mut out = vec![];
+    spans(&tree, &mut out);
+    // root, sequence, word, star, group, union, c, d, charset
+    assert_eq!(out, vec![(0, 12), (0, 12), (0, 2), (2, 8), (2, 7), (3, 6),
+                         (3, 4), (5, 6), (8, 12)]);
   }
-  print!("]");
 
-  println!("");
+  #[test]
+  fn parse_fold_case() {
+    let folded = fold_case(&[CharRange::new('a' as u32, 'c' as u32, false),
+                             CharRange::new(0xe9, 0xe9, false)], true);
+    assert_eq!(folded, vec![CharRange::new('A' as u32, 'C' as u32, false),
+                            CharRange::new('a' as u32, 'c' as u32, false),
+                            CharRange::new(0xc9, 0xc9, false),
+                            CharRange::new(0xe9, 0xe9, false)]);
+    let folded = fold_case(&[CharRange::new(0xe9, 0xe9, false)], false);
+    assert_eq!(folded, vec![CharRange::new(0xe9, 0xe9, false)]);
+
+    // classes that cover most units are folded without looking at each one
+    let expr = format!("(?i){}", "\\W.[^a]".repeat(1000));
+    assert!(Parser::new(&expr).parse().is_ok());
+  }
 
-  for n in &node.children {
-    print_node(n, depth + 1);
+  #[test]
+  fn parse_spans_empty_branches() {
+    let tree = Parser::new("|a|").parse().unwrap();
+    let mut out = vec![];
+    spans(&tree, &mut out);
+    // root, union, empty, a, empty
+    assert_eq!(out, vec![(0, 3), (0, 3), (0, 0), (1, 2), (3, 3)]);
   }
 }
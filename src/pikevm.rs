@@ -0,0 +1,119 @@
+use crate::program::Inst;
+use crate::program::Program;
+
+// a set of instruction indexes that remembers insertion order
+struct Threads {
+  dense: Vec<usize>,
+  contains: Vec<bool>,
+}
+
+impl Threads {
+  fn new(size: usize) -> Self {
+    return Threads { dense: vec![], contains: vec![false; size] };
+  }
+
+  fn insert(&mut self, pc: usize) -> bool {
+    if self.contains[pc] {
+      return false;
+    }
+    self.contains[pc] = true;
+    self.dense.push(pc);
+    return true;
+  }
+
+  fn clear(&mut self) {
+    for pc in self.dense.drain(..) {
+      self.contains[pc] = false;
+    }
+  }
+}
+
+// runs every thread of a program in lockstep over the haystack, so each
+// character is only looked at once no matter how many patterns there are
+pub struct PikeVM<'p> {
+  prog: &'p Program,
+}
+
+impl<'p> PikeVM<'p> {
+  pub fn new(prog: &'p Program) -> Self {
+    return PikeVM { prog };
+  }
+
+  // mark every pattern that matches anywhere in the haystack
+  // if first_only, stop as soon as any pattern has matched
+  pub fn which_match(&self, haystack: &str, matched: &mut [bool],
+                     first_only: bool) {
+    let size = self.prog.insts.len();
+    let mut clist = Threads::new(size);
+    let mut nlist = Threads::new(size);
+    let mut remaining = matched.iter().filter(|m| !**m).count();
+
+    let mut at = 0;
+    loop {
+      // a match may begin at any position
+      self.add_thread(&mut clist, self.prog.start, haystack, at, matched,
+                      &mut remaining);
+      if remaining == 0 || (first_only && remaining < matched.len()) {
+        return;
+      }
+
+      let (c, next) = match haystack[at..].chars().next() {
+        Some(c) => (c as u32, at + c.len_utf8()),
+        None => { return; },
+      };
+
+      // step every thread over this character
+      for i in 0..clist.dense.len() {
+        let pc = clist.dense[i];
+        let step = match &self.prog.insts[pc] {
+          Inst::Char { c: ic, .. } => *ic == c,
+          Inst::Ranges { ranges, .. } => ranges.iter().any(|r| r.includes(c)),
+          _ => false,
+        };
+        if step {
+          self.add_thread(&mut nlist, pc + 1, haystack, next, matched,
+                          &mut remaining);
+        }
+      }
+
+      std::mem::swap(&mut clist, &mut nlist);
+      nlist.clear();
+      at = next;
+    }
+  }
+
+  // add pc and everything reachable from it without consuming anything,
+  // with the threads at index at of the haystack
+  fn add_thread(&self, list: &mut Threads, pc: usize, haystack: &str,
+                at: usize, matched: &mut [bool], remaining: &mut usize) {
+    let mut stack = vec![pc];
+    while let Some(pc) = stack.pop() {
+      if !list.insert(pc) {
+        continue;
+      }
+
+      match &self.prog.insts[pc] {
+        Inst::Split(a, b) => {
+          stack.push(*b);
+          stack.push(*a);
+        },
+        Inst::Jmp(t) => { stack.push(*t); },
+        Inst::Save(_) => { stack.push(pc + 1); },
+        Inst::Assert(anchor) => {
+          if anchor.matches(haystack.as_bytes(), at) {
+            stack.push(pc + 1);
+          }
+        },
+        Inst::Match(n) => {
+          if !matched[*n] {
+            matched[*n] = true;
+            *remaining -= 1;
+          }
+        },
+        Inst::Char { .. } | Inst::Ranges { .. } | Inst::Fail => {
+          // Empty - these wait to be stepped
+        },
+      }
+    }
+  }
+}
@@ -0,0 +1,77 @@
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Mutex;
+
+// values that are costly to make and only needed for a while (like the
+// scratch space of a search), kept for reuse by whichever thread wants one
+// next, so a RegEx can be shared between threads without them waiting on
+// each other for longer than it takes to pop or push one
+pub(crate) struct Pool<T> {
+  free: Mutex<Vec<T>>,
+  create: fn() -> T,
+}
+
+impl<T> Pool<T> {
+  pub fn new(create: fn() -> T) -> Self {
+    return Pool { free: Mutex::new(vec![]), create };
+  }
+
+  // a value to use, which goes back in the pool when it's dropped
+  pub fn get(&self) -> PoolGuard<'_, T> {
+    let value = self.lock().pop().unwrap_or_else(self.create);
+    return PoolGuard { pool: self, value: Some(value) };
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, Vec<T>> {
+    // the lock is never held while anything can panic, but if it was the
+    // values are still fine to use
+    return self.free.lock().unwrap_or_else(|e| e.into_inner());
+  }
+}
+
+pub(crate) struct PoolGuard<'p, T> {
+  pool: &'p Pool<T>,
+  value: Option<T>,
+}
+
+impl<T> Deref for PoolGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    return self.value.as_ref().unwrap();
+  }
+}
+
+impl<T> DerefMut for PoolGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    return self.value.as_mut().unwrap();
+  }
+}
+
+impl<T> Drop for PoolGuard<'_, T> {
+  fn drop(&mut self) {
+    if let Some(value) = self.value.take() {
+      self.pool.lock().push(value);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn pool_reuses_values() {
+    let pool: Pool<Vec<u8>> = Pool::new(Vec::new);
+    {
+      let mut v = pool.get();
+      v.push(1);
+      // both are in use, so the second is new
+      assert!(pool.get().is_empty());
+    }
+    assert_eq!(pool.lock().len(), 2);
+    // the last one back is the first out
+    assert_eq!(*pool.get(), vec![1]);
+    assert_eq!(pool.lock().len(), 2);
+  }
+}
@@ -0,0 +1,356 @@
+use crate::ast::Anchor;
+use crate::ast::CharRange;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::scanner::PerlCC;
+
+/// Turn a tree back into an expression.
+///
+/// Parsing the result gives the same tree again (apart from spans), though
+/// not always the text it was first parsed from: e.g.: `[0-9]` is printed as
+/// `\d`, which is the same Charset. Stars that repeat something other than
+/// `*`, `+` or `?` times can't be written as one, so they are written out
+/// longhand.
+pub fn print(tree: &TreeNode) -> String {
+  return Printer::new(false).print(tree);
+}
+
+/// Turn a tree from `ast::parse_bytes` back into an expression, see `print`.
+pub fn print_bytes(tree: &TreeNode) -> String {
+  return Printer::new(true).print(tree);
+}
+
+// characters that have to be escaped to be matched literally
+const RESERVED: &[char] = &[
+  '|', '*', '(', ')', '[', ']', '^', '$', '?', '+', '.', '\\',
+];
+
+// escapes that stand for a set of ranges
+const CLASSES: &[(&str, &[CharRange])] = &[
+  ("\\d", PerlCC::DIGIT),
+  ("\\D", PerlCC::NOT_DIGIT),
+  ("\\w", PerlCC::WORD),
+  ("\\W", PerlCC::NOT_WORD),
+  ("\\s", PerlCC::WHITESPACE),
+  ("\\S", PerlCC::NOT_WHITESPACE),
+  // `\N` is the same
+  (".", PerlCC::NOT_NEWLINE),
+];
+
+// a piece of a Charset
+enum Item<'n> {
+  Class(&'static str),
+  Range(&'n CharRange),
+}
+
+struct Printer {
+  bytes: bool,
+  unicode: bool, // whether `(?u)` is on at the end of out
+  multi_line: bool, // whether `(?m)` is
+  out: String,
+}
+
+impl Printer {
+  fn new(bytes: bool) -> Self {
+    return Printer {
+      bytes,
+      unicode: true,
+      multi_line: false,
+      out: String::new(),
+    };
+  }
+
+  fn print(mut self, tree: &TreeNode) -> String {
+    self.print_node(tree);
+    return self.out;
+  }
+
+  fn print_node(&mut self, node: &TreeNode) {
+    match node.n_type {
+      NodeType::Word => {
+        for c in &node.image {
+          if node.unicode {
+            self.push_char(*c, false);
+          }
+          else {
+            self.push_byte(*c as u32);
+          }
+        }
+      },
+      NodeType::Charset => self.print_charset(node),
+      NodeType::Union => {
+        for (i, n) in node.children.iter().enumerate() {
+          if i > 0 {
+            self.out.push('|');
+          }
+          self.print_node(n);
+        }
+      },
+      NodeType::Star => self.print_star(node),
+      NodeType::Group => {
+        for n in &node.children {
+          self.print_node(n);
+        }
+      },
+      NodeType::MatchGroup => {
+        self.out.push('(');
+        if let Some(name) = &node.name {
+          self.out.push_str(&format!("?<{}>", name));
+        }
+        for n in &node.children {
+          self.print_node(n);
+        }
+        self.out.push(')');
+      },
+      NodeType::Anchor => self.print_anchor(node.anchor),
+      NodeType::Empty | NodeType::Error => {
+        // Empty
+      },
+    }
+  }
+
+  fn print_anchor(&mut self, anchor: Anchor) {
+    // anchors take on whether `(?m)` is on
+    let (c, multi_line) = match anchor {
+      Anchor::Start => ('^', false),
+      Anchor::End => ('$', false),
+      Anchor::LineStart => ('^', true),
+      Anchor::LineEnd => ('$', true),
+    };
+    if self.multi_line != multi_line {
+      self.out.push_str(if multi_line { "(?m)" } else { "(?-m)" });
+      self.multi_line = multi_line;
+    }
+    self.out.push(c);
+  }
+
+  fn print_star(&mut self, node: &TreeNode) {
+    let n = &node.children[0];
+    let min = node.repeats.min;
+    let max = node.repeats.max;
+
+    match (min, max) {
+      (0, 0) => { self.print_node(n); self.out.push('*'); },
+      (1, 0) => { self.print_node(n); self.out.push('+'); },
+      (0, 1) => { self.print_node(n); self.out.push('?'); },
+      // as many copies as are needed, then one or more
+      (_, 0) => {
+        for _ in 1..min {
+          self.print_node(n);
+        }
+        self.print_node(n);
+        self.out.push('+');
+      },
+      // as many copies as are needed, then the optional ones
+      _ => {
+        for _ in 0..min {
+          self.print_node(n);
+        }
+        for _ in min..max {
+          self.print_node(n);
+          self.out.push('?');
+        }
+      },
+    }
+  }
+
+  fn print_charset(&mut self, node: &TreeNode) {
+    if node.ranges.is_empty() {
+      self.out.push_str("[^]");
+      return;
+    }
+
+    // escapes are stored in reverse, look for them that way
+    let mut items = vec![];
+    let mut i = 0;
+    while i < node.ranges.len() {
+      let rest = &node.ranges[i..];
+      let class = CLASSES.iter().find(|(_, ranges)| {
+        ranges.len() <= rest.len() &&
+        ranges.iter().rev().zip(rest).all(|(a, b)| a == b)
+      });
+      match class {
+        Some((escape, ranges)) => {
+          items.push(Item::Class(escape));
+          i += ranges.len();
+        },
+        None => {
+          items.push(Item::Range(&node.ranges[i]));
+          i += 1;
+        },
+      }
+    }
+
+    // escapes like `\d` take on whether `(?u)` is on
+    let has_class = items.iter().any(|item| matches!(item, Item::Class(_)));
+    if has_class && self.unicode != node.unicode {
+      self.out.push_str(if node.unicode { "(?u)" } else { "(?-u)" });
+      self.unicode = node.unicode;
+    }
+
+    // the ranges written out by hand are negated by '^' all together
+    let negated = items.iter().any(|item| {
+      matches!(item, Item::Range(r) if r.negate)
+    });
+
+    if items.len() == 1 && !negated {
+      if let Item::Class(escape) = items[0] {
+        self.out.push_str(escape);
+        return;
+      }
+    }
+
+    self.out.push('[');
+    if negated {
+      self.out.push('^');
+    }
+    for item in items {
+      match item {
+        Item::Class(escape) => self.out.push_str(escape),
+        Item::Range(r) => {
+          self.push_class_unit(r.min, node.unicode);
+          if r.max != r.min {
+            self.out.push('-');
+            self.push_class_unit(r.max, node.unicode);
+          }
+        },
+      }
+    }
+    self.out.push(']');
+  }
+
+  // one end of a range in a charset
+  fn push_class_unit(&mut self, u: u32, unicode: bool) {
+    if self.bytes && !unicode && u <= 0xFF {
+      self.push_byte(u);
+      return;
+    }
+    match char::from_u32(u) {
+      Some(c) => self.push_char(c, true),
+      None => self.out.push_str(&format!("\\u{:04x}", u)),
+    }
+  }
+
+  fn push_byte(&mut self, u: u32) {
+    self.out.push_str(&format!("\\x{:02X}", u));
+  }
+
+  fn push_char(&mut self, c: char, in_charset: bool) {
+    match c {
+      '\t' => self.out.push_str("\\t"),
+      '\n' => self.out.push_str("\\n"),
+      '\x0b' => self.out.push_str("\\v"),
+      '\x0c' => self.out.push_str("\\f"),
+      '\r' => self.out.push_str("\\r"),
+      // '-' is only special in a charset
+      _ if RESERVED.contains(&c) || (in_charset && c == '-') => {
+        self.out.push('\\');
+        self.out.push(c);
+      },
+      // `\u` escapes only go up to 0xFFFF
+      _ if c.is_control() && (c as u32) <= 0xFFFF => {
+        self.out.push_str(&format!("\\u{:04x}", c as u32));
+      },
+      _ => self.out.push(c),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::ast::TreeNode;
+  use crate::ast::VisitorMut;
+  use crate::error::Span;
+  use super::*;
+
+  struct ClearSpans;
+
+  impl VisitorMut for ClearSpans {
+    fn visit_pre(&mut self, node: &mut TreeNode) {
+      node.span = Span::default();
+    }
+  }
+
+  fn without_spans(mut tree: TreeNode) -> TreeNode {
+    tree.visit_mut(&mut ClearSpans);
+    return tree;
+  }
+
+  // parse -> print -> parse gives the same tree, returns what was printed
+  fn round_trip(expr: &str) -> String {
+    let tree = without_spans(ast::parse(expr).unwrap());
+    let printed = print(&tree);
+    let again = without_spans(ast::parse(&printed).unwrap());
+    assert_eq!(tree, again, "{} was printed as {}", expr, printed);
+    return printed;
+  }
+
+  fn round_trip_bytes(expr: &str) -> String {
+    let tree = without_spans(ast::parse_bytes(expr).unwrap());
+    let printed = print_bytes(&tree);
+    let again = without_spans(ast::parse_bytes(&printed).unwrap());
+    assert_eq!(tree, again, "{} was printed as {}", expr, printed);
+    return printed;
+  }
+
+  #[test]
+  fn print_unchanged() {
+    for expr in ["", "abc", "a|b|c", "(a|b)*c+d?", "(|a|)", "()", "a(b(c)d)",
+                 "(?<year>\\d+)-(?<month>\\d+)", "[a-z_]", "[^a-z_]",
+                 "\\w+@\\w+\\.com", ".\\S\\W\\D", "[\\w\\-]", "[^]"] {
+      assert_eq!(round_trip(expr), expr);
+    }
+  }
+
+  #[test]
+  fn print_escapes() {
+    assert_eq!(round_trip("\\|\\*\\(\\)\\[\\]\\^\\$\\?\\+\\.\\\\"),
+               "\\|\\*\\(\\)\\[\\]\\^\\$\\?\\+\\.\\\\");
+    assert_eq!(round_trip("\\x61\\97\\u00e9\\u2603"), "aa\u{e9}\u{2603}");
+    assert_eq!(round_trip("\\t\\n\\v\\f\\r\\u0001"), "\\t\\n\\v\\f\\r\\u0001");
+    assert_eq!(round_trip("[\\-\\]\\^\\t]"), "[\\-\\]\\^\\t]");
+  }
+
+  #[test]
+  fn print_equivalent() {
+    assert_eq!(round_trip("[0-9]"), "\\d");
+    assert_eq!(round_trip("\\N"), ".");
+    assert_eq!(round_trip("(?s).(?-s)."), "[\\u0000-\u{10ffff}].");
+    assert_eq!(round_trip("[a-a]"), "[a]");
+    assert_eq!(round_trip("[\\d]x"), "\\dx");
+    assert_eq!(round_trip("[^a\\d]"), "[^a\\d]");
+    assert_eq!(round_trip("[a\\D]"), "[a\\D]");
+  }
+
+  #[test]
+  fn print_unicode_flags() {
+    assert_eq!(round_trip("(?-u)a.\\d(?u)."), "a(?-u).\\d(?u).");
+    assert_eq!(round_trip("(?-u)[a\\d]"), "(?-u)[a\\d]");
+  }
+
+  #[test]
+  fn print_anchors() {
+    assert_eq!(round_trip("^a$|^$"), "^a$|^$");
+    assert_eq!(round_trip("^(?m)^a$(?-m)$"), "^(?m)^a$(?-m)$");
+    assert_eq!(round_trip("(?m)a(?-m)b"), "ab");
+    assert_eq!(round_trip("[$a]"), "[\\$a]");
+  }
+
+  #[test]
+  fn print_bytes_mode() {
+    assert_eq!(round_trip_bytes("a\\xff\\x41b"), "a\\xFF\\x41b");
+    assert_eq!(round_trip_bytes("[\\x80-\\xFF]+"), "[\\x80-\\xFF]+");
+    assert_eq!(round_trip_bytes("(?-u)\\xFF."), "\\xFF(?-u).");
+  }
+
+  #[test]
+  fn print_longhand_repeats() {
+    let mut tree = ast::parse("a*").unwrap();
+    tree.children[0].repeats.min = 2;
+    tree.children[0].repeats.max = 4;
+    assert_eq!(print(&tree), "aaa?a?");
+    tree.children[0].repeats.max = 0;
+    assert_eq!(print(&tree), "aa+");
+  }
+}
@@ -0,0 +1,478 @@
+use crate::aho::AhoCorasick;
+use crate::ast::Anchor;
+use crate::ast::CharRange;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::error::Span;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// a single instruction of a compiled program (a Thompson NFA)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+  // match one unit (char or byte) exactly
+  Char { c: u32, unicode: bool },
+  // match one unit included in any of the ranges
+  Ranges { ranges: Vec<CharRange>, unicode: bool },
+  // continue at both, the first is preferred
+  Split(usize, usize),
+  Jmp(usize),
+  // record the current position in a capture slot
+  Save(usize),
+  // continue only where the anchor matches, reading nothing
+  Assert(Anchor),
+  // pattern n has matched
+  Match(usize),
+  // never matches anything
+  Fail,
+}
+
+// instructions for one or more patterns, each ending in its own Match
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+  pub insts: Vec<Inst>,
+  pub start: usize,
+  pub pattern_count: usize,
+  // the characters of the expression each instruction was compiled from
+  pub spans: Vec<Span>,
+  // the Unions of many literals, by the instruction they begin at
+  pub literals: BTreeMap<usize, Literals>,
+}
+
+// a Union of many literals, compiled to the instructions up to end, which
+// the backtracker matches with its automaton rather than a branch at a time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Literals {
+  pub end: usize,
+  pub automaton: Arc<AhoCorasick>,
+}
+
+impl Program {
+  // compile several patterns into one program
+  // the start instruction tries every pattern, in order
+  pub fn compile_many(trees: &[&TreeNode]) -> Self {
+    return Self::compile_within(trees, usize::MAX).unwrap();
+  }
+
+  // like compile_many, but None once the program has more than max_size
+  // instructions, which is found out before it is all written (copies of
+  // nested repetitions can make it exponentially bigger than the trees)
+  pub fn compile_within(trees: &[&TreeNode], max_size: usize)
+    -> Option<Self> {
+    let mut compiler = Compiler {
+      insts: vec![],
+      spans: vec![],
+      span: Span::new(0, 0),
+      literals: BTreeMap::new(),
+      max_size,
+    };
+
+    // nothing can match an empty set of patterns
+    if trees.is_empty() {
+      compiler.push(Inst::Fail);
+    }
+
+    // a chain of splits leading to each pattern
+    let mut entries = vec![];
+    for i in 0..trees.len() {
+      if i + 1 < trees.len() {
+        entries.push(compiler.push(Inst::Split(0, 0)));
+      }
+    }
+
+    let mut starts = vec![];
+    for (i, tree) in trees.iter().enumerate() {
+      starts.push(compiler.insts.len());
+      compiler.compile_node(tree);
+      compiler.push(Inst::Match(i));
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+      let next = if i + 1 < entries.len() { entries[i + 1] }
+                 else { starts[i + 1] };
+      compiler.insts[*entry] = Inst::Split(starts[i], next);
+    }
+
+    if compiler.too_big() {
+      return None;
+    }
+
+    let start = if entries.is_empty() {
+      *starts.first().unwrap_or(&0)
+    }
+    else {
+      entries[0]
+    };
+
+    return Some(Program {
+      insts: compiler.insts,
+      start,
+      pattern_count: trees.len(),
+      spans: compiler.spans,
+      literals: compiler.literals,
+    });
+  }
+
+  // whether this is the program trees compile to (as for one loaded with
+  // them), in which case the automata for the unions of literals are taken
+  // from the trees, checked without writing the program again
+  pub fn check(&mut self, trees: &[&TreeNode]) -> bool {
+    if trees.is_empty() {
+      return self.insts == [Inst::Fail] && self.start == 0 &&
+             self.pattern_count == 0;
+    }
+
+    // after the chain of splits leading to each pattern
+    let mut checker = Checker {
+      insts: &self.insts,
+      pc: trees.len() - 1,
+      literals: BTreeMap::new(),
+    };
+    let mut starts = vec![];
+    for (i, tree) in trees.iter().enumerate() {
+      starts.push(checker.pc);
+      if !checker.check_node(tree) || !checker.expect(&Inst::Match(i)) {
+        return false;
+      }
+    }
+    let chained = (0..trees.len() - 1).all(|i| {
+      let next = if i + 2 < trees.len() { i + 1 } else { starts[i + 1] };
+      return self.insts[i] == Inst::Split(starts[i], next);
+    });
+    if !chained || checker.pc != self.insts.len() || self.start != 0 ||
+       self.pattern_count != trees.len() {
+      return false;
+    }
+    self.literals = checker.literals;
+    return true;
+  }
+}
+
+struct Compiler {
+  insts: Vec<Inst>,
+  spans: Vec<Span>,
+  span: Span, // of the node being compiled
+  literals: BTreeMap<usize, Literals>,
+  max_size: usize,
+}
+
+impl Compiler {
+  fn too_big(&self) -> bool {
+    return self.insts.len() > self.max_size;
+  }
+
+  fn push(&mut self, inst: Inst) -> usize {
+    self.insts.push(inst);
+    self.spans.push(self.span);
+    return self.insts.len() - 1;
+  }
+
+  fn compile_node(&mut self, node: &TreeNode) {
+    // what's left of the program isn't written once it's too big
+    if self.too_big() {
+      return;
+    }
+    let outer = self.span;
+    self.span = node.span;
+    match node.n_type {
+      NodeType::Word => {
+        for c in &node.image {
+          self.push(Inst::Char { c: *c as u32, unicode: node.unicode });
+        }
+      },
+      NodeType::Charset => {
+        self.push(Inst::Ranges {
+          ranges: node.ranges.clone(),
+          unicode: node.unicode,
+        });
+      },
+      NodeType::Group => {
+        for n in &node.children {
+          self.compile_node(n);
+        }
+      },
+      NodeType::MatchGroup => {
+        self.push(Inst::Save(node.group * 2));
+        for n in &node.children {
+          self.compile_node(n);
+        }
+        self.push(Inst::Save(node.group * 2 + 1));
+      },
+      NodeType::Anchor => {
+        self.push(Inst::Assert(node.anchor));
+      },
+      NodeType::Union => self.compile_union(node),
+      NodeType::Star => self.compile_star(node),
+      NodeType::Empty => {
+        // Empty
+      },
+      NodeType::Error => {
+        self.push(Inst::Fail);
+      },
+    }
+    self.span = outer;
+  }
+
+  fn compile_union(&mut self, node: &TreeNode) {
+    let start = self.insts.len();
+    let mut jumps = vec![];
+    for (i, n) in node.children.iter().enumerate() {
+      // the last branch doesn't need to split
+      if i + 1 == node.children.len() {
+        self.compile_node(n);
+        break;
+      }
+
+      let split = self.push(Inst::Split(0, 0));
+      self.compile_node(n);
+      jumps.push(self.push(Inst::Jmp(0)));
+      self.insts[split] = Inst::Split(split + 1, self.insts.len());
+    }
+
+    // every branch continues after the union
+    let end = self.insts.len();
+    for j in jumps {
+      self.insts[j] = Inst::Jmp(end);
+    }
+
+    if let Some(automaton) = &node.automaton {
+      let automaton = Arc::clone(automaton);
+      self.literals.insert(start, Literals { end, automaton });
+    }
+  }
+
+  fn compile_star(&mut self, node: &TreeNode) {
+    let n = &node.children[0];
+    let min = node.repeats.min;
+    let max = node.repeats.max;
+
+    // required repetitions are just copies
+    for _ in 0..min {
+      self.compile_node(n);
+    }
+
+    // a maximum of zero means no maximum
+    if max == 0 {
+      let split = self.push(Inst::Split(0, 0));
+      self.compile_node(n);
+      self.push(Inst::Jmp(split));
+      self.insts[split] = Inst::Split(split + 1, self.insts.len());
+      return;
+    }
+
+    // optional repetitions can each be skipped to the end
+    let mut splits = vec![];
+    for _ in min..max {
+      splits.push(self.push(Inst::Split(0, 0)));
+      self.compile_node(n);
+    }
+    let end = self.insts.len();
+    for s in splits {
+      self.insts[s] = Inst::Split(s + 1, end);
+    }
+  }
+}
+
+// goes through trees like the Compiler, but reads each instruction the
+// Compiler would write rather than writing it
+struct Checker<'p> {
+  insts: &'p [Inst],
+  pc: usize, // of the next instruction
+  literals: BTreeMap<usize, Literals>,
+}
+
+impl Checker<'_> {
+  // whether the next instruction is inst, moving past it
+  fn expect(&mut self, inst: &Inst) -> bool {
+    self.pc += 1;
+    return self.insts.get(self.pc - 1) == Some(inst);
+  }
+
+  fn at(&self, pc: usize, inst: &Inst) -> bool {
+    return self.insts.get(pc) == Some(inst);
+  }
+
+  fn check_node(&mut self, node: &TreeNode) -> bool {
+    return match node.n_type {
+      NodeType::Word => node.image.iter().all(|c| {
+        return self.expect(&Inst::Char { c: *c as u32, unicode: node.unicode });
+      }),
+      NodeType::Charset => {
+        self.pc += 1;
+        matches!(self.insts.get(self.pc - 1),
+                 Some(Inst::Ranges { ranges, unicode })
+                   if *ranges == node.ranges && *unicode == node.unicode)
+      },
+      NodeType::Group => node.children.iter().all(|n| self.check_node(n)),
+      NodeType::MatchGroup => {
+        self.expect(&Inst::Save(node.group * 2)) &&
+        node.children.iter().all(|n| self.check_node(n)) &&
+        self.expect(&Inst::Save(node.group * 2 + 1))
+      },
+      NodeType::Anchor => self.expect(&Inst::Assert(node.anchor)),
+      NodeType::Union => self.check_union(node),
+      NodeType::Star => self.check_star(node),
+      NodeType::Empty => true,
+      NodeType::Error => self.expect(&Inst::Fail),
+    };
+  }
+
+  fn check_union(&mut self, node: &TreeNode) -> bool {
+    let start = self.pc;
+    let mut jumps = vec![];
+    for (i, n) in node.children.iter().enumerate() {
+      if i + 1 == node.children.len() {
+        if !self.check_node(n) {
+          return false;
+        }
+        break;
+      }
+
+      let split = self.pc;
+      self.pc += 1;
+      if !self.check_node(n) {
+        return false;
+      }
+      jumps.push(self.pc);
+      self.pc += 1;
+      if !self.at(split, &Inst::Split(split + 1, self.pc)) {
+        return false;
+      }
+    }
+
+    let end = self.pc;
+    if !jumps.iter().all(|j| self.at(*j, &Inst::Jmp(end))) {
+      return false;
+    }
+    if let Some(automaton) = &node.automaton {
+      let automaton = Arc::clone(automaton);
+      self.literals.insert(start, Literals { end, automaton });
+    }
+    return true;
+  }
+
+  fn check_star(&mut self, node: &TreeNode) -> bool {
+    let n = &node.children[0];
+    let min = node.repeats.min;
+    let max = node.repeats.max;
+
+    for _ in 0..min {
+      if !self.check_node(n) {
+        return false;
+      }
+    }
+
+    if max == 0 {
+      let split = self.pc;
+      self.pc += 1;
+      return self.check_node(n) && self.expect(&Inst::Jmp(split)) &&
+             self.at(split, &Inst::Split(split + 1, self.pc));
+    }
+
+    let mut splits = vec![];
+    for _ in min..max {
+      splits.push(self.pc);
+      self.pc += 1;
+      if !self.check_node(n) {
+        return false;
+      }
+    }
+    let end = self.pc;
+    return splits.iter().all(|s| self.at(*s, &Inst::Split(s + 1, end)));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::parser::Parser;
+  use super::*;
+
+  fn compile(expr: &str) -> Program {
+    return Program::compile_many(&[&Parser::new(expr).parse().unwrap()]);
+  }
+
+  #[test]
+  fn compile_word() {
+    let p = compile("ab");
+    assert_eq!(p.insts, vec![Inst::Char { c: 'a' as u32, unicode: true },
+                             Inst::Char { c: 'b' as u32, unicode: true },
+                             Inst::Match(0)]);
+    assert_eq!(p.start, 0);
+  }
+
+  #[test]
+  fn compile_union_and_star() {
+    let p = compile("(a|b)*");
+    assert_eq!(p.insts, vec![Inst::Split(1, 8),
+                             Inst::Save(2),
+                             Inst::Split(3, 5),
+                             Inst::Char { c: 'a' as u32, unicode: true },
+                             Inst::Jmp(6),
+                             Inst::Char { c: 'b' as u32, unicode: true },
+                             Inst::Save(3),
+                             Inst::Jmp(0),
+                             Inst::Match(0)]);
+  }
+
+  #[test]
+  fn compile_plus_and_question() {
+    let p = compile("a+b?");
+    assert_eq!(p.insts, vec![Inst::Char { c: 'a' as u32, unicode: true },
+                             Inst::Split(2, 4),
+                             Inst::Char { c: 'a' as u32, unicode: true },
+                             Inst::Jmp(1),
+                             Inst::Split(5, 6),
+                             Inst::Char { c: 'b' as u32, unicode: true },
+                             Inst::Match(0)]);
+  }
+
+  #[test]
+  fn compile_many_patterns() {
+    let a = Parser::new("a").parse().unwrap();
+    let b = Parser::new("b").parse().unwrap();
+    let c = Parser::new("c").parse().unwrap();
+    let p = Program::compile_many(&[&a, &b, &c]);
+    assert_eq!(p.insts, vec![Inst::Split(2, 1),
+                             Inst::Split(4, 6),
+                             Inst::Char { c: 'a' as u32, unicode: true },
+                             Inst::Match(0),
+                             Inst::Char { c: 'b' as u32, unicode: true },
+                             Inst::Match(1),
+                             Inst::Char { c: 'c' as u32, unicode: true },
+                             Inst::Match(2)]);
+    assert_eq!(p.start, 0);
+    assert_eq!(p.pattern_count, 3);
+  }
+
+  #[test]
+  fn check_compiled_programs() {
+    for expr in ["ab", "(a|b)*", "a+b?", "x(one|two|three)[a-z]?"] {
+      let tree = Parser::new(expr).parse().unwrap();
+      let mut p = compile(expr);
+      assert!(p.check(&[&tree]), "{}", expr);
+      p.insts.swap(0, 1);
+      assert!(!p.check(&[&tree]), "{}", expr);
+    }
+
+    let a = Parser::new("a").parse().unwrap();
+    let b = Parser::new("b*").parse().unwrap();
+    let mut p = Program::compile_many(&[&a, &b, &a]);
+    assert!(p.check(&[&a, &b, &a]));
+    assert!(!p.check(&[&a, &b]));
+    assert!(!p.check(&[&a, &a, &b]));
+    assert!(Program::compile_many(&[]).check(&[]));
+  }
+
+  #[test]
+  fn compile_within_size() {
+    let tree = Parser::new("a+b?").parse().unwrap();
+    assert_eq!(Program::compile_within(&[&tree], 7), Some(compile("a+b?")));
+    assert_eq!(Program::compile_within(&[&tree], 6), None);
+
+    // each level doubles the program, which stops being written once it's
+    // over the limit
+    let nested = format!("{}a{}", "(".repeat(60), ")+".repeat(60));
+    let tree = Parser::new(&nested).parse().unwrap();
+    assert_eq!(Program::compile_within(&[&tree], 1000), None);
+  }
+}
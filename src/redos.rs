@@ -0,0 +1,615 @@
+use crate::ast::unit_set;
+use crate::ast::TreeNode;
+use crate::error::Span;
+use crate::limits::DEFAULT_MAX_SIZE;
+use crate::program::Inst;
+use crate::program::Program;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hash;
+
+// the most ways to reach the next instruction that reads a unit, from one
+// instruction, that are looked at
+const MAX_EDGES: usize = 64;
+// the most states of the searches for ambiguity that are looked at, past
+// this the rest of the expression isn't checked
+const MAX_STATES: usize = 1_000_000;
+
+/// How the time a backtracking matcher takes can grow with the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backtracking {
+  /// Like a power of the input's length (e.g.: `a*a*`).
+  Polynomial,
+  /// Doubling with every few more characters (e.g.: `(a+)+`).
+  Exponential,
+}
+
+/// A part of an expression that a backtracking matcher (like the one used
+/// for `Semantics::LeftmostFirst` elsewhere, or Perl's) can take far too
+/// long on, because it can match the same input in more and more ways.
+///
+/// `attack` gives an input that makes it try all of them: after `prefix`,
+/// each `pump` multiplies the ways (or adds one more for each loop, for
+/// polynomial cases), and `suffix` makes every one of them fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vulnerability {
+  pub kind: Backtracking,
+  /// The repetitions at fault.
+  pub span: Span,
+  pub prefix: String,
+  pub pump: String,
+  /// Empty if nothing is known to fail everywhere.
+  pub suffix: String,
+}
+
+impl Vulnerability {
+  /// An input that takes a backtracking full match a long time, `pump`
+  /// repeated the given number of times.
+  pub fn attack(&self, repeats: usize) -> String {
+    return format!("{}{}{}", self.prefix, self.pump.repeat(repeats),
+                   self.suffix);
+  }
+}
+
+impl fmt::Display for Vulnerability {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = match self.kind {
+      Backtracking::Polynomial => "polynomial",
+      Backtracking::Exponential => "exponential",
+    };
+    return write!(f, "{} backtracking at position {}, on repeats of {:?}",
+                  kind, self.span.start, self.pump);
+  }
+}
+
+/// Every part of a parsed (not optimized) expression that can make a
+/// backtracking matcher take exponential or polynomial time, in the order
+/// they appear.
+///
+/// Only true ambiguity counts: `(a|ab)*` has branches that begin the same
+/// way but can only match any input one way, so it isn't reported, while
+/// `(a|aa)*` is. Very large expressions are only checked in part, and ones
+/// too big to compile with the default size limit aren't checked at all.
+pub fn analyze(tree: &TreeNode) -> Vec<Vulnerability> {
+  let prog = match Program::compile_within(&[tree], DEFAULT_MAX_SIZE) {
+    Some(prog) => prog,
+    None => { return vec![]; },
+  };
+  let nfa = Nfa::new(&prog);
+  let parents = nfa.parents();
+  let looped = nfa.looped();
+  let mut found: Vec<Vulnerability> = vec![];
+  let mut states = 0;
+
+  // the loops that can be reached from the start
+  let loops: Vec<usize> = nfa.consumers().filter(|p| {
+    return looped[*p].is_some() && parents.contains_key(p);
+  }).collect();
+
+  // two different ways round a loop reading the same thing
+  for &p in &loops {
+    if let Some((pump, loops)) = nfa.twice_around(p, &mut states) {
+      let span = outermost(&prog, &loops);
+      let prefix = nfa.path_to(p, &parents);
+      add(&mut found, nfa.vulnerability(Backtracking::Exponential, span,
+                                        prefix, pump));
+    }
+  }
+
+  // a loop that can read the same thing as a later loop, and as the way
+  // from one to the other, so the input can be split between them in as
+  // many ways as it's long (the later loop is in another component, or the
+  // first could be reached again from it)
+  for &p in &loops {
+    let later = match nfa.reach(p, &mut states) {
+      Some(later) => later,
+      None => { break; },
+    };
+    for q in later {
+      if looped[q].is_none() || looped[q] == looped[p] {
+        continue;
+      }
+      if let Some((pump, loops)) = nfa.split_between(p, q, &mut states) {
+        let span = covering(&prog, &loops);
+        if !found.iter().any(|f| inside(span, f.span)) {
+          let prefix = nfa.path_to(p, &parents);
+          add(&mut found, nfa.vulnerability(Backtracking::Polynomial,
+                                            span, prefix, pump));
+        }
+      }
+    }
+  }
+
+  found.sort_by_key(|v| (v.span.start, v.span.end));
+  return found;
+}
+
+// one report for each part of the expression
+fn add(found: &mut Vec<Vulnerability>, v: Vulnerability) {
+  if !found.iter().any(|f| f.span == v.span) {
+    found.push(v);
+  }
+}
+
+// whether a is within b
+fn inside(a: Span, b: Span) -> bool {
+  return b.start <= a.start && a.end <= b.end;
+}
+
+// the widest of the loops (by the Jmp back to their start), which is the
+// repetition the others are inside of
+fn outermost(prog: &Program, loops: &[usize]) -> Span {
+  return loops.iter()
+              .map(|pc| prog.spans[*pc])
+              .max_by_key(|s| s.end - s.start)
+              .unwrap_or_default();
+}
+
+// from the first of the loops to the end of the last
+fn covering(prog: &Program, loops: &[usize]) -> Span {
+  let spans = loops.iter().map(|pc| prog.spans[*pc]);
+  let start = spans.clone().map(|s| s.start).min().unwrap_or(0);
+  let end = spans.map(|s| s.end).max().unwrap_or(0);
+  return Span::new(start, end);
+}
+
+// a way from one instruction that reads a unit to the next one, through
+// the jumps back to the start of the loops listed
+#[derive(Debug, Clone, PartialEq)]
+struct Edge {
+  to: usize,
+  loops: Vec<usize>,
+}
+
+// how a search for ambiguity got to a state
+struct Step<S> {
+  from: S,
+  unit: u32, // read by every way
+  loops: Vec<usize>, // gone round by any way
+}
+
+// the units read and loops gone round on the way from first to last
+fn walk<S: Copy + Eq + Hash>(first: S, last: S, steps: &HashMap<S, Step<S>>)
+  -> (Vec<u32>, Vec<usize>) {
+  let mut units = vec![];
+  let mut loops = vec![];
+  let mut s = last;
+  while s != first {
+    let step = &steps[&s];
+    units.push(step.unit);
+    loops.extend(&step.loops);
+    s = step.from;
+  }
+  units.reverse();
+  return (units, loops);
+}
+
+// the program without the instructions that don't read anything: the
+// states are the instructions that do (by index), and one more to start at
+struct Nfa {
+  sets: Vec<Vec<(u32, u32)>>, // what each state reads, empty if nothing
+  edges: Vec<Vec<Edge>>,
+  start: usize,
+  unread: Vec<(u32, u32)>, // what no state reads
+}
+
+impl Nfa {
+  fn new(prog: &Program) -> Self {
+    let start = prog.insts.len();
+    let mut sets = vec![vec![]; start + 1];
+    let mut edges = vec![vec![]; start + 1];
+    for (pc, inst) in prog.insts.iter().enumerate() {
+      match inst {
+        Inst::Char { c, .. } => { sets[pc] = vec![(*c, *c)]; },
+        Inst::Ranges { ranges, .. } => { sets[pc] = unit_set(ranges); },
+        _ => { continue; },
+      }
+      edges[pc] = closure(prog, pc + 1);
+    }
+    edges[start] = closure(prog, prog.start);
+
+    // anything no state reads fails wherever it's read
+    let mut read = vec![];
+    for set in &sets {
+      read.extend(set);
+    }
+    let unread = complement(&merge(read));
+    return Nfa { sets, edges, start, unread };
+  }
+
+  fn consumers(&self) -> impl Iterator<Item = usize> + '_ {
+    return (0..self.start).filter(|s| !self.sets[*s].is_empty());
+  }
+
+  // the state before each on a shortest way to it from the start
+  fn parents(&self) -> HashMap<usize, usize> {
+    let mut parents: HashMap<usize, usize> = HashMap::new();
+    let mut queue = VecDeque::from([self.start]);
+    while let Some(s) = queue.pop_front() {
+      for e in &self.edges[s] {
+        if let Entry::Vacant(parent) = parents.entry(e.to) {
+          parent.insert(s);
+          queue.push_back(e.to);
+        }
+      }
+    }
+    return parents;
+  }
+
+  // what can be read to get from the start to state p (reading what p
+  // does), which has to be reachable
+  fn path_to(&self, p: usize, parents: &HashMap<usize, usize>) -> Vec<u32> {
+    let mut path = vec![];
+    let mut s = p;
+    while let Some(from) = parents.get(&s) {
+      path.push(s);
+      s = *from;
+    }
+    path.reverse();
+    return path.iter().map(|s| pick(&self.sets[*s])).collect();
+  }
+
+  // for each state that can be reached again from itself by reading, the
+  // strongly connected component it's in (by Tarjan's algorithm, with a
+  // stack of its own rather than recursion)
+  fn looped(&self) -> Vec<Option<usize>> {
+    let n = self.start + 1;
+    let mut index = vec![usize::MAX; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = vec![];
+    let mut looped = vec![None; n];
+    let mut next = 0;
+    let mut components = 0;
+
+    for root in 0..n {
+      if index[root] != usize::MAX {
+        continue;
+      }
+      // each state being visited, and which of its edges is next
+      let mut work = vec![(root, 0)];
+      index[root] = next;
+      low[root] = next;
+      next += 1;
+      stack.push(root);
+      on_stack[root] = true;
+
+      while let Some(&(s, i)) = work.last() {
+        if let Some(e) = self.edges[s].get(i) {
+          work.last_mut().unwrap().1 += 1;
+          let t = e.to;
+          if index[t] == usize::MAX {
+            index[t] = next;
+            low[t] = next;
+            next += 1;
+            stack.push(t);
+            on_stack[t] = true;
+            work.push((t, 0));
+          }
+          else if on_stack[t] {
+            low[s] = low[s].min(index[t]);
+          }
+          continue;
+        }
+
+        work.pop();
+        if let Some(&(parent, _)) = work.last() {
+          low[parent] = low[parent].min(low[s]);
+        }
+        if low[s] == index[s] {
+          let mut members = vec![];
+          loop {
+            let t = stack.pop().unwrap();
+            on_stack[t] = false;
+            members.push(t);
+            if t == s {
+              break;
+            }
+          }
+          if members.len() > 1 || self.edges[s].iter().any(|e| e.to == s) {
+            for t in members {
+              looped[t] = Some(components);
+            }
+          }
+          components += 1;
+        }
+      }
+    }
+    return looped;
+  }
+
+  // the states that can be reached from p by reading at least one unit, in
+  // order, or None once the searches have looked at too many states
+  fn reach(&self, p: usize, states: &mut usize) -> Option<Vec<usize>> {
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<usize> =
+      self.edges[p].iter().map(|e| e.to).collect();
+    while let Some(t) = queue.pop_front() {
+      if seen.insert(t) {
+        *states += 1;
+        if *states > MAX_STATES {
+          return None;
+        }
+        queue.extend(self.edges[t].iter().map(|e| e.to));
+      }
+    }
+    let mut reached: Vec<usize> = seen.into_iter().collect();
+    reached.sort();
+    return Some(reached);
+  }
+
+  // two different ways from p back to p that read the same units, which
+  // are what's pumped, and the loops they go round
+  fn twice_around(&self, p: usize, states: &mut usize)
+    -> Option<(Vec<u32>, Vec<usize>)> {
+    // the pair of states, and whether the ways have parted yet
+    type State = (usize, usize, bool);
+    let first: State = (p, p, false);
+    let mut steps: HashMap<State, Step<State>> = HashMap::new();
+    let mut queue = VecDeque::from([first]);
+
+    while let Some(state) = queue.pop_front() {
+      let (a, b, parted) = state;
+      for (i, e) in self.edges[a].iter().enumerate() {
+        for (j, f) in self.edges[b].iter().enumerate() {
+          let both = intersect(&self.sets[e.to], &self.sets[f.to]);
+          let next = (e.to, f.to, parted || i != j);
+          if both.is_empty() || next == first || steps.contains_key(&next) {
+            continue;
+          }
+          let loops = [&e.loops[..], &f.loops[..]].concat();
+          steps.insert(next, Step { from: state, unit: pick(&both), loops });
+          if next == (p, p, true) {
+            return Some(walk(first, next, &steps));
+          }
+          *states += 1;
+          if *states > MAX_STATES {
+            return None;
+          }
+          queue.push_back(next);
+        }
+      }
+    }
+    return None;
+  }
+
+  // a way round p's loop, a way from p to q, and a way round q's loop that
+  // all read the same units, which are what's pumped, and the loops they go
+  // round
+  fn split_between(&self, p: usize, q: usize, states: &mut usize)
+    -> Option<(Vec<u32>, Vec<usize>)> {
+    type State = (usize, usize, usize);
+    let first: State = (p, p, q);
+    let mut steps: HashMap<State, Step<State>> = HashMap::new();
+    let mut queue = VecDeque::from([first]);
+
+    while let Some(state) = queue.pop_front() {
+      let (a, b, c) = state;
+      for e in &self.edges[a] {
+        for f in &self.edges[b] {
+          let both = intersect(&self.sets[e.to], &self.sets[f.to]);
+          if both.is_empty() {
+            continue;
+          }
+          for g in &self.edges[c] {
+            let all = intersect(&both, &self.sets[g.to]);
+            let next = (e.to, f.to, g.to);
+            if all.is_empty() || next == first || steps.contains_key(&next) {
+              continue;
+            }
+            let loops = [&e.loops[..], &f.loops[..], &g.loops[..]].concat();
+            steps.insert(next, Step { from: state, unit: pick(&all), loops });
+            if next == (p, q, q) {
+              return Some(walk(first, next, &steps));
+            }
+            *states += 1;
+            if *states > MAX_STATES {
+              return None;
+            }
+            queue.push_back(next);
+          }
+        }
+      }
+    }
+    return None;
+  }
+
+  fn vulnerability(&self, kind: Backtracking, span: Span, prefix: Vec<u32>,
+                   pump: Vec<u32>) -> Vulnerability {
+    return Vulnerability {
+      kind,
+      span,
+      prefix: text(&prefix),
+      pump: text(&pump),
+      suffix: if self.unread.is_empty() { String::new() }
+              else { text(&[pick(&self.unread)]) },
+    };
+  }
+}
+
+// every way from pc to an instruction that reads a unit, without reading
+// anything on the way (ways that read nothing after a Match are left out)
+fn closure(prog: &Program, pc: usize) -> Vec<Edge> {
+  let mut edges = vec![];
+  let mut path = vec![];
+  follow(prog, pc, &mut vec![], &mut path, &mut edges);
+  return edges;
+}
+
+fn follow(prog: &Program, pc: usize, loops: &mut Vec<usize>,
+          path: &mut Vec<(usize, usize)>, edges: &mut Vec<Edge>) {
+  // a way round a loop that reads nothing is only worth taking once, and
+  // only if it's through a loop not gone round yet
+  if edges.len() >= MAX_EDGES || path.contains(&(pc, loops.len())) {
+    return;
+  }
+  path.push((pc, loops.len()));
+
+  match &prog.insts[pc] {
+    Inst::Char { .. } | Inst::Ranges { .. } => {
+      edges.push(Edge { to: pc, loops: loops.clone() });
+    },
+    Inst::Split(a, b) => {
+      follow(prog, *a, loops, path, edges);
+      follow(prog, *b, loops, path, edges);
+    },
+    Inst::Jmp(to) if *to < pc => {
+      let back = !loops.contains(&pc);
+      if back {
+        loops.push(pc);
+      }
+      follow(prog, *to, loops, path, edges);
+      if back {
+        loops.pop();
+      }
+    },
+    Inst::Jmp(to) => follow(prog, *to, loops, path, edges),
+    // an anchor may hold or not, either way the search has to try it
+    Inst::Save(_) | Inst::Assert(_) => {
+      follow(prog, pc + 1, loops, path, edges);
+    },
+    Inst::Match(_) | Inst::Fail => {
+      // Empty
+    },
+  }
+  path.pop();
+}
+
+// the units in both sets (both sorted and merged)
+fn intersect(a: &[(u32, u32)], b: &[(u32, u32)]) -> Vec<(u32, u32)> {
+  let mut both = vec![];
+  let (mut i, mut j) = (0, 0);
+  while i < a.len() && j < b.len() {
+    let min = a[i].0.max(b[j].0);
+    let max = a[i].1.min(b[j].1);
+    if min <= max {
+      both.push((min, max));
+    }
+    if a[i].1 < b[j].1 { i += 1; } else { j += 1; }
+  }
+  return both;
+}
+
+// sorted, with ranges that touch joined
+fn merge(mut set: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+  set.sort();
+  let mut merged: Vec<(u32, u32)> = vec![];
+  for (min, max) in set {
+    match merged.last_mut() {
+      Some(last) if min <= last.1.saturating_add(1) => {
+        last.1 = last.1.max(max);
+      },
+      _ => merged.push((min, max)),
+    }
+  }
+  return merged;
+}
+
+// every character not in the set
+fn complement(set: &[(u32, u32)]) -> Vec<(u32, u32)> {
+  let mut rest = vec![];
+  let mut next = 0;
+  for (min, max) in set {
+    if *min > next {
+      rest.push((next, min - 1));
+    }
+    next = max + 1;
+  }
+  if next <= char::MAX as u32 {
+    rest.push((next, char::MAX as u32));
+  }
+  return rest;
+}
+
+// a unit from a non-empty set, preferably one that can be seen when printed
+fn pick(set: &[(u32, u32)]) -> u32 {
+  let visible = intersect(set, &[('!' as u32, '~' as u32)]);
+  if let Some((min, _)) = visible.first() {
+    return *min;
+  }
+  for (min, max) in set {
+    if let Some(u) = (*min..=*max).find(|u| char::from_u32(*u).is_some()) {
+      return u;
+    }
+  }
+  return set.first().map(|r| r.0).unwrap_or(0);
+}
+
+fn text(units: &[u32]) -> String {
+  return units.iter().filter_map(|u| char::from_u32(*u)).collect();
+}
+
+#[cfg(test)]
+mod test {
+  use crate::ast;
+  use crate::regex::RegEx;
+  use super::*;
+
+  fn analyze(expr: &str) -> Vec<(Backtracking, String, String)> {
+    let chars: Vec<char> = expr.chars().collect();
+    return super::analyze(&ast::parse(expr).unwrap())
+                 .iter()
+                 .map(|v| (v.kind,
+                           chars[v.span.start..v.span.end].iter().collect(),
+                           v.attack(3)))
+                 .collect();
+  }
+
+  #[test]
+  fn redos_nested_stars() {
+    assert_eq!(analyze("(a+)+"),
+               vec![(Backtracking::Exponential, String::from("(a+)+"),
+                     String::from("aaaaaaaa!"))]);
+    assert_eq!(analyze("x(a*)*y"),
+               vec![(Backtracking::Exponential, String::from("(a*)*"),
+                     String::from("xaaaa!"))]);
+    assert_eq!(analyze("(\\w+\\d+)+").len(), 1);
+    // the languages don't overlap
+    assert_eq!(analyze("(a+b)+"), vec![]);
+  }
+
+  #[test]
+  fn redos_overlapping_branches() {
+    assert_eq!(analyze("(a|aa)*b"),
+               vec![(Backtracking::Exponential, String::from("(a|aa)*"),
+                     String::from("aaaaaaaaaa!"))]);
+    assert_eq!(analyze("(\\w|\\d)*")[0].0, Backtracking::Exponential);
+    // only one way to split up any input
+    assert_eq!(analyze("(a|ab)*"), vec![]);
+    assert_eq!(analyze("(a|b)*"), vec![]);
+  }
+
+  #[test]
+  fn redos_adjacent_stars() {
+    assert_eq!(analyze("a*a*"),
+               vec![(Backtracking::Polynomial, String::from("a*a*"),
+                     String::from("aaaa!"))]);
+    assert_eq!(analyze("x\\d+.\\d+")[0].1, "\\d+.\\d+");
+    assert_eq!(analyze("a*b*"), vec![]);
+  }
+
+  #[test]
+  fn redos_large_expressions() {
+    // many loops that can't reach each other
+    let words: Vec<String> = (0..4000).map(|i| format!("w{}x[a-z]+y", i))
+                                      .collect();
+    assert_eq!(analyze(&words.join("|")), vec![]);
+    // many loops that can all reach each other, which are only checked in
+    // part (so the last two aren't)
+    let chain: String = (0..1000).map(|i| format!("w{}x[a-z]+", i)).collect();
+    assert_eq!(analyze(&format!("{}a*a*", chain)), vec![]);
+  }
+
+  #[test]
+  fn redos_attack_fails() {
+    for expr in ["(a+)+", "x(a|aa)*y", "(\\w+\\d+)+", "a*a*", "\\d+.\\d+"] {
+      let v = &super::analyze(&ast::parse(expr).unwrap())[0];
+      let re = RegEx::new(expr).unwrap();
+      assert!(!v.suffix.is_empty());
+      assert!(!re.is_full_match(&v.attack(10)));
+    }
+  }
+}
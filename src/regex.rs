@@ -1,136 +1,445 @@
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::backtrack;
+use crate::backtrack::Backtracker;
+use crate::builder::RegExBuilder;
+use crate::builder::Semantics;
+use crate::error::Error;
+use crate::limits::Budget;
+use crate::limits::LimitExceeded;
+use crate::limits::Limits;
+use crate::literal;
+use crate::literal::Prefilter;
+use crate::literal::Required;
+use crate::onepass::OnePass;
+use crate::optimize::optimize;
 use crate::parser::Parser;
-use crate::parser::TreeNode;
-use crate::parser::NodeType;
-use std::collections::VecDeque;
+use crate::pool::Pool;
+use crate::program::Program;
+use crate::serialize;
+use crate::serialize::Kind;
+use crate::serialize::LoadError;
+use crate::serialize::Loaded;
+use crate::serialize::Parts;
+use crate::replace;
+use crate::replace::Replacer;
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// A single match (or matching group) in a haystack.
+///
+/// Only the position of the match is stored; its text is borrowed from the
+/// haystack when it is asked for. `bytes::Match` is the same for a byte
+/// haystack.
+pub struct Match<'h, H: ?Sized = str> {
+  haystack: &'h H,
+  start: usize,
+  end: usize,
+}
 
-pub struct MatchGroup {
-  pub start: usize,
-  pub end: usize,
-  pub string: String,
+// written out rather than derived, which would want H itself to be Clone
+impl<H: ?Sized> Clone for Match<'_, H> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<H: ?Sized> Copy for Match<'_, H> {}
+
+impl<H: Debug + ?Sized> Debug for Match<'_, H> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return f.debug_struct("Match")
+            .field("haystack", &self.haystack)
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish();
+  }
+}
+
+impl<H: PartialEq + ?Sized> PartialEq for Match<'_, H> {
+  fn eq(&self, other: &Self) -> bool {
+    return self.haystack == other.haystack
+           && self.start == other.start
+           && self.end == other.end;
+  }
+}
+
+impl<H: Eq + ?Sized> Eq for Match<'_, H> {}
+
+impl<'h, H: ?Sized> Match<'h, H> {
+  fn new(haystack: &'h H, start: usize, end: usize) -> Self {
+    return Match { haystack, start, end };
+  }
+
+  /// Byte offset of the first character of the match.
+  pub fn start(&self) -> usize {
+    return self.start;
+  }
+
+  /// Byte offset just past the last character of the match.
+  pub fn end(&self) -> usize {
+    return self.end;
+  }
+
+  /// Length of the match in bytes.
+  pub fn len(&self) -> usize {
+    return self.end - self.start;
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.start == self.end;
+  }
+
+  // the whole haystack the match is in
+  pub(crate) fn haystack(&self) -> &'h H {
+    return self.haystack;
+  }
+}
+
+impl<'h> Match<'h> {
+  /// The match's position in bytes, suitable for slicing the haystack.
+  pub fn byte_range(&self) -> Range<usize> {
+    return self.start..self.end;
+  }
+
+  /// The match's position counted in characters (Unicode scalar values).
+  /// This has to count from the start of the haystack, so it isn't free.
+  pub fn char_range(&self) -> Range<usize> {
+    let char_start = self.haystack[..self.start].chars().count();
+    return char_start..char_start + self.as_str().chars().count();
+  }
+
+  /// The matched text.
+  pub fn as_str(&self) -> &'h str {
+    return &self.haystack[self.start..self.end];
+  }
+}
+
+/// The overall match (group 0) and every matching group of a single match.
+///
+/// Groups are numbered by their opening parenthesis, starting from 1.
+/// A group that didn't participate in the match has no `Match`.
+pub struct Captures<'h, H: ?Sized = str> {
+  haystack: &'h H,
+  groups: Vec<Option<(usize, usize)>>,
+  names: Arc<Vec<Option<String>>>,
+}
+
+impl<H: ?Sized> Clone for Captures<'_, H> {
+  fn clone(&self) -> Self {
+    return Captures {
+      haystack: self.haystack,
+      groups: self.groups.clone(),
+      names: Arc::clone(&self.names),
+    };
+  }
+}
+
+impl<H: Debug + ?Sized> Debug for Captures<'_, H> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return f.debug_struct("Captures")
+            .field("haystack", &self.haystack)
+            .field("groups", &self.groups)
+            .field("names", &self.names)
+            .finish();
+  }
+}
+
+impl<'h, H: ?Sized> Captures<'h, H> {
+  /// The match for group i, if it participated in the match.
+  pub fn get(&self, i: usize) -> Option<Match<'h, H>> {
+    let (start, end) = (*self.groups.get(i)?)?;
+    return Some(Match::new(self.haystack, start, end));
+  }
+
+  /// The match for the group with the given name, if it participated.
+  pub fn name(&self, name: &str) -> Option<Match<'h, H>> {
+    let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+    return self.get(i);
+  }
+
+  /// The overall match, which always exists.
+  pub fn get_match(&self) -> Match<'h, H> {
+    return self.get(0).unwrap();
+  }
+
+  /// Number of groups in the expression, including group 0.
+  #[allow(clippy::len_without_is_empty)] // there is always a group 0
+  pub fn len(&self) -> usize {
+    return self.groups.len();
+  }
+
+  /// Every group in order, including the ones that didn't participate.
+  pub fn iter(&self) -> impl Iterator<Item = Option<Match<'h, H>>> + '_ {
+    return (0..self.groups.len()).map(|i| self.get(i));
+  }
+}
+
+impl<'h> Captures<'h> {
+  /// Expand a replacement template with these captures, see
+  /// `replace::expand`.
+  pub fn expand(&self, template: &str, dst: &mut String) {
+    replace::expand(self, template, dst);
+  }
+}
+
+/// The result of `RegEx::backtrack_at`.
+pub struct Backtracked<'h, H: ?Sized = str> {
+  /// The first match the backtracker found, if any.
+  pub captures: Option<Captures<'h, H>>,
+  /// False if the haystack was too long to remember every step tried, in
+  /// which case the search wasn't bounded and may have taken a long time.
+  pub memoized: bool,
+}
+
+impl<H: ?Sized> Clone for Backtracked<'_, H> {
+  fn clone(&self) -> Self {
+    return Backtracked {
+      captures: self.captures.clone(),
+      memoized: self.memoized,
+    };
+  }
 }
 
-pub struct MatchData {
+impl<H: Debug + ?Sized> Debug for Backtracked<'_, H> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return f.debug_struct("Backtracked")
+            .field("captures", &self.captures)
+            .field("memoized", &self.memoized)
+            .finish();
+  }
+}
+
+impl<'h> Index<usize> for Captures<'h> {
+  type Output = str;
+
+  // panics if the group didn't participate in the match
+  fn index(&self, i: usize) -> &str {
+    return match self.get(i) {
+      Some(m) => m.as_str(),
+      None => panic!("no match for group {}", i),
+    };
+  }
+}
+
+// something the interpreter can step through one unit (char or byte) at a time
+pub(crate) trait Haystack {
+  // whether units that aren't unicode are single bytes, as they are for
+  // `bytes::RegEx` (a str only ever has whole characters)
+  const BYTES: bool;
+
+  // the unit beginning at i and the index of the unit after it
+  // if unicode is false the unit is a single byte (where that makes sense)
+  fn unit_at(&self, i: usize, unicode: bool) -> Option<(u32, usize)>;
+  // the next index after i that a search is allowed to start at
+  fn next_start(&self, i: usize) -> usize;
+  fn len(&self) -> usize;
+  // the haystack as it is stored, for finding literals in
+  fn bytes(&self) -> &[u8];
+
+  // where to look for the next match after a match from start to end
+  fn after_match(&self, start: usize, end: usize) -> usize {
+    // an empty match would be found again, so step past it
+    if start < end {
+      return end;
+    }
+    return self.next_start(end);
+  }
+}
+
+impl Haystack for str {
+  const BYTES: bool = false;
+
+  fn unit_at(&self, i: usize, _unicode: bool) -> Option<(u32, usize)> {
+    // a str only ever has whole characters
+    if !self.is_char_boundary(i) {
+      return None;
+    }
+    let c = self[i..].chars().next()?;
+    return Some((c as u32, i + c.len_utf8()));
+  }
+
+  fn next_start(&self, i: usize) -> usize {
+    // never start in the middle of a character
+    return match self.get(i..).and_then(|s| s.chars().next()) {
+      Some(c) => i + c.len_utf8(),
+      None => i + 1,
+    };
+  }
+
+  fn len(&self) -> usize {
+    return str::len(self);
+  }
+
+  fn bytes(&self) -> &[u8] {
+    return self.as_bytes();
+  }
+}
+
+// positions found by the interpreter, before any text is attached to them
+// groups are indexed by group number, group 0 is the whole match
+pub(crate) struct MatchPositions {
   pub start: usize,
   pub end: usize,
-  pub groups: VecDeque<MatchGroup>,
+  pub groups: Vec<Option<(usize, usize)>>,
 }
 
-impl MatchData {
-  fn new(start: usize, end: usize) -> Self {
-    return MatchData {
+impl MatchPositions {
+  pub fn new(start: usize, end: usize, group_count: usize) -> Self {
+    return MatchPositions {
       start,
       end,
-      groups: VecDeque::new(),
+      groups: vec![None; group_count],
     };
   }
 }
 
-pub struct RegExEnv {
-  chars: Vec<char>,
-  pub string: String,
-  pub matches: Vec<MatchData>,
+pub(crate) struct RegExEnv<'h, H: Haystack + ?Sized> {
+  haystack: &'h H,
+  group_count: usize,
+  budget: Budget,
 }
 
-impl RegExEnv {
-  fn new(s: String) -> Self {
+impl<'h, H: Haystack + ?Sized> RegExEnv<'h, H> {
+  pub fn new(haystack: &'h H, group_count: usize) -> Self {
     return RegExEnv {
-      chars: (&s).chars().collect(),
-      string: s,
-      // the below should be set before the match is returned
-      matches: vec![],
+      haystack,
+      group_count,
+      budget: Budget::unlimited(),
     };
   }
 
-  fn interpret(&mut self, tree: &TreeNode, start: usize) -> (bool, usize) {
-    let mut new_match = MatchData::new(start, 0);
-    let (success, end) = self.interpret_node(tree, start, &mut new_match);
+  // give up on searching once a limit is gone over (the clock starts now)
+  pub fn limits(mut self, limits: &Limits) -> Self {
+    self.budget = Budget::new(limits);
+    return self;
+  }
+
+  // the limit that was gone over, in which case nothing that was found
+  // (or not found) can be trusted
+  pub fn exceeded(&self) -> Option<LimitExceeded> {
+    return self.budget.exceeded();
+  }
 
-    // if matched, create new MatchData
-    if success {
-      new_match.end = end;
-      new_match.groups.push_front(MatchGroup {
-          start,
-          end,
-          string: String::from(&self.string[start..end]),
-      });
-      self.matches.push(new_match);
-
-      if start < end {
-        return (success, end);
+  // find the leftmost match that starts at or after start
+  // if there is a prefilter, only the places it finds are tried
+  pub fn search(&self, tree: &TreeNode, prefilter: Option<&Prefilter>,
+                start: usize) -> Option<MatchPositions> {
+    let mut start = start;
+    while start <= self.haystack.len() {
+      if let Some(p) = prefilter {
+        start = p.find(self.haystack.bytes(), start)?;
       }
-      else {
-        return (success, end + 1);
+
+      // return as soon as a match is found
+      if let Some(m) = self.match_at(tree, start) {
+        return Some(m);
       }
+      if self.exceeded().is_some() {
+        return None;
+      }
+
+      // if attempt failed, try again from the next possible start
+      // (the interpreter may have gotten past start before failing)
+      start = self.haystack.next_start(start);
     }
-    // if attempt failed, end = start and match_all will enter infinite loop
-    else {
-      return (success, end + 1);
-    }
+
+    // nothing was found
+    return None;
   }
 
-  fn print_chars(&self, i: usize) -> String {
-    let mut s = String::new();
-    let mut ci = i;
-    while ci < self.chars.len() {
-      s.push(self.chars[ci]);
-      ci += 1;
+  // try to match the tree beginning exactly at start
+  pub fn match_at(&self, tree: &TreeNode, start: usize)
+    -> Option<MatchPositions> {
+    let mut new_match = MatchPositions::new(start, 0, self.group_count);
+    let (success, end) = self.interpret_node(tree, start, &mut new_match);
+
+    if !success || self.exceeded().is_some() {
+      return None;
     }
 
-    return s;
+    new_match.end = end;
+    new_match.groups[0] = Some((start, end));
+    return Some(new_match);
   }
 
-  fn interpret_node(&self, node: &TreeNode, i: usize, m: &mut MatchData)
+  fn interpret_node(&self, node: &TreeNode, i: usize, m: &mut MatchPositions)
     -> (bool, usize) {
+    // every node tried counts against the limits
+    if !self.budget.step() {
+      return (false, i);
+    }
+
     match &node.n_type {
-      NodeType::Word => self.interpret_word(node, i, m),
+      NodeType::Word => self.interpret_word(node, i),
       NodeType::Union => self.interpret_union(node, i, m),
       NodeType::Star => self.interpret_star(node, i, m),
       NodeType::Group => self.interpret_group(node, i, m),
       NodeType::MatchGroup => self.interpret_match_group(node, i, m),
-      NodeType::Charset => self.interpret_charset(node, i, m),
-      _ => {
-        println!("runtime error: unknown node type {:?}", node.n_type);
+      NodeType::Charset => self.interpret_charset(node, i),
+      NodeType::Anchor => {
+        return (node.anchor.matches(self.haystack.bytes(), i), i);
+      },
+      // trees with errors are never matched, and Empty nodes are culled
+      NodeType::Error | NodeType::Empty => {
         return (false, i);
       },
     }
   }
 
-  fn interpret_word(&self, node: &TreeNode, i: usize, m: &mut MatchData)
-    -> (bool, usize) {
+  fn interpret_word(&self, node: &TreeNode, i: usize) -> (bool, usize) {
     let mut w_i = i;
     // try to match every char in word
     for c in &node.image {
-      // reached the end of the string, can't possibly match
-      if w_i >= self.chars.len() {
-        return (false, w_i);
+      match self.haystack.unit_at(w_i, node.unicode) {
+        // break if mismatch
+        Some((u, next_i)) if u == *c as u32 => { w_i = next_i; },
+        // mismatch or end of the string, can't possibly match
+        _ => { return (false, w_i); },
       }
-
-      // break if mismatch
-      if &self.chars[w_i] != c {
-        return (false, w_i);
-      }
-      w_i += 1;
     }
 
     return (true, w_i); // nothing bad happened
   }
 
-  fn interpret_union(&self, node: &TreeNode, i: usize, m: &mut MatchData)
+  fn interpret_union(&self, node: &TreeNode, i: usize, m: &mut MatchPositions)
     -> (bool, usize) {
+    // a Union of many literals is matched all at once
+    if let Some(ac) = &node.automaton {
+      return match ac.find_at(self.haystack.bytes(), i) {
+        Some(lit) => (true, lit.end),
+        None => (false, i),
+      };
+    }
+
     let mut success = false;
     let mut best_i = i;
+    let mut best_groups = None;
     for n in &node.children {
-      let (n_s, n_i) = self.interpret_node(n, i, m);
-      success = success | n_s;
-      if n_s && n_i > best_i {
+      // each branch records its groups separately, only the best one is kept
+      let mut n_m = MatchPositions::new(m.start, m.end, 0);
+      n_m.groups = m.groups.clone();
+      let (n_s, n_i) = self.interpret_node(n, i, &mut n_m);
+      if n_s && (!success || n_i > best_i) {
         best_i = n_i;
+        best_groups = Some(n_m.groups);
       }
+      success |= n_s;
+    }
+
+    if let Some(groups) = best_groups {
+      m.groups = groups;
     }
 
     return (success, best_i);
   }
 
-  fn interpret_star(&self, node: &TreeNode, i: usize, m: &mut MatchData)
+  fn interpret_star(&self, node: &TreeNode, i: usize, m: &mut MatchPositions)
     -> (bool, usize) {
     // there will only ever be one child
     let n = &node.children[0];
@@ -139,8 +448,24 @@ impl RegExEnv {
     // interpret that child as long as possible
     let mut loop_ct = 0;
     loop {
+      // a failed repetition shouldn't leave its groups behind
+      let groups = m.groups.clone();
       let (n_s, n_i) = self.interpret_node(n, best_i, m);
       if !n_s {
+        m.groups = groups;
+        break;
+      }
+
+      // an iteration that reads nothing would go on the same way forever,
+      // so it only counts towards the minimum (which the rest of it would
+      // make up the same way), like the backtracker does with its loops
+      if n_i == best_i {
+        if loop_ct < node.repeats.min {
+          loop_ct = node.repeats.min;
+        }
+        else {
+          m.groups = groups;
+        }
         break;
       }
 
@@ -158,7 +483,7 @@ impl RegExEnv {
     return (loop_ct >= node.repeats.min, best_i);
   }
 
-  fn interpret_group(&self, node: &TreeNode, i: usize, m: &mut MatchData)
+  fn interpret_group(&self, node: &TreeNode, i: usize, m: &mut MatchPositions)
     -> (bool, usize) {
     let mut last_i = i;
 
@@ -175,34 +500,31 @@ impl RegExEnv {
     return (true, last_i);
   }
 
-  fn interpret_match_group(&self, node: &TreeNode, i: usize, m: &mut MatchData)
-    -> (bool, usize) {
+  fn interpret_match_group(&self, node: &TreeNode, i: usize,
+                           m: &mut MatchPositions) -> (bool, usize) {
     // println!("interpret_match_group @ {}", i);
     // just interpret a group like normal
     let (g_s, g_i) = self.interpret_group(node, i, m);
 
+    // if the group repeats, the last repetition wins
     if g_s {
-      m.groups.push_back(MatchGroup {
-          start: i,
-          end: g_i,
-          string: String::from(&self.string[i..g_i]),
-        });
+      m.groups[node.group] = Some((i, g_i));
     }
 
     return (g_s, g_i);
   }
 
-  fn interpret_charset(&self, node: &TreeNode, i: usize, m: &mut MatchData)
-    -> (bool, usize) {
+  fn interpret_charset(&self, node: &TreeNode, i: usize) -> (bool, usize) {
     // skip if out of bounds
-    if i >= self.chars.len() {
-      return (false, i);
-    }
+    let (u, next_i) = match self.haystack.unit_at(i, node.unicode) {
+      Some(unit) => unit,
+      None => { return (false, i); },
+    };
 
     // try to find any match in char set
     for r in &node.ranges {
-      if r.includes_char(self.chars[i]) {
-        return (true, i + 1);
+      if r.includes(u) {
+        return (true, next_i);
       }
     }
 
@@ -211,219 +533,879 @@ impl RegExEnv {
   }
 }
 
+// a compiled expression and everything needed to search with it, for either
+// kind of haystack (RegEx and bytes::RegEx only give it their types)
+pub(crate) struct RegExImpl<H: ?Sized> {
+  pub tree: TreeNode,
+  prefilter: Option<Prefilter>, // finds where a match could begin
+  required: Option<Required>,
+  program: Program,
+  onepass: Option<OnePass>, // for full matches, if the pattern is unambiguous
+  group_names: Arc<Vec<Option<String>>>,
+  semantics: Semantics,
+  limits: Limits,
+  // scratch space for the backtracker, for any thread searching
+  cache: Pool<backtrack::Cache>,
+  haystack: PhantomData<fn(&H)>,
+}
+
+impl<H: Haystack + ?Sized> RegExImpl<H> {
+  pub fn build(options: &RegExBuilder) -> Result<Self, Error> {
+    let mut parser = options.parser(H::BYTES);
+    let tree = parser.parse().map_err(|mut errors| errors.remove(0))?;
+    let mut tree = optimize(tree);
+    literal::attach(&mut tree, H::BYTES, options.semantics);
+    let prefilter = Prefilter::new(literal::prefixes(&tree, H::BYTES));
+    let required = literal::required(&tree, H::BYTES);
+    let group_names = Arc::new(parser.group_names());
+    let program = options.compile(&tree)?;
+    let onepass = OnePass::new(&program, group_names.len(), H::BYTES);
+    options.check_compiled(onepass.is_some())?;
+    let onepass = onepass.filter(|_| options.allows_onepass());
+    return Ok(RegExImpl {
+      tree,
+      prefilter,
+      required,
+      program,
+      onepass,
+      group_names,
+      semantics: options.semantics,
+      limits: options.limits,
+      cache: Pool::new(backtrack::Cache::new),
+      haystack: PhantomData,
+    });
+  }
+
+  pub fn parts<'a>(&'a self, expr: &'a str) -> Parts<'a> {
+    return Parts {
+      expr,
+      tree: &self.tree,
+      program: &self.program,
+      group_names: &self.group_names,
+      semantics: self.semantics,
+      limits: self.limits,
+      onepass: self.onepass.is_some(),
+    };
+  }
+
+  // work out again what isn't saved, as long as the program is the one the
+  // tree compiles to
+  pub fn load(loaded: Loaded) -> Result<Self, LoadError> {
+    let mut tree = loaded.tree;
+    literal::attach(&mut tree, H::BYTES, loaded.semantics);
+    let mut program = loaded.program;
+    if !program.check(&[&tree]) {
+      return Err(LoadError::Corrupt("program"));
+    }
+    let prefilter = Prefilter::new(literal::prefixes(&tree, H::BYTES));
+    let required = literal::required(&tree, H::BYTES);
+    let group_names = Arc::new(loaded.group_names);
+    let onepass = if !loaded.onepass { None }
+                  else { OnePass::new(&program, group_names.len(), H::BYTES) };
+    return Ok(RegExImpl {
+      tree,
+      prefilter,
+      required,
+      program,
+      onepass,
+      group_names,
+      semantics: loaded.semantics,
+      limits: loaded.limits,
+      cache: Pool::new(backtrack::Cache::new),
+      haystack: PhantomData,
+    });
+  }
+
+  pub fn required(&self) -> Option<&Required> {
+    return self.required.as_ref();
+  }
+
+  pub fn captures_len(&self) -> usize {
+    return self.group_names.len();
+  }
+
+  pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+    return self.group_names.iter().map(|n| n.as_deref());
+  }
+
+  // text attached to the positions found in haystack
+  fn captures<'h>(&self, haystack: &'h H, m: MatchPositions)
+    -> Captures<'h, H> {
+    return Captures {
+      haystack,
+      groups: m.groups,
+      names: Arc::clone(&self.group_names),
+    };
+  }
+
+  pub fn full_captures<'h>(&self, haystack: &'h H) -> Option<Captures<'h, H>> {
+    return self.try_full_captures(haystack)
+               .unwrap_or_else(|e| panic!("{}", e));
+  }
+
+  pub fn try_full_captures<'h>(&self, haystack: &'h H)
+    -> Result<Option<Captures<'h, H>>, LimitExceeded> {
+    let m = self.full_match_positions(haystack)?;
+    return Ok(m.map(|m| self.captures(haystack, m)));
+  }
+
+  fn full_match_positions(&self, haystack: &H)
+    -> Result<Option<MatchPositions>, LimitExceeded> {
+    // one pass is never too much work
+    if let Some(onepass) = &self.onepass {
+      return Ok(onepass.full_match(haystack));
+    }
+    if self.semantics == Semantics::LeftmostFirst {
+      let budget = Budget::new(&self.limits);
+      let m = Backtracker::new(&self.program, self.group_names.len())
+                          .full_match(haystack, &budget,
+                                      &mut self.cache.get());
+      return match budget.exceeded() {
+        Some(e) => Err(e),
+        None => Ok(m),
+      };
+    }
+
+    let env = RegExEnv::new(haystack, self.group_names.len())
+                       .limits(&self.limits);
+    let m = env.match_at(&self.tree, 0);
+    if let Some(e) = env.exceeded() {
+      return Err(e);
+    }
+    return Ok(m.filter(|m| m.end == haystack.len()));
+  }
+
+  pub fn find_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Option<Match<'h, H>> {
+    return self.captures_at(haystack, start).map(|c| c.get_match());
+  }
+
+  pub fn try_find_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Result<Option<Match<'h, H>>, LimitExceeded> {
+    let c = self.try_captures_at(haystack, start)?;
+    return Ok(c.map(|c| c.get_match()));
+  }
+
+  pub fn captures_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Option<Captures<'h, H>> {
+    return self.try_captures_at(haystack, start)
+               .unwrap_or_else(|e| panic!("{}", e));
+  }
+
+  pub fn try_captures_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Result<Option<Captures<'h, H>>, LimitExceeded> {
+    let m = match self.semantics {
+      Semantics::LeftmostLongest => {
+        let env = RegExEnv::new(haystack, self.group_names.len())
+                           .limits(&self.limits);
+        let m = env.search(&self.tree, self.prefilter.as_ref(), start);
+        if let Some(e) = env.exceeded() {
+          return Err(e);
+        }
+        m
+      },
+      Semantics::LeftmostFirst => {
+        let budget = Budget::new(&self.limits);
+        let backtracker = Backtracker::new(&self.program,
+                                           self.group_names.len());
+        let (m, _) = backtracker.search(haystack, self.prefilter.as_ref(),
+                                        start, &budget,
+                                        &mut self.cache.get());
+        if let Some(e) = budget.exceeded() {
+          return Err(e);
+        }
+        m
+      },
+    };
+    return Ok(m.map(|m| self.captures(haystack, m)));
+  }
+
+  pub fn backtrack_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Backtracked<'h, H> {
+    return self.try_backtrack_at(haystack, start)
+               .unwrap_or_else(|e| panic!("{}", e));
+  }
+
+  pub fn try_backtrack_at<'h>(&self, haystack: &'h H, start: usize)
+    -> Result<Backtracked<'h, H>, LimitExceeded> {
+    let budget = Budget::new(&self.limits);
+    let backtracker = Backtracker::new(&self.program, self.group_names.len());
+    let (m, memoized) = backtracker.search(haystack, self.prefilter.as_ref(),
+                                           start, &budget,
+                                           &mut self.cache.get());
+    if let Some(e) = budget.exceeded() {
+      return Err(e);
+    }
+    let captures = m.map(|m| self.captures(haystack, m));
+    return Ok(Backtracked { captures, memoized });
+  }
+
+  pub fn find_iter<'r, 'h>(&'r self, haystack: &'h H) -> Matches<'r, 'h, H> {
+    return Matches { captures: self.captures_iter_at(haystack, 0) };
+  }
+
+  pub fn captures_iter_at<'r, 'h>(&'r self, haystack: &'h H, start: usize)
+    -> CaptureMatches<'r, 'h, H> {
+    return CaptureMatches { re: self, haystack, start };
+  }
+
+  // the match of an iterator that's got up to start, and where the one
+  // after it is looked for
+  pub fn next_captures<'h>(&self, haystack: &'h H, start: &mut usize)
+    -> Result<Option<Captures<'h, H>>, LimitExceeded> {
+    if *start > haystack.len() {
+      return Ok(None);
+    }
+
+    let c = self.try_captures_at(haystack, *start)?;
+    match &c {
+      Some(c) => {
+        let m = c.get_match();
+        *start = haystack.after_match(m.start(), m.end());
+      },
+      // nothing else to find, stay done
+      None => { *start = haystack.len() + 1; },
+    }
+    return Ok(c);
+  }
+}
+
 pub struct RegEx {
   pub expr: String,
-  tree: TreeNode,
+  pub(crate) inner: RegExImpl<str>,
 }
 
 impl RegEx {
-  pub fn new(expr: &str) -> Self {
-    let expr = String::from(expr);
-    let mut parser = Parser::new(&expr);
-    return RegEx { expr, tree: parser.parse() };
+  /// Parse an expression, or return the first problem with it.
+  pub fn new(expr: &str) -> Result<Self, Error> {
+    return RegExBuilder::new(expr).build();
   }
 
-  pub fn match_first(&self, s: String) -> Option<MatchData> {
-    let mut m = RegExEnv::new(s);
-    let mut start = 0;
-    while start <= m.string.len() {
-      let (success, end) = m.interpret(&self.tree, start);
-      start = end;
+  /// Like `new`, but searches give up once they go over the limits, and the
+  /// expression can't nest groups deeper than `limits.max_depth`.
+  ///
+  /// A search that gives up is an error from the `try_` methods, and a panic
+  /// from the others.
+  pub fn with_limits(expr: &str, limits: Limits) -> Result<Self, Error> {
+    return RegExBuilder::new(expr).limits(limits).build();
+  }
 
-      // return as soon as a match is found
-      if success {
-        return m.matches.pop(); // if its mysteriously missing this still works
+  pub(crate) fn build(options: &RegExBuilder) -> Result<Self, Error> {
+    let inner = RegExImpl::build(options)?;
+    return Ok(RegEx { expr: options.expr.clone(), inner });
+  }
+
+  /// The compiled expression (with its options) as bytes, which
+  /// `from_bytes` loads again without parsing it. See
+  /// `serialize::FORMAT_VERSION`.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    return serialize::save(Kind::Text, |w| w.regex(&self.parts()));
+  }
+
+  /// Load an expression saved by `to_bytes`, checking the bytes are valid.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+    let mut r = serialize::Reader::new(bytes, Kind::Text)?;
+    let loaded = r.regex()?;
+    r.finish()?;
+    return Self::load(loaded);
+  }
+
+  pub(crate) fn parts(&self) -> Parts<'_> {
+    return self.inner.parts(&self.expr);
+  }
+
+  pub(crate) fn load(mut loaded: Loaded) -> Result<Self, LoadError> {
+    let expr = std::mem::take(&mut loaded.expr);
+    return Ok(RegEx { expr, inner: RegExImpl::load(loaded)? });
+  }
+
+  /// Every problem with an expression, in the order they appear. Empty if
+  /// the expression is valid.
+  pub fn check(expr: &str) -> Vec<Error> {
+    return match Parser::new(expr).parse() {
+      Ok(_) => vec![],
+      Err(errors) => errors,
+    };
+  }
+
+  /// The longest literal that every match contains, if there is one.
+  pub fn required(&self) -> Option<&Required> {
+    return self.inner.required();
+  }
+
+  /// Number of groups in the expression, including group 0.
+  pub fn captures_len(&self) -> usize {
+    return self.inner.captures_len();
+  }
+
+  /// The name of every group (if it has one) by group number.
+  pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+    return self.inner.capture_names();
+  }
+
+  /// Whether the expression matches anywhere in the haystack.
+  pub fn is_match(&self, haystack: &str) -> bool {
+    return self.find(haystack).is_some();
+  }
+
+  /// Like `is_match`, but gives an error if the search goes over the limits
+  /// given to `with_limits` rather than panicking.
+  pub fn try_is_match(&self, haystack: &str) -> Result<bool, LimitExceeded> {
+    return Ok(self.try_find(haystack)?.is_some());
+  }
+
+  /// Whether the expression matches the entire haystack.
+  pub fn is_full_match(&self, haystack: &str) -> bool {
+    return self.full_captures(haystack).is_some();
+  }
+
+  /// Like `is_full_match`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_is_full_match(&self, haystack: &str)
+    -> Result<bool, LimitExceeded> {
+    return Ok(self.try_full_captures(haystack)?.is_some());
+  }
+
+  /// The match of the entire haystack, along with its groups.
+  ///
+  /// Patterns where the next unit always decides the only way to go on
+  /// (like `(\d\d\d\d)-(\d\d)-(\d\d)`) are matched in a single scan.
+  pub fn full_captures<'h>(&self, haystack: &'h str)
+    -> Option<Captures<'h>> {
+    return self.inner.full_captures(haystack);
+  }
+
+  /// Like `full_captures`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_full_captures<'h>(&self, haystack: &'h str)
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.inner.try_full_captures(haystack);
+  }
+
+  /// The leftmost match in the haystack.
+  pub fn find<'h>(&self, haystack: &'h str) -> Option<Match<'h>> {
+    return self.find_at(haystack, 0);
+  }
+
+  /// Like `find`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_find<'h>(&self, haystack: &'h str)
+    -> Result<Option<Match<'h>>, LimitExceeded> {
+    return self.try_find_at(haystack, 0);
+  }
+
+  /// The leftmost match that begins at or after the byte offset start.
+  /// Offsets are still relative to the whole haystack, and the text before
+  /// start is still visible to the expression.
+  pub fn find_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Option<Match<'h>> {
+    return self.inner.find_at(haystack, start);
+  }
+
+  /// Like `find_at`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_find_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Result<Option<Match<'h>>, LimitExceeded> {
+    return self.inner.try_find_at(haystack, start);
+  }
+
+  /// The leftmost match in the haystack, along with its groups.
+  pub fn captures<'h>(&self, haystack: &'h str) -> Option<Captures<'h>> {
+    return self.captures_at(haystack, 0);
+  }
+
+  /// Like `captures`, but gives an error if the search goes over the limits
+  /// rather than panicking.
+  pub fn try_captures<'h>(&self, haystack: &'h str)
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.try_captures_at(haystack, 0);
+  }
+
+  /// Like `captures`, but begins searching at the byte offset start.
+  pub fn captures_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Option<Captures<'h>> {
+    return self.inner.captures_at(haystack, start);
+  }
+
+  /// Like `captures_at`, but gives an error if the search goes over the
+  /// limits given to `with_limits` rather than panicking.
+  pub fn try_captures_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.inner.try_captures_at(haystack, start);
+  }
+
+  /// Like `captures_at`, but the expression is matched by backtracking like
+  /// Perl does: a union takes its first branch that leads to a match rather
+  /// than its longest one, and repetitions give back what a match needs
+  /// (e.g.: `a*a` matches "aaa", which `captures` doesn't).
+  ///
+  /// The search takes at most O(expression * haystack) steps, unless the
+  /// haystack is too long to remember what was tried, which the result says.
+  /// It still gives up once it goes over the limits given to `with_limits`.
+  pub fn backtrack_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Backtracked<'h> {
+    return self.inner.backtrack_at(haystack, start);
+  }
+
+  /// Like `backtrack_at`, but gives an error if the search goes over the
+  /// limits rather than panicking.
+  pub fn try_backtrack_at<'h>(&self, haystack: &'h str, start: usize)
+    -> Result<Backtracked<'h>, LimitExceeded> {
+    return self.inner.try_backtrack_at(haystack, start);
+  }
+
+  /// Every successive non-overlapping match in the haystack, found lazily.
+  pub fn find_iter<'r, 'h>(&'r self, haystack: &'h str) -> Matches<'r, 'h> {
+    return self.inner.find_iter(haystack);
+  }
+
+  /// Every successive non-overlapping match in the haystack along with its
+  /// groups, found lazily.
+  pub fn captures_iter<'r, 'h>(&'r self, haystack: &'h str)
+    -> CaptureMatches<'r, 'h> {
+    return self.captures_iter_at(haystack, 0);
+  }
+
+  /// Like `captures_iter`, but begins searching at the offset start.
+  pub fn captures_iter_at<'r, 'h>(&'r self, haystack: &'h str, start: usize)
+    -> CaptureMatches<'r, 'h> {
+    return self.inner.captures_iter_at(haystack, start);
+  }
+
+  pub fn match_all<'h>(&self, s: &'h str) -> Vec<Captures<'h>> {
+    return self.captures_iter(s).collect();
+  }
+
+  /// The text between successive matches, found lazily.
+  ///
+  /// There is always at least one piece, even if there are no matches or the
+  /// haystack is empty. A match at the start or end of the haystack gives an
+  /// empty piece there, as do two matches next to each other. Since empty
+  /// matches are found between every character, an expression that only
+  /// matches empty strings splits the haystack into characters with an
+  /// empty piece at each end (e.g.: `x*` splits "ab" into "", "a", "b", "").
+  pub fn split<'r, 'h>(&'r self, haystack: &'h str) -> Split<'r, 'h> {
+    return Split {
+      captures: self.captures_iter(haystack),
+      haystack,
+      last: 0,
+      done: false,
+    };
+  }
+
+  /// Like `split`, but gives at most limit pieces. The last piece is the rest
+  /// of the haystack, delimiters included.
+  pub fn splitn<'r, 'h>(&'r self, haystack: &'h str, limit: usize)
+    -> SplitN<'r, 'h> {
+    return SplitN { split: self.split(haystack), limit };
+  }
+
+  /// Like `split`, but the delimiters are given too (with their groups),
+  /// between the text before and after them.
+  pub fn split_delimiters<'r, 'h>(&'r self, haystack: &'h str)
+    -> SplitDelimiters<'r, 'h> {
+    return SplitDelimiters { split: self.split(haystack), delimiter: None };
+  }
+
+  /// Replace the leftmost match, see `replacen`.
+  pub fn replace<'h, R: Replacer>(&self, haystack: &'h str, rep: R)
+    -> Cow<'h, str> {
+    return self.replacen(haystack, 1, rep);
+  }
+
+  /// Like `replace`, but gives an error if a search goes over the limits
+  /// rather than panicking.
+  pub fn try_replace<'h, R: Replacer>(&self, haystack: &'h str, rep: R)
+    -> Result<Cow<'h, str>, LimitExceeded> {
+    return self.try_replacen(haystack, 1, rep);
+  }
+
+  /// Replace every non-overlapping match, see `replacen`.
+  pub fn replace_all<'h, R: Replacer>(&self, haystack: &'h str, rep: R)
+    -> Cow<'h, str> {
+    return self.replacen(haystack, 0, rep);
+  }
+
+  /// Like `replace_all`, but gives an error if a search goes over the limits
+  /// rather than panicking.
+  pub fn try_replace_all<'h, R: Replacer>(&self, haystack: &'h str, rep: R)
+    -> Result<Cow<'h, str>, LimitExceeded> {
+    return self.try_replacen(haystack, 0, rep);
+  }
+
+  /// Replace the first limit non-overlapping matches (or all of them if limit
+  /// is 0). The replacement can be a template (see `replace::expand`) or a
+  /// closure that is given the captures of each match.
+  ///
+  /// If nothing is replaced the haystack is borrowed rather than copied.
+  pub fn replacen<'h, R: Replacer>(&self, haystack: &'h str, limit: usize,
+                                   rep: R) -> Cow<'h, str> {
+    return self.try_replacen(haystack, limit, rep)
+               .unwrap_or_else(|e| panic!("{}", e));
+  }
+
+  /// Like `replacen`, but gives an error if a search goes over the limits
+  /// rather than panicking.
+  pub fn try_replacen<'h, R: Replacer>(&self, haystack: &'h str,
+                                       limit: usize, mut rep: R)
+    -> Result<Cow<'h, str>, LimitExceeded> {
+    let mut it = self.captures_iter(haystack);
+    let mut caps = match it.try_next()? {
+      Some(caps) => caps,
+      None => { return Ok(Cow::Borrowed(haystack)); },
+    };
+
+    let mut new = String::with_capacity(haystack.len());
+    let mut last = 0;
+    let mut i = 0;
+    loop {
+      // copy everything between the last match and this one
+      let m = caps.get_match();
+      new.push_str(&haystack[last..m.start()]);
+      rep.replace_append(&caps, &mut new);
+      last = m.end();
+
+      i += 1;
+      if limit > 0 && i >= limit {
+        break;
       }
+      caps = match it.try_next()? {
+        Some(caps) => caps,
+        None => { break; },
+      };
     }
+    new.push_str(&haystack[last..]);
 
-    // nothing was found
-    return None;
+    return Ok(Cow::Owned(new));
   }
+}
+
+/// Iterator over the captures of successive matches, see
+/// `RegEx::captures_iter`.
+///
+/// The next search begins where the last match ended. An empty match is
+/// followed by a search from the next character, so matches never begin in
+/// the middle of a character. An empty match may directly follow a non-empty
+/// one (e.g.: `a*` finds `aa` and then `` in "aa").
+pub struct CaptureMatches<'r, 'h, H: ?Sized = str> {
+  pub(crate) re: &'r RegExImpl<H>,
+  pub(crate) haystack: &'h H,
+  pub(crate) start: usize,
+}
+
+impl<'h, H: Haystack + ?Sized> Iterator for CaptureMatches<'_, 'h, H> {
+  type Item = Captures<'h, H>;
+
+  fn next(&mut self) -> Option<Captures<'h, H>> {
+    return self.re.next_captures(self.haystack, &mut self.start)
+                  .unwrap_or_else(|e| panic!("{}", e));
+  }
+}
+
+impl<'h> CaptureMatches<'_, 'h> {
+  /// Like `next`, but gives an error if the search goes over the limits
+  /// given to `RegEx::with_limits` rather than panicking.
+  pub fn try_next(&mut self) -> Result<Option<Captures<'h>>, LimitExceeded> {
+    return self.re.next_captures(self.haystack, &mut self.start);
+  }
+}
+
+/// Iterator over successive matches, see `RegEx::find_iter`.
+pub struct Matches<'r, 'h, H: ?Sized = str> {
+  pub(crate) captures: CaptureMatches<'r, 'h, H>,
+}
+
+impl<'h, H: Haystack + ?Sized> Iterator for Matches<'_, 'h, H> {
+  type Item = Match<'h, H>;
+
+  fn next(&mut self) -> Option<Match<'h, H>> {
+    return self.captures.next().map(|c| c.get_match());
+  }
+}
+
+impl<'h> Matches<'_, 'h> {
+  /// Like `next`, but gives an error if the search goes over the limits
+  /// given to `RegEx::with_limits` rather than panicking.
+  pub fn try_next(&mut self) -> Result<Option<Match<'h>>, LimitExceeded> {
+    return Ok(self.captures.try_next()?.map(|c| c.get_match()));
+  }
+}
+
+/// Iterator over the text between matches, see `RegEx::split`.
+pub struct Split<'r, 'h> {
+  captures: CaptureMatches<'r, 'h>,
+  haystack: &'h str,
+  last: usize, // where the next piece begins
+  done: bool,
+}
+
+impl<'r, 'h> Split<'r, 'h> {
+  // the next piece and the delimiter after it (if there is one)
+  fn next_with_delimiter(&mut self) -> Option<(&'h str, Option<Captures<'h>>)> {
+    if self.done {
+      return None;
+    }
+
+    match self.captures.next() {
+      Some(c) => {
+        let m = c.get_match();
+        let piece = &self.haystack[self.last..m.start()];
+        self.last = m.end();
+        return Some((piece, Some(c)));
+      },
+      // everything after the last match is the last piece
+      None => {
+        self.done = true;
+        return Some((&self.haystack[self.last..], None));
+      },
+    }
+  }
+
+  // everything that hasn't been split yet
+  fn rest(&mut self) -> Option<&'h str> {
+    if self.done {
+      return None;
+    }
+
+    self.done = true;
+    return Some(&self.haystack[self.last..]);
+  }
+}
+
+impl<'r, 'h> Iterator for Split<'r, 'h> {
+  type Item = &'h str;
+
+  fn next(&mut self) -> Option<&'h str> {
+    return self.next_with_delimiter().map(|(piece, _)| piece);
+  }
+}
+
+/// Iterator over at most a fixed number of pieces, see `RegEx::splitn`.
+pub struct SplitN<'r, 'h> {
+  split: Split<'r, 'h>,
+  limit: usize,
+}
+
+impl<'r, 'h> Iterator for SplitN<'r, 'h> {
+  type Item = &'h str;
+
+  fn next(&mut self) -> Option<&'h str> {
+    if self.limit == 0 {
+      return None;
+    }
+
+    self.limit -= 1;
+    if self.limit == 0 {
+      return self.split.rest();
+    }
+    return self.split.next();
+  }
+}
 
-  pub fn match_all(&self, s: String) -> Vec<MatchData> {
-    let mut m = RegExEnv::new(s);
-    let mut start = 0;
-    while start <= m.string.len() {
-      let (_, end) = m.interpret(&self.tree, start);
-      start = end;
+/// Something given by `RegEx::split_delimiters`.
+#[derive(Debug)]
+pub enum SplitItem<'h> {
+  /// Text between delimiters.
+  Text(&'h str),
+  /// A delimiter (a match) with its groups.
+  Delimiter(Captures<'h>),
+}
+
+/// Iterator over the text between matches and the matches themselves, see
+/// `RegEx::split_delimiters`.
+pub struct SplitDelimiters<'r, 'h> {
+  split: Split<'r, 'h>,
+  delimiter: Option<Captures<'h>>, // given after the text before it
+}
+
+impl<'r, 'h> Iterator for SplitDelimiters<'r, 'h> {
+  type Item = SplitItem<'h>;
+
+  fn next(&mut self) -> Option<SplitItem<'h>> {
+    if let Some(c) = self.delimiter.take() {
+      return Some(SplitItem::Delimiter(c));
     }
 
-    return m.matches;
+    let (piece, delimiter) = self.split.next_with_delimiter()?;
+    self.delimiter = delimiter;
+    return Some(SplitItem::Text(piece));
   }
 }
 
 #[cfg(test)]
 mod test {
+  use crate::error::ErrorKind;
+  use std::time::Duration;
   use super::*;
 
   #[test]
   fn match_first_character() {
-    let r = RegEx::new("a");
-    let m = r.match_first(String::from("a"));
+    let r = RegEx::new("a").unwrap();
+    let m = r.find("a");
 
     assert!(m.is_some());
 
     let mu = m.unwrap();
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 1);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 1);
   }
 
   #[test]
   fn miss_first_character() {
-    let r = RegEx::new("b");
-    let m = r.match_first(String::from("a"));
+    let r = RegEx::new("b").unwrap();
+    let m = r.find("a");
     assert!(m.is_none());
   }
 
   #[test]
   fn match_all_characters() {
-    let r = RegEx::new("a");
-    let m = r.match_all(String::from("aaaaa"));
+    let r = RegEx::new("a").unwrap();
+    let m = r.match_all("aaaaa");
     assert_eq!(m.len(), 5);
 
     for i in 0..m.len() {
-      assert_eq!(m[i].start, i);
-      assert_eq!(m[i].end, i + 1);
+      assert_eq!(m[i].get_match().start(), i);
+      assert_eq!(m[i].get_match().end(), i + 1);
     }
   }
 
   #[test]
   fn miss_all_characters() {
-    let r = RegEx::new("b");
-    let m = r.match_all(String::from("aaaaa"));
+    let r = RegEx::new("b").unwrap();
+    let m = r.match_all("aaaaa");
     assert_eq!(m.len(), 0);
   }
 
   #[test]
   fn match_first_sequence() {
-    let r = RegEx::new("abc");
-    let m = r.match_first(String::from("abc"));
+    let r = RegEx::new("abc").unwrap();
+    let m = r.find("abc");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 3);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 3);
   }
 
   #[test]
   fn miss_first_sequence() {
-    let r = RegEx::new("abc");
-    let m = r.match_first(String::from("axc"));
+    let r = RegEx::new("abc").unwrap();
+    let m = r.find("axc");
     assert!(m.is_none());
   }
 
   #[test]
   fn match_all_sequences() {
-    let r = RegEx::new("abc");
-    let m = r.match_all(String::from("abcabcabc"));
+    let r = RegEx::new("abc").unwrap();
+    let m = r.match_all("abcabcabc");
 
     assert_eq!(m.len(), 3);
 
     for i in 0..m.len() {
-      assert_eq!(m[i].start, i * 3);
-      assert_eq!(m[i].end, i * 3 + 3);
+      assert_eq!(m[i].get_match().start(), i * 3);
+      assert_eq!(m[i].get_match().end(), i * 3 + 3);
     }
   }
 
   #[test]
   fn miss_all_sequences() {
-    let r = RegEx::new("abc");
-    let m = r.match_all(String::from("axcxbcabx"));
+    let r = RegEx::new("abc").unwrap();
+    let m = r.match_all("axcxbcabx");
     assert_eq!(m.len(), 0);
   }
 
   #[test]
   fn match_first_sequence_union() {
-    let r = RegEx::new("abc|xyz");
-    let m = r.match_first(String::from("ab_xyzabc"));
+    let r = RegEx::new("abc|xyz").unwrap();
+    let m = r.find("ab_xyzabc");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 3);
-    assert_eq!(mu.end, 6);
+    assert_eq!(mu.start(), 3);
+    assert_eq!(mu.end(), 6);
   }
 
   #[test]
   fn miss_first_sequence_union() {
-    let r = RegEx::new("abc|xyz");
-    let m = r.match_first(String::from("aaaaaa"));
+    let r = RegEx::new("abc|xyz").unwrap();
+    let m = r.find("aaaaaa");
     assert!(m.is_none());
   }
 
   #[test]
   fn match_all_sequence_union() {
-    let r = RegEx::new("abc|xyz");
-    let m = r.match_all(String::from("xyzabcddd"));
+    let r = RegEx::new("abc|xyz").unwrap();
+    let m = r.match_all("xyzabcddd");
 
     assert_eq!(m.len(), 2);
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 3);
-    assert_eq!(m[1].start, 3);
-    assert_eq!(m[1].end, 6);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 3);
+    assert_eq!(m[1].get_match().start(), 3);
+    assert_eq!(m[1].get_match().end(), 6);
   }
 
   #[test]
   fn miss_all_sequence_union() {
-    let r = RegEx::new("abc|xyz");
-    let m = r.match_all(String::from("defdefdef"));
+    let r = RegEx::new("abc|xyz").unwrap();
+    let m = r.match_all("defdefdef");
     assert_eq!(m.len(), 0);
   }
 
   #[test]
   fn match_first_character_kleene_exists() {
-    let r = RegEx::new("a*");
-    let m = r.match_first(String::from("aaaa"));
+    let r = RegEx::new("a*").unwrap();
+    let m = r.find("aaaa");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 4);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 4);
   }
 
   // NOTE: match_first_character_kleene_exists cannot miss
 
   #[test]
   fn match_first_character_kleene_doesnt_exist() {
-    let r = RegEx::new("a*");
-    let m = r.match_first(String::from(""));
+    let r = RegEx::new("a*").unwrap();
+    let m = r.find("");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 0);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 0);
   }
 
   // NOTE: match_first_character_kleene_doesnt_exist cannot miss
 
   #[test]
   fn match_all_character_kleene_exists() {
-    let r = RegEx::new("a*");
-    let m = r.match_all(String::from("aaaa"));
+    let r = RegEx::new("a*").unwrap();
+    let m = r.match_all("aaaa");
 
     assert_eq!(m.len(), 2);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 4);
-    assert_eq!(m[1].start, 4);
-    assert_eq!(m[1].end, 4);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 4);
+    assert_eq!(m[1].get_match().start(), 4);
+    assert_eq!(m[1].get_match().end(), 4);
   }
 
   // NOTE: match_all_character_kleene_exists cannot miss
 
   #[test]
   fn match_all_character_kleene_doesnt_exist() {
-    let r = RegEx::new("a*");
-    let m = r.match_all(String::from("bbbb"));
+    let r = RegEx::new("a*").unwrap();
+    let m = r.match_all("bbbb");
 
     assert_eq!(m.len(), 5);
 
     for i in 0..m.len() {
-      assert_eq!(m[i].start, i);
-      assert_eq!(m[i].end, i);
+      assert_eq!(m[i].get_match().start(), i);
+      assert_eq!(m[i].get_match().end(), i);
     }
   }
 
@@ -431,198 +1413,772 @@ mod test {
 
   #[test]
   fn match_first_sequence_and_kleene() {
-    let r = RegEx::new("abc*");
-    let m = r.match_first(String::from("abccc"));
+    let r = RegEx::new("abc*").unwrap();
+    let m = r.find("abccc");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 5);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 5);
   }
 
   #[test]
   fn miss_first_sequence_and_kleene() {
-    let r = RegEx::new("abc*");
-    let m = r.match_first(String::from("def"));
+    let r = RegEx::new("abc*").unwrap();
+    let m = r.find("def");
     assert!(m.is_none());
   }
 
   #[test]
   fn match_all_sequence_and_kleene() {
-    let r = RegEx::new("abc*");
-    let m = r.match_all(String::from("abcccababc"));
+    let r = RegEx::new("abc*").unwrap();
+    let m = r.match_all("abcccababc");
 
     assert_eq!(m.len(), 3);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 5);
-    assert_eq!(m[1].start, 5);
-    assert_eq!(m[1].end, 7);
-    assert_eq!(m[2].start, 7);
-    assert_eq!(m[2].end, 10);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 5);
+    assert_eq!(m[1].get_match().start(), 5);
+    assert_eq!(m[1].get_match().end(), 7);
+    assert_eq!(m[2].get_match().start(), 7);
+    assert_eq!(m[2].get_match().end(), 10);
   }
 
   #[test]
   fn miss_all_sequence_and_kleene() {
-    let r = RegEx::new("abc*");
-    let m = r.match_all(String::from("ccca"));
+    let r = RegEx::new("abc*").unwrap();
+    let m = r.match_all("ccca");
     assert_eq!(m.len(), 0);
   }
 
   #[test]
   fn match_first_kleene_within_union() {
-    let r = RegEx::new("abc|a*");
-    let m = r.match_first(String::from("aaaaabc"));
+    let r = RegEx::new("abc|a*").unwrap();
+    let m = r.find("aaaaabc");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 5);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 5);
   }
 
   // NOTE: match_first_kleene_within_union cannot miss
 
   #[test]
   fn match_all_kleene_within_union() {
-    let r = RegEx::new("abc|a*");
-    let m = r.match_all(String::from("aaabcabc"));
+    let r = RegEx::new("abc|a*").unwrap();
+    let m = r.match_all("aaabcabc");
 
     assert_eq!(m.len(), 5);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 3);
-    assert_eq!(m[1].start, 3);
-    assert_eq!(m[1].end, 3);
-    assert_eq!(m[2].start, 4);
-    assert_eq!(m[2].end, 4);
-    assert_eq!(m[3].start, 5);
-    assert_eq!(m[3].end, 8);
-    assert_eq!(m[4].start, 8);
-    assert_eq!(m[4].end, 8);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 3);
+    assert_eq!(m[1].get_match().start(), 3);
+    assert_eq!(m[1].get_match().end(), 3);
+    assert_eq!(m[2].get_match().start(), 4);
+    assert_eq!(m[2].get_match().end(), 4);
+    assert_eq!(m[3].get_match().start(), 5);
+    assert_eq!(m[3].get_match().end(), 8);
+    assert_eq!(m[4].get_match().start(), 8);
+    assert_eq!(m[4].get_match().end(), 8);
   }
 
   // NOTE: match_all_kleene_within_union cannot miss
 
   #[test]
   fn match_first_character_kleene_plus() {
-    let r = RegEx::new("a+");
-    let m = r.match_first(String::from("aa"));
+    let r = RegEx::new("a+").unwrap();
+    let m = r.find("aa");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 2);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 2);
   }
 
   #[test]
   fn miss_first_character_kleene_plus() {
-    let r = RegEx::new("a+");
-    let m = r.match_first(String::from("b"));
+    let r = RegEx::new("a+").unwrap();
+    let m = r.find("b");
     assert!(m.is_none());
   }
 
   #[test]
   fn match_all_character_kleene_plus() {
-    let r = RegEx::new("a+");
-    let m = r.match_all(String::from("abaabaaa"));
+    let r = RegEx::new("a+").unwrap();
+    let m = r.match_all("abaabaaa");
 
     assert_eq!(m.len(), 3);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 1);
-    assert_eq!(m[1].start, 2);
-    assert_eq!(m[1].end, 4);
-    assert_eq!(m[2].start, 5);
-    assert_eq!(m[2].end, 8);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 1);
+    assert_eq!(m[1].get_match().start(), 2);
+    assert_eq!(m[1].get_match().end(), 4);
+    assert_eq!(m[2].get_match().start(), 5);
+    assert_eq!(m[2].get_match().end(), 8);
   }
 
   #[test]
   fn miss_all_character_kleene_plus() {
-    let r = RegEx::new("a+");
-    let m = r.match_all(String::from("bbbb"));
+    let r = RegEx::new("a+").unwrap();
+    let m = r.match_all("bbbb");
     assert_eq!(m.len(), 0);
   }
 
   #[test]
   fn match_first_character_kleene_question() {
-    let r = RegEx::new("a?");
-    let m = r.match_first(String::from("aa"));
+    let r = RegEx::new("a?").unwrap();
+    let m = r.find("aa");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 1);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 1);
   }
 
   // NOTE: match_first_character_kleene_question cannot miss
 
   #[test]
   fn match_all_character_kleene_question() {
-    let r = RegEx::new("a?");
-    let m = r.match_all(String::from("abaa"));
+    let r = RegEx::new("a?").unwrap();
+    let m = r.match_all("abaa");
 
     assert_eq!(m.len(), 5);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 1);
-    assert_eq!(m[1].start, 1);
-    assert_eq!(m[1].end, 1);
-    assert_eq!(m[2].start, 2);
-    assert_eq!(m[2].end, 3);
-    assert_eq!(m[3].start, 3);
-    assert_eq!(m[3].end, 4);
-    assert_eq!(m[4].start, 4);
-    assert_eq!(m[4].end, 4);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 1);
+    assert_eq!(m[1].get_match().start(), 1);
+    assert_eq!(m[1].get_match().end(), 1);
+    assert_eq!(m[2].get_match().start(), 2);
+    assert_eq!(m[2].get_match().end(), 3);
+    assert_eq!(m[3].get_match().start(), 3);
+    assert_eq!(m[3].get_match().end(), 4);
+    assert_eq!(m[4].get_match().start(), 4);
+    assert_eq!(m[4].get_match().end(), 4);
   }
 
   // NOTE: match_all_character_kleene_question cannot miss
 
   #[test]
   fn match_first_kleene_of_sequence_union() {
-    let r = RegEx::new("(abc|xyz)*");
-    let m = r.match_first(String::from("abcxyzbb"));
+    let r = RegEx::new("(abc|xyz)*").unwrap();
+    let m = r.find("abcxyzbb");
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 0);
-    assert_eq!(mu.end, 6);
+    assert_eq!(mu.start(), 0);
+    assert_eq!(mu.end(), 6);
   }
 
   // NOTE: match_first_kleene_of_sequence_union cannot miss
 
   #[test]
   fn match_all_kleene_of_sequence_union() {
-    let r = RegEx::new("(abc|xyz)*");
-    let m = r.match_all(String::from("babcxyzbxyz"));
+    let r = RegEx::new("(abc|xyz)*").unwrap();
+    let m = r.match_all("babcxyzbxyz");
 
     assert_eq!(m.len(), 5);
 
-    assert_eq!(m[0].start, 0);
-    assert_eq!(m[0].end, 0);
-    assert_eq!(m[1].start, 1);
-    assert_eq!(m[1].end, 7);
-    assert_eq!(m[2].start, 7);
-    assert_eq!(m[2].end, 7);
-    assert_eq!(m[3].start, 8);
-    assert_eq!(m[3].end, 11);
-    assert_eq!(m[4].start, 11);
-    assert_eq!(m[4].end, 11);
+    assert_eq!(m[0].get_match().start(), 0);
+    assert_eq!(m[0].get_match().end(), 0);
+    assert_eq!(m[1].get_match().start(), 1);
+    assert_eq!(m[1].get_match().end(), 7);
+    assert_eq!(m[2].get_match().start(), 7);
+    assert_eq!(m[2].get_match().end(), 7);
+    assert_eq!(m[3].get_match().start(), 8);
+    assert_eq!(m[3].get_match().end(), 11);
+    assert_eq!(m[4].get_match().start(), 11);
+    assert_eq!(m[4].get_match().end(), 11);
   }
 
   #[test]
   fn match_first_escaped_sequence() {
-    let r = RegEx::new("\\(a\\|b\\)\\*");
-    let m = r.match_first(String::from("__(a|b)*__"));
+    let r = RegEx::new("\\(a\\|b\\)\\*").unwrap();
+    let m = r.find("__(a|b)*__");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.start(), 2);
+    assert_eq!(mu.end(), 8);
+  }
+
+  #[test]
+  fn match_first_several_ranges() {
+    let r = RegEx::new("[a-zA-Z_]+").unwrap();
+    let m = r.find("12 snake_Case 34");
+    assert_eq!(m.unwrap().as_str(), "snake_Case");
+  }
+
+  #[test]
+  fn match_all_word_list() {
+    let words: Vec<String> = (0..1000).map(|i| format!("w{}", i)).collect();
+    let r = RegEx::new(&format!("({})!", words.join("|"))).unwrap();
+    let m = r.match_all("w1! w12 w999! w1000!");
+    let found: Vec<_> = m.iter().map(|c| &c[1]).collect();
+    // "w100" is the longest word at "w1000", which isn't followed by '!'
+    assert_eq!(found, vec!["w1", "w999"]);
+  }
+
+  #[test]
+  fn match_first_word_list_longest() {
+    // like any other Union, the longest word wins
+    let r = RegEx::new("x(foo|foobar|ba)").unwrap();
+    assert_eq!(&r.captures("xfoobarx").unwrap()[1], "foobar");
+    assert_eq!(&r.captures("xfoobax").unwrap()[1], "foo");
+  }
+
+  #[test]
+  fn match_all_from_required_literal() {
+    let r = RegEx::new("\\w+@example\\.com").unwrap();
+    let h = "to: bob@example.com, al@example.com";
+    let req = r.required().unwrap();
+    assert_eq!(req.literal(), b"@example.com");
+    let start = req.starts(req.find(h.as_bytes(), 0).unwrap()).start;
+    let m: Vec<_> = r.captures_iter_at(h, start)
+                     .map(|c| c.get_match().as_str())
+                     .collect();
+    assert_eq!(m, vec!["bob@example.com", "al@example.com"]);
+    assert!(RegEx::new("a|b").unwrap().required().is_none());
+  }
+
+  #[test]
+  fn match_all_literal_prefix() {
+    let r = RegEx::new("ERROR: (\\w+)").unwrap();
+    let m = r.match_all("ERROR ERROR: disk\n\u{e9}ERROR: ERROR: net");
+    let found: Vec<_> = m.iter().map(|c| (c.get_match().start(), &c[1]))
+                                .collect();
+    assert_eq!(found, vec![(6, "disk"), (20, "ERROR")]);
+  }
+
+  #[test]
+  fn match_all_after_failed_partial_match() {
+    let r = RegEx::new("ab").unwrap();
+    let m = r.match_all("aab");
+
+    assert_eq!(m.len(), 1);
+    assert_eq!(m[0].get_match().start(), 1);
+    assert_eq!(m[0].get_match().end(), 3);
+  }
+
+  #[test]
+  fn match_first_after_unicode() {
+    let r = RegEx::new("b+").unwrap();
+    let m = r.find("\u{e9}\u{2603}bb");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.byte_range(), 5..7);
+    assert_eq!(mu.char_range(), 2..4);
+    assert_eq!(mu.as_str(), "bb");
+  }
+
+  #[test]
+  fn match_all_unicode_groups() {
+    let r = RegEx::new("(\\u2603)x").unwrap();
+    let m = r.match_all("\u{e9}\u{2603}x\u{2603}x");
+
+    assert_eq!(m.len(), 2);
+
+    assert_eq!(m[0].get(1).unwrap().byte_range(), 2..5);
+    assert_eq!(m[0].get(1).unwrap().char_range(), 1..2);
+    assert_eq!(&m[0][1], "\u{2603}");
+    assert_eq!(m[1].get_match().byte_range(), 6..10);
+    assert_eq!(m[1].get_match().char_range(), 3..5);
+  }
+
+  #[test]
+  fn match_all_empty_between_unicode() {
+    let r = RegEx::new("a*").unwrap();
+    let m = r.match_all("\u{e9}\u{e9}");
+
+    // empty matches never split a character
+    assert_eq!(m.len(), 3);
+    assert_eq!(m[0].get_match().byte_range(), 0..0);
+    assert_eq!(m[1].get_match().byte_range(), 2..2);
+    assert_eq!(m[2].get_match().byte_range(), 4..4);
+  }
+
+  #[test]
+  fn match_first_nested_groups() {
+    let r = RegEx::new("((a)b)(c)").unwrap();
+    let m = r.captures("_abc");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.len(), 4);
+    assert_eq!(&mu[0], "abc");
+    assert_eq!(&mu[1], "ab");
+    assert_eq!(&mu[2], "a");
+    assert_eq!(&mu[3], "c");
+    assert_eq!(mu.get(2).unwrap().byte_range(), 1..2);
+  }
+
+  #[test]
+  fn match_first_repeated_group_keeps_last() {
+    let r = RegEx::new("(a|b)*").unwrap();
+    let m = r.captures("abb");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.len(), 2);
+    assert_eq!(mu.get(1).unwrap().byte_range(), 2..3);
+  }
+
+  #[test]
+  fn match_first_group_in_other_branch() {
+    let r = RegEx::new("(a)x|(a)").unwrap();
+    let m = r.captures("ay");
+    assert!(m.is_some());
+
+    // the first branch failed, so its group didn't participate
+    let mu = m.unwrap();
+
+    assert!(mu.get(1).is_none());
+    assert_eq!(&mu[2], "a");
+  }
+
+  #[test]
+  fn match_as_str_borrows_haystack() {
+    let haystack = String::from("xx\u{e9}\u{e9}yy");
+    let r = RegEx::new("\\u00e9+").unwrap();
+    let m = r.find(&haystack).unwrap();
+
+    assert_eq!(m.as_str(), "\u{e9}\u{e9}");
+    assert_eq!(m.len(), 4);
+    assert!(std::ptr::eq(m.as_str().as_ptr(), haystack[2..].as_ptr()));
+  }
+
+  #[test]
+  fn is_match_anywhere() {
+    let r = RegEx::new("b+").unwrap();
+    assert!(r.is_match("aabba"));
+    assert!(!r.is_match("aaaa"));
+  }
+
+  #[test]
+  fn is_full_match_anchored() {
+    let r = RegEx::new("a(b|c)+").unwrap();
+    assert!(r.is_full_match("abcb"));
+    assert!(!r.is_full_match("abcbx"));
+    assert!(!r.is_full_match("xabcb"));
+    assert!(r.is_match("xabcbx"));
+  }
+
+  #[test]
+  fn is_full_match_empty() {
+    let r = RegEx::new("a*").unwrap();
+    assert!(r.is_full_match(""));
+    assert!(!r.is_full_match("b"));
+  }
+
+  #[test]
+  fn full_captures_one_pass() {
+    let r = RegEx::new("(\\d\\d\\d\\d)-(\\d\\d)-(\\d\\d)").unwrap();
+    assert!(r.inner.onepass.is_some());
+    let c = r.full_captures("2024-01-31").unwrap();
+    assert_eq!((&c[1], &c[2], &c[3]), ("2024", "01", "31"));
+    assert!(r.full_captures("2024-01-31 ").is_none());
+    assert!(r.full_captures("2024-1-31").is_none());
+  }
+
+  #[test]
+  fn backtrack_gives_back() {
+    let r = RegEx::new("(a*)(a)").unwrap();
+    assert!(r.captures("aaa").is_none());
+    let b = r.backtrack_at("xaaa", 0);
+    let c = b.captures.unwrap();
+    assert_eq!((&c[0], &c[1], &c[2]), ("aaa", "aa", "a"));
+    assert!(b.memoized);
+
+    let r = RegEx::new("(foo|foobar)").unwrap();
+    assert_eq!(&r.captures("foobar").unwrap()[1], "foobar");
+    assert_eq!(&r.backtrack_at("foobar", 0).captures.unwrap()[1], "foo");
+  }
+
+  #[test]
+  fn backtrack_ambiguous_in_time() {
+    let r = RegEx::new("(a|a)*b").unwrap();
+    let haystack = "a".repeat(10000);
+    let b = r.backtrack_at(&haystack, 0);
+    assert!(b.captures.is_none());
+    assert!(b.memoized);
+  }
+
+  #[test]
+  fn backtrack_limits() {
+    let limits = Limits { max_steps: Some(100), ..Limits::default() };
+    let r = RegEx::with_limits("(a|a)*b", limits).unwrap();
+    assert_eq!(r.try_backtrack_at(&"a".repeat(100), 0).err(),
+               Some(LimitExceeded::Steps(100)));
+    let b = r.try_backtrack_at("aab", 0).unwrap();
+    assert_eq!(&b.captures.unwrap()[0], "aab");
+  }
+
+  #[test]
+  fn limits_steps() {
+    // `a*` goes to the end of the a's from every place the search starts
+    let limits = Limits { max_steps: Some(1000), ..Limits::default() };
+    let r = RegEx::with_limits("x|a*c", limits).unwrap();
+    let a = "a".repeat(100);
+    assert_eq!(&r.try_captures_at("zzx", 0).unwrap().unwrap()[0], "x");
+    assert_eq!(r.try_captures_at(&a, 0).err(),
+               Some(LimitExceeded::Steps(1000)));
+
+    let haystack = format!("x x {} x", a);
+    let mut matches = r.captures_iter(&haystack);
+    assert!(matches.try_next().unwrap().is_some());
+    assert!(matches.try_next().unwrap().is_some());
+    assert!(matches.try_next().is_err());
+  }
+
+  #[test]
+  fn limits_try_methods() {
+    let limits = Limits { max_steps: Some(1000), ..Limits::default() };
+    let exceeded = Err(LimitExceeded::Steps(1000));
+    let r = RegEx::with_limits("x|a*c", limits).unwrap();
+    let a = "a".repeat(100);
+    let haystack = format!("x x {} x", a);
+    assert_eq!(r.try_is_match("zzx"), Ok(true));
+    assert_eq!(r.try_is_match(&a), exceeded);
+    assert_eq!(r.try_find_at("x x", 1).unwrap().unwrap().start(), 2);
+    assert!(r.try_find(&a).is_err());
+    assert!(r.try_captures(&a).is_err());
+    // a full match is only tried once (and not in one pass)
+    let full = RegEx::with_limits("(a|a)*", limits).unwrap();
+    let long = "a".repeat(2000);
+    assert_eq!(full.try_is_full_match(&a), Ok(true));
+    assert_eq!(&full.try_full_captures(&a).unwrap().unwrap()[1], "a");
+    assert!(full.try_full_captures(&long).is_err());
+    assert_eq!(full.try_is_full_match(&long), exceeded);
+
+    assert_eq!(r.try_replace("zxz", "y").unwrap(), "zyz");
+    assert_eq!(r.try_replace(&haystack, "y").unwrap(),
+               format!("y x {} x", a));
+    assert!(r.try_replace_all(&haystack, "y").is_err());
+    assert_eq!(r.try_replacen(&haystack, 2, "y").unwrap(),
+               format!("y y {} x", a));
+    assert!(matches!(r.try_replace_all("zz", "y"), Ok(Cow::Borrowed(_))));
+
+    let mut matches = r.find_iter(&haystack);
+    assert_eq!(matches.try_next().unwrap().unwrap().start(), 0);
+    assert_eq!(matches.try_next().unwrap().unwrap().start(), 2);
+    assert!(matches.try_next().is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "search gave up after 10 steps")]
+  fn limits_steps_panic() {
+    let limits = Limits { max_steps: Some(10), ..Limits::default() };
+    RegEx::with_limits("a*c", limits).unwrap().is_match(&"a".repeat(100));
+  }
+
+  #[test]
+  fn limits_timeout() {
+    let timeout = Duration::from_millis(10);
+    let limits = Limits { timeout: Some(timeout), ..Limits::default() };
+    let r = RegEx::with_limits("a*c", limits).unwrap();
+    assert_eq!(r.try_captures_at(&"a".repeat(100_000), 0).err(),
+               Some(LimitExceeded::Timeout(timeout)));
+  }
+
+  #[test]
+  fn limits_empty_repetitions_end() {
+    // a repetition that matches nothing is no work
+    let limits = Limits { max_steps: Some(100), ..Limits::default() };
+    let r = RegEx::with_limits("(a*)*", limits).unwrap();
+    assert!(r.is_full_match("aa"));
+    let c = RegEx::with_limits("(a*)*b", limits).unwrap()
+                  .captures("xaab").unwrap();
+    assert_eq!((&c[0], &c[1]), ("aab", "aa"));
+    // but still makes up the minimum
+    let c = RegEx::new("(a?)+b").unwrap().captures("b").unwrap();
+    assert_eq!(&c[1], "");
+  }
+
+  #[test]
+  fn limits_depth() {
+    let limits = Limits { max_depth: 2, ..Limits::default() };
+    assert!(RegEx::with_limits("((a)(b))", limits).is_ok());
+    let e = RegEx::with_limits("((a)((b)))", limits).err().unwrap();
+    assert_eq!((e.kind, e.span.start), (ErrorKind::NestedTooDeep(2), 5));
+  }
+
+  #[test]
+  fn limits_long_expressions() {
+    // only groups open inside each other use up the stack, not the number
+    // of atoms (a spawned thread has the default 2 MiB)
+    let thread = std::thread::Builder::new().stack_size(2 * 1024 * 1024);
+    let found = thread.spawn(|| {
+      let flat = RegEx::new(&"a.".repeat(50_000)).unwrap();
+      let groups = RegEx::new(&"(a)".repeat(100_000)).unwrap();
+      let haystack = "a".repeat(100_000);
+      return (flat.is_match(&haystack), groups.captures(&haystack).is_some(),
+              groups.captures_len());
+    }).unwrap().join().unwrap();
+    assert_eq!(found, (true, true, 100_001));
+  }
+
+  #[test]
+  fn full_captures_ambiguous() {
+    let r = RegEx::new("(\\w+)(\\d)").unwrap();
+    assert!(r.inner.onepass.is_none());
+    assert!(r.full_captures("ab1").is_none());
+    let r = RegEx::new("(a|b)*(c)?").unwrap();
+    let c = r.full_captures("abac").unwrap();
+    assert_eq!((&c[1], &c[2]), ("a", "c"));
+  }
+
+  #[test]
+  fn find_at_offsets_from_haystack_start() {
+    let r = RegEx::new("ab").unwrap();
+    let m = r.find_at("ab_ab_ab", 1);
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.start(), 3);
+    assert_eq!(mu.end(), 5);
+    assert_eq!(mu.as_str(), "ab");
+  }
+
+  #[test]
+  fn anchors_every_engine() {
+    let text = "ab\nab";
+    for semantics in [Semantics::LeftmostLongest, Semantics::LeftmostFirst] {
+      let starts = |expr: &str| {
+        let re = RegExBuilder::new(expr).semantics(semantics)
+                                        .build().unwrap();
+        return re.find_iter(text).map(|m| m.start()).collect::<Vec<_>>();
+      };
+      assert_eq!(starts("^a"), vec![0]);
+      assert_eq!(starts("b$"), vec![4]);
+      assert_eq!(starts("(?m)^a"), vec![0, 3]);
+      assert_eq!(starts("(?m)b$"), vec![1, 4]);
+      assert_eq!(starts("(x|^)a"), vec![0]);
+      assert_eq!(starts("^*a"), vec![0, 3]);
+
+      let re = RegExBuilder::new("^(a)b$").semantics(semantics)
+                                          .build().unwrap();
+      assert!(re.inner.onepass.is_none());
+      assert_eq!(&re.full_captures("ab").unwrap()[1], "a");
+      assert!(!re.is_full_match("ab\n"));
+      // an offset doesn't move the start of the haystack
+      let re = RegExBuilder::new("^b").semantics(semantics).build().unwrap();
+      assert!(re.find_at("ab", 1).is_none());
+      assert!(re.find_at("b", 0).is_some());
+    }
+  }
+
+  #[test]
+  fn find_at_past_every_match() {
+    let r = RegEx::new("ab").unwrap();
+    assert!(r.find_at("ab_ab", 4).is_none());
+    assert!(r.find_at("ab_ab", 5).is_none());
+  }
+
+  #[test]
+  fn captures_at_after_unicode() {
+    let r = RegEx::new("(\\w)(\\w)").unwrap();
+    let m = r.captures_at("\u{e9}ab\u{e9}cd", 3);
     assert!(m.is_some());
 
     let mu = m.unwrap();
 
-    assert_eq!(mu.start, 2);
-    assert_eq!(mu.end, 8);
+    assert_eq!(&mu[0], "cd");
+    assert_eq!(mu.get_match().start(), 6);
+    assert_eq!(&mu[1], "c");
+    assert_eq!(&mu[2], "d");
+  }
+
+  #[test]
+  fn find_iter_is_lazy() {
+    let r = RegEx::new("a").unwrap();
+    let mut it = r.find_iter("a_a_a");
+
+    assert_eq!(it.next().unwrap().start(), 0);
+    assert_eq!(it.next().unwrap().start(), 2);
+    assert_eq!(it.next().unwrap().start(), 4);
+    assert!(it.next().is_none());
+    assert!(it.next().is_none());
+  }
+
+  #[test]
+  fn find_iter_stops_early() {
+    let r = RegEx::new("\\d+").unwrap();
+    let m: Vec<&str> = r.find_iter("1 22 333 4444").take(2)
+                        .map(|m| m.as_str())
+                        .collect();
+    assert_eq!(m, vec!["1", "22"]);
+  }
+
+  #[test]
+  fn find_iter_empty_matches_between_characters() {
+    let r = RegEx::new("x*").unwrap();
+    let m: Vec<(usize, usize)> = r.find_iter("\u{2603}x\u{e9}")
+                                  .map(|m| (m.start(), m.end()))
+                                  .collect();
+
+    // never lands inside the 3 byte snowman or the 2 byte e
+    assert_eq!(m, vec![(0, 0), (3, 4), (4, 4), (6, 6)]);
+  }
+
+  #[test]
+  fn captures_iter_groups() {
+    let r = RegEx::new("(\\w)=(\\d)").unwrap();
+    let m: Vec<(String, String)> = r.captures_iter("a=1, b=2,c=x")
+                                    .map(|c| (c[1].to_owned(), c[2].to_owned()))
+                                    .collect();
+    assert_eq!(m, vec![(String::from("a"), String::from("1")),
+                       (String::from("b"), String::from("2"))]);
+  }
+
+  #[test]
+  fn captures_named_groups() {
+    let r = RegEx::new("(?<y>\\d\\d)-(?P<m>\\d)(x)").unwrap();
+    let m = r.captures("on 24-3x");
+    assert!(m.is_some());
+
+    let mu = m.unwrap();
+
+    assert_eq!(mu.name("y").unwrap().as_str(), "24");
+    assert_eq!(mu.name("m").unwrap().as_str(), "3");
+    assert!(mu.name("x").is_none());
+    assert_eq!(&mu[3], "x");
+
+    let names: Vec<Option<&str>> = r.capture_names().collect();
+    assert_eq!(names, vec![None, Some("y"), Some("m"), None]);
+  }
+
+  #[test]
+  fn split_on_delimiters() {
+    let r = RegEx::new(", *").unwrap();
+    let m: Vec<&str> = r.split("a, b,c,,  d").collect();
+    assert_eq!(m, vec!["a", "b", "c", "", "d"]);
+  }
+
+  #[test]
+  fn split_edges_and_no_match() {
+    let r = RegEx::new("-").unwrap();
+    let m: Vec<&str> = r.split("-a-").collect();
+    assert_eq!(m, vec!["", "a", ""]);
+
+    let m: Vec<&str> = r.split("abc").collect();
+    assert_eq!(m, vec!["abc"]);
+
+    let m: Vec<&str> = r.split("").collect();
+    assert_eq!(m, vec![""]);
+  }
+
+  #[test]
+  fn split_empty_matches() {
+    let r = RegEx::new("x*").unwrap();
+    let m: Vec<&str> = r.split("a\u{e9}").collect();
+    assert_eq!(m, vec!["", "a", "\u{e9}", ""]);
+
+    // an empty match may directly follow a non-empty one
+    let m: Vec<&str> = r.split("axb").collect();
+    assert_eq!(m, vec!["", "a", "", "b", ""]);
+  }
+
+  #[test]
+  fn splitn_limits() {
+    let r = RegEx::new(" ").unwrap();
+    let m: Vec<&str> = r.splitn("a b c d", 2).collect();
+    assert_eq!(m, vec!["a", "b c d"]);
+
+    let m: Vec<&str> = r.splitn("a b", 5).collect();
+    assert_eq!(m, vec!["a", "b"]);
+
+    let m: Vec<&str> = r.splitn("a b", 1).collect();
+    assert_eq!(m, vec!["a b"]);
+
+    assert_eq!(r.splitn("a b", 0).count(), 0);
+  }
+
+  #[test]
+  fn split_delimiters_with_groups() {
+    let r = RegEx::new("(\\d)(x)?").unwrap();
+    let items: Vec<String> = r.split_delimiters("a1b2xc")
+      .map(|i| match i {
+        SplitItem::Text(t) => format!("T:{}", t),
+        SplitItem::Delimiter(c) => {
+          format!("D:{}/{}", &c[1], c.get(2).map_or("-", |m| m.as_str()))
+        },
+      })
+      .collect();
+    assert_eq!(items, vec!["T:a", "D:1/-", "T:b", "D:2/x", "T:c"]);
+  }
+
+  #[test]
+  fn split_delimiters_at_end() {
+    let r = RegEx::new(";").unwrap();
+    let items: Vec<bool> = r.split_delimiters("a;")
+      .map(|i| matches!(i, SplitItem::Delimiter(_)))
+      .collect();
+    // text, delimiter, then the empty text after it
+    assert_eq!(items, vec![false, true, false]);
+  }
+
+  #[test]
+  fn to_bytes_round_trip() {
+    let re = RegExBuilder::new("(?<year>\\d+)-(a|ab)(c|bcd)|hello")
+                          .case_insensitive(true)
+                          .semantics(Semantics::LeftmostFirst)
+                          .step_limit(1000)
+                          .build().unwrap();
+    let loaded = RegEx::from_bytes(&re.to_bytes()).unwrap();
+    assert_eq!(loaded.expr, re.expr);
+    assert_eq!(loaded.capture_names().collect::<Vec<_>>(),
+               vec![None, Some("year"), None, None]);
+    let c = loaded.captures("x 2024-abcd HELLO").unwrap();
+    assert_eq!((&c[0], &c[2], &c[3]), ("2024-abcd", "a", "bcd"));
+    assert_eq!(loaded.find_iter("x 2024-abcd HELLO")
+                     .map(|m| m.as_str())
+                     .collect::<Vec<_>>(), vec!["2024-abcd", "HELLO"]);
+    assert_eq!(loaded.inner.limits, re.inner.limits);
+    assert_eq!(loaded.to_bytes(), re.to_bytes());
+
+    // unions of literals get their automata back
+    let re = RegExBuilder::new("x(one|two|three)")
+                          .semantics(Semantics::LeftmostFirst)
+                          .build().unwrap();
+    let loaded = RegEx::from_bytes(&re.to_bytes()).unwrap();
+    assert_eq!(loaded.inner.program, re.inner.program);
+    assert_eq!(loaded.inner.program.literals.len(), 1);
+
+    let re = RegExBuilder::new("^a|(?m)b$").build().unwrap();
+    let loaded = RegEx::from_bytes(&re.to_bytes()).unwrap();
+    assert_eq!(loaded.find_iter("ba\nab\nb").map(|m| m.start())
+                     .collect::<Vec<_>>(), vec![4, 6]);
+  }
+
+  #[test]
+  fn shared_between_threads() {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<RegEx>();
+    send_sync::<crate::bytes::RegEx>();
+    send_sync::<crate::set::RegExSet>();
+
+    let re = RegExBuilder::new("(a|ab)(c|bcd)")
+                          .semantics(Semantics::LeftmostFirst)
+                          .build().unwrap();
+    std::thread::scope(|s| {
+      for _ in 0..4 {
+        s.spawn(|| {
+          for _ in 0..100 {
+            assert_eq!(&re.captures("xabcd").unwrap()[2], "bcd");
+          }
+        });
+      }
+    });
   }
 }
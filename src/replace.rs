@@ -0,0 +1,190 @@
+use crate::regex::Captures;
+
+/// Something that can produce the replacement text for a match.
+///
+/// Implemented for template strings (see `expand`) and for closures of the
+/// form `FnMut(&Captures) -> String`.
+pub trait Replacer {
+  /// Append the replacement for caps to dst.
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String);
+}
+
+impl Replacer for &str {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    expand(caps, self, dst);
+  }
+}
+
+impl Replacer for String {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    expand(caps, self, dst);
+  }
+}
+
+impl Replacer for &String {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    expand(caps, self, dst);
+  }
+}
+
+impl<F, T> Replacer for F
+  where F: FnMut(&Captures<'_>) -> T,
+        T: AsRef<str> {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    dst.push_str((*self)(caps).as_ref());
+  }
+}
+
+/// A replacement that is used as-is, without expanding `$` references.
+pub struct NoExpand<'s>(pub &'s str);
+
+impl<'s> Replacer for NoExpand<'s> {
+  fn replace_append(&mut self, _caps: &Captures<'_>, dst: &mut String) {
+    dst.push_str(self.0);
+  }
+}
+
+/// Expand a replacement template for caps and append the result to dst.
+///
+/// - `$n` and `${n}` are replaced by group n
+/// - `${name}` is replaced by the group with that name
+/// - `$$` is a literal `$`
+///
+/// Groups that don't exist or didn't participate are replaced by nothing.
+/// A `$` that doesn't begin a reference is kept as-is.
+pub fn expand(caps: &Captures<'_>, template: &str, dst: &mut String) {
+  let mut rest = template;
+  while let Some(i) = rest.find('$') {
+    dst.push_str(&rest[..i]);
+    rest = &rest[i..];
+
+    match parse_reference(rest) {
+      Some((reference, len)) => {
+        let m = match reference {
+          Reference::Dollar => { dst.push('$'); None },
+          Reference::Number(n) => caps.get(n),
+          Reference::Name(name) => caps.name(name),
+        };
+        if let Some(m) = m {
+          dst.push_str(m.as_str());
+        }
+        rest = &rest[len..];
+      },
+      // not a reference, keep the '$'
+      None => {
+        dst.push('$');
+        rest = &rest[1..];
+      },
+    }
+  }
+  dst.push_str(rest);
+}
+
+enum Reference<'t> {
+  Dollar,
+  Number(usize),
+  Name(&'t str),
+}
+
+// parse the reference at the start of s (which begins with a '$')
+// returns the reference and its length
+fn parse_reference(s: &str) -> Option<(Reference<'_>, usize)> {
+  let after = &s[1..];
+
+  // $$
+  if after.starts_with('$') {
+    return Some((Reference::Dollar, 2));
+  }
+
+  // ${n} or ${name}
+  if let Some(braced) = after.strip_prefix('{') {
+    let close = braced.find('}')?;
+    let inner = &braced[..close];
+    if inner.is_empty() ||
+       !inner.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+      return None;
+    }
+    let reference = match inner.parse::<usize>() {
+      Ok(n) => Reference::Number(n),
+      Err(_) => Reference::Name(inner),
+    };
+    return Some((reference, close + 3));
+  }
+
+  // $n
+  let digits = after.chars().take_while(|c| c.is_ascii_digit()).count();
+  if digits == 0 {
+    return None;
+  }
+  let n = after[..digits].parse::<usize>().ok()?;
+  return Some((Reference::Number(n), digits + 1));
+}
+
+#[cfg(test)]
+mod test {
+  use crate::regex::RegEx;
+  use super::*;
+
+  #[test]
+  fn replace_first_only() {
+    let r = RegEx::new("a+").unwrap();
+    assert_eq!(r.replace("baaba", "x"), "bxba");
+  }
+
+  #[test]
+  fn replace_all_numbered_groups() {
+    let r = RegEx::new("(\\w+)=(\\w+)").unwrap();
+    assert_eq!(r.replace_all("a=1 b=2", "$2=$1"), "1=a 2=b");
+  }
+
+  #[test]
+  fn replace_all_braced_and_named_groups() {
+    let r = RegEx::new("(?<key>\\w+)=(\\w+)").unwrap();
+    assert_eq!(r.replace_all("a=1 b=2", "${2}0 ${key}$$"), "10 a$ 20 b$");
+  }
+
+  #[test]
+  fn replace_all_missing_groups_are_empty() {
+    let r = RegEx::new("(a)|(b)").unwrap();
+    assert_eq!(r.replace_all("ab", "[$1$2$3${nope}]"), "[a][b]");
+  }
+
+  #[test]
+  fn replace_all_literal_dollars() {
+    let r = RegEx::new("x").unwrap();
+    assert_eq!(r.replace_all("xx", "$ ${} $a ${1"), "$ ${} $a ${1$ ${} $a ${1");
+    assert_eq!(r.replace_all("x", NoExpand("$1")), "$1");
+  }
+
+  #[test]
+  fn replacen_limit() {
+    let r = RegEx::new("\\d").unwrap();
+    assert_eq!(r.replacen("1234", 2, "_"), "__34");
+    assert_eq!(r.replacen("1234", 0, "_"), "____");
+  }
+
+  #[test]
+  fn replace_all_with_closure() {
+    let r = RegEx::new("\\d+").unwrap();
+    let mut total = 0;
+    let s = r.replace_all("1 22 333", |c: &Captures| {
+      total += 1;
+      return (c[0].len() * 2).to_string();
+    });
+    assert_eq!(s, "2 4 6");
+    assert_eq!(total, 3);
+  }
+
+  #[test]
+  fn replace_all_empty_matches() {
+    let r = RegEx::new("x*").unwrap();
+    assert_eq!(r.replace_all("a\u{e9}", "-"), "-a-\u{e9}-");
+  }
+
+  #[test]
+  fn replace_without_match_borrows() {
+    let r = RegEx::new("z").unwrap();
+    let s = r.replace_all("abc", "y");
+    assert!(matches!(s, std::borrow::Cow::Borrowed("abc")));
+  }
+}
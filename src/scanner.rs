@@ -1,4 +1,11 @@
-#[derive(Debug)]
+use crate::ast::CharRange;
+use crate::ast::MAX_UNIT;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Span;
+
+#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TokenType {
   Error,
   Character,
@@ -9,80 +16,91 @@ pub enum TokenType {
   LBracket,
   RBracket,
   Caret,
+  Anchor,
   Question,
   Plus,
   Range,
   EOF,
 }
 
-#[derive(Clone)]
-pub struct CharRange {
-  pub min: u32,
-  pub max: u32,
-  pub negate: bool,
-}
-
-impl CharRange {
-  pub fn new(min: u32, max: u32, negate: bool) -> Self {
-    return CharRange { min, max, negate };
-  }
-
-  pub fn includes_char(&self, c: char) -> bool {
-    let u = c as u32;
-
-    if !self.negate { u >= self.min && u <= self.max }
-    else { u < self.min || u > self.max }
-  }
-}
-
-struct PerlCC {
+pub struct PerlCC {
   // Empty
 }
 
 impl PerlCC {
   // NOTE: these character classes are for ASCII ranges only
-  const DIGIT: &[CharRange] = &[
+  pub const DIGIT: &[CharRange] = &[
     CharRange { min: '0' as u32, max: '9' as u32, negate: false },
   ];
 
-  const NOT_DIGIT: &[CharRange] = &[
+  pub const NOT_DIGIT: &[CharRange] = &[
     CharRange { min: '0' as u32, max: '9' as u32, negate: true },
   ];
 
-  const WORD: &[CharRange] = &[
+  pub const WORD: &[CharRange] = &[
     CharRange { min: 'a' as u32, max: 'z' as u32, negate: false },
     CharRange { min: 'A' as u32, max: 'Z' as u32, negate: false },
     CharRange { min: '0' as u32, max: '9' as u32, negate: false },
     CharRange { min: '_' as u32, max: '_' as u32, negate: false },
   ];
 
-  const NOT_WORD: &[CharRange] = &[
+  pub const NOT_WORD: &[CharRange] = &[
     CharRange { min: 'a' as u32, max: 'z' as u32, negate: true },
     CharRange { min: 'A' as u32, max: 'Z' as u32, negate: true },
     CharRange { min: '0' as u32, max: '9' as u32, negate: true },
     CharRange { min: '_' as u32, max: '_' as u32, negate: true },
   ];
 
-  const WHITESPACE: &[CharRange] = &[
+  pub const WHITESPACE: &[CharRange] = &[
     // NOTE: this includes \f, which some versions of Perl do not
     CharRange { min: 0x000A, max: 0x000D, negate: false },
   ];
 
-  const NOT_WHITESPACE: &[CharRange] = &[
+  pub const NOT_WHITESPACE: &[CharRange] = &[
     CharRange { min: 0x000A, max: 0x000D, negate: true },
   ];
 
-  const NOT_NEWLINE: &[CharRange] = &[
+  pub const NOT_NEWLINE: &[CharRange] = &[
     CharRange { min: '\n' as u32, max: '\n' as u32, negate: true },
   ];
 
   // TODO: \h, \H, \v, \V
 }
 
+// the flags an expression starts with, each can be changed inline
+// (e.g.: `(?i)` or `(?-u)`) from there to the end of the expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flags {
+  pub case_insensitive: bool, // i
+  pub dot_all: bool,          // s: '.' matches '\n' too
+  pub extended: bool,         // x: whitespace and `#` comments are ignored
+  pub multi_line: bool,       // m: '^' and '$' match at every line
+  pub unicode: bool,          // u
+}
+
+impl Default for Flags {
+  fn default() -> Self {
+    return Flags {
+      case_insensitive: false,
+      dot_all: false,
+      extended: false,
+      multi_line: false,
+      unicode: true,
+    };
+  }
+}
+
 pub struct Token {
   pub t_type: TokenType,
   pub image: char,
   pub range: Vec<CharRange>,
+  // false if the token matches single bytes rather than unicode characters
+  pub unicode: bool,
+  // true if the token matches its characters in any case
+  pub caseless: bool,
+  pub name: Option<String>, // used by LParens of named groups
+  pub multi_line: bool,     // used by Anchors: true if they match at lines
+  pub span: Span,
 }
 
 impl Token {
@@ -91,6 +109,11 @@ impl Token {
       t_type,
       image,
       range: vec![CharRange::new(0x0000, 0x0000, true)],
+      unicode: true,
+      caseless: false,
+      name: None,
+      multi_line: false,
+      span: Span::default(),
     };
   }
 }
@@ -98,6 +121,14 @@ impl Token {
 pub struct Scanner {
   chars: Vec<char>,
   index: usize,
+  unicode: bool, // toggled by (?u) and (?-u)
+  caseless: bool,
+  dot_all: bool,
+  extended: bool,
+  multi_line: bool,
+  in_class: bool, // between '[' and ']', where extended doesn't apply
+  bytes: bool,   // scanning an expression for bytes::RegEx
+  errors: Vec<Error>,
 }
 
 enum EscapeType {
@@ -105,37 +136,206 @@ enum EscapeType {
   UnicodeHex,
   AsciiDec,
   AsciiHex,
+  ByteHex,
 }
 
 fn char_to_hex(h: char) -> u32 {
   match h {
     '0' => 0x0, '1' => 0x1, '2' => 0x2, '3' => 0x3, '4' => 0x4, '5' => 0x5,
     '6' => 0x6, '7' => 0x7, '8' => 0x8, '9' => 0x9, 'a' => 0xa, 'b' => 0xb,
-    'c' => 0xc, 'd' => 0xd, 'e' => 0xe, 'f' => 0xf, _ => 0x0,
+    'c' => 0xc, 'd' => 0xd, 'e' => 0xe, 'f' => 0xf, 'A' => 0xa, 'B' => 0xb,
+    'C' => 0xc, 'D' => 0xd, 'E' => 0xe, 'F' => 0xf, _ => 0x0,
   }
 }
 
 impl Scanner {
-  pub fn new(input: &String) -> Self {
+  pub fn new(input: &str) -> Self {
     let chars = input.chars().collect();
     return Scanner {
       chars,
       index: 0usize,
+      unicode: true,
+      caseless: false,
+      dot_all: false,
+      extended: false,
+      multi_line: false,
+      in_class: false,
+      bytes: false,
+      errors: vec![],
     };
   }
 
+  // in bytes mode `\xNN` is always a single byte, up to `\xFF`
+  pub fn new_bytes(input: &str) -> Self {
+    let mut scanner = Scanner::new(input);
+    scanner.bytes = true;
+    return scanner;
+  }
+
+  // the flags to start with, instead of the defaults
+  pub fn flags(mut self, flags: &Flags) -> Self {
+    self.unicode = flags.unicode;
+    self.caseless = flags.case_insensitive;
+    self.dot_all = flags.dot_all;
+    self.extended = flags.extended;
+    self.multi_line = flags.multi_line;
+    return self;
+  }
+
   pub fn scan_next(&mut self) -> Token {
-    let t = match self.chars.get(self.index) {
+    // flag directives (and in extended mode, whitespace and comments) don't
+    // produce tokens of their own
+    while self.scan_flags() || self.scan_ignored() {
+      // Empty
+    }
+
+    let start = self.index;
+    let mut t = match self.chars.get(self.index) {
       Some(c) => self.char_to_token(*c),
       None => Token::new(TokenType::EOF, '\0'),
     };
 
     self.index += 1;
+    t.span = Span::new(start, self.index.min(self.chars.len()));
+    if matches!(t.t_type, TokenType::Character | TokenType::Range) {
+      t.caseless = self.caseless;
+    }
+    match t.t_type {
+      TokenType::LBracket => { self.in_class = true; },
+      TokenType::RBracket => { self.in_class = false; },
+      _ => {
+        // Empty
+      },
+    }
 
     return t;
   }
 
+  // errors found so far, an Error token is returned in place of each one
+  pub fn take_errors(&mut self) -> Vec<Error> {
+    return std::mem::take(&mut self.errors);
+  }
+
+  fn error(&mut self, kind: ErrorKind, start: usize, end: usize) {
+    let end = end.min(self.chars.len()).max(start);
+    self.errors.push(Error::new(kind, Span::new(start, end)));
+  }
+
+  // consume an inline flag directive (e.g.: `(?-u)`) if one starts at index
+  // flags apply from the directive until the end of the expression
+  fn scan_flags(&mut self) -> bool {
+    if self.chars.get(self.index) != Some(&'(') ||
+       self.chars.get(self.index + 1) != Some(&'?') {
+      return false;
+    }
+
+    let mut i = self.index + 2;
+    let mut negate = false;
+    let mut flags = (self.unicode, self.caseless, self.dot_all, self.extended,
+                     self.multi_line);
+    loop {
+      match self.chars.get(i) {
+        Some('-') if !negate => { negate = true; },
+        Some('u') => { flags.0 = !negate; },
+        Some('i') => { flags.1 = !negate; },
+        Some('s') => { flags.2 = !negate; },
+        Some('x') => { flags.3 = !negate; },
+        Some('m') => { flags.4 = !negate; },
+        Some(')') => { break; },
+        // not a directive we know, leave it for the parser
+        _ => { return false; },
+      }
+      i += 1;
+    }
+
+    (self.unicode, self.caseless, self.dot_all, self.extended,
+     self.multi_line) = flags;
+    self.index = i + 1;
+    return true;
+  }
+
+  // skip whitespace or a `#` comment (to the end of the line) if the
+  // expression is extended and one starts at index
+  fn scan_ignored(&mut self) -> bool {
+    if !self.extended || self.in_class {
+      return false;
+    }
+    match self.chars.get(self.index) {
+      Some(c) if c.is_whitespace() => { self.index += 1; },
+      Some('#') => {
+        while self.chars.get(self.index).is_some_and(|c| *c != '\n') {
+          self.index += 1;
+        }
+      },
+      _ => { return false; },
+    }
+    return true;
+  }
+
+  // an LParen, which may begin a named group: `(?<name>` or `(?P<name>`
+  fn scan_group_open(&mut self) -> Token {
+    let mut token = Token::new(TokenType::LParen, '(');
+
+    let mut i = self.index + 1;
+    if self.chars.get(i) != Some(&'?') {
+      return token;
+    }
+    i += 1;
+    if self.chars.get(i) == Some(&'P') {
+      i += 1;
+    }
+    // not a named group, leave the rest for the parser
+    if self.chars.get(i) != Some(&'<') {
+      return token;
+    }
+    i += 1;
+
+    // names are made of word characters and can't start with a digit
+    let mut name = String::new();
+    loop {
+      match self.chars.get(i) {
+        Some('>') if !name.is_empty() => { break; },
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' ||
+                   (c.is_ascii_digit() && !name.is_empty()) => {
+          name.push(*c);
+        },
+        _ => {
+          self.error(ErrorKind::InvalidGroupName, self.index, i + 1);
+          // carry on after the name as if it was fine, so the group still
+          // lines up with its ')'
+          return match self.chars[i..].iter().position(|c| *c == '>') {
+            Some(close) => {
+              self.index = i + close;
+              token
+            },
+            None => Token::new(TokenType::Error, '('),
+          };
+        },
+      }
+      i += 1;
+    }
+
+    // point at '>', scan_next will step past it
+    self.index = i;
+    token.name = Some(name);
+    return token;
+  }
+
+  fn range_token(&self, range: &[CharRange]) -> Token {
+    return Token {
+      t_type: TokenType::Range,
+      image: '\0',
+      range: Vec::from(range),
+      unicode: self.unicode,
+      caseless: false,
+      name: None,
+      multi_line: false,
+      span: Span::default(),
+    };
+  }
+
   fn handle_escape(&mut self) -> Token {
+    let start = self.index;
     let mut escape_len = 0;
     let mut escape_type = EscapeType::Basic;
     let mut unicode_code: u32 = 0x0;
@@ -143,12 +343,12 @@ impl Scanner {
     loop {
       // modified scan_next procedure
       self.index += 1;
-      let mut c;
+      let c;
       match self.chars.get(self.index) {
         Some(nc) => { c = nc; },
         None => {
           if escape_len == 0 {
-            println!("lexical error: saw EOF while parsing escape sequence");
+            self.error(ErrorKind::TrailingBackslash, start, self.index);
             return Token::new(TokenType::Error, '\0');
           }
           // EOF can be handled by escape code parsers
@@ -167,7 +367,8 @@ impl Scanner {
             escape_len += 1;
           },
           'x' => {
-            escape_type = EscapeType::AsciiHex;
+            escape_type = if self.bytes { EscapeType::ByteHex }
+                          else { EscapeType::AsciiHex };
             escape_len += 1;
           },
           '0'..='9' => {
@@ -183,55 +384,13 @@ impl Scanner {
           'f' => { return Token::new(TokenType::Character, '\x0c'); },
           'r' => { return Token::new(TokenType::Character, '\r'); },
           // Perl character classes
-          'd' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::DIGIT),
-            };
-          },
-          'D' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::NOT_DIGIT),
-            };
-          },
-          'w' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::WORD),
-            };
-          },
-          'W' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::NOT_WORD),
-            };
-          },
-          's' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::WHITESPACE),
-            };
-          },
-          'S' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::NOT_WHITESPACE),
-            };
-          },
-          'N' => {
-            return Token {
-              t_type: TokenType::Range,
-              image: '\0',
-              range: Vec::from(PerlCC::NOT_NEWLINE),
-            };
-          }
+          'd' => { return self.range_token(PerlCC::DIGIT); },
+          'D' => { return self.range_token(PerlCC::NOT_DIGIT); },
+          'w' => { return self.range_token(PerlCC::WORD); },
+          'W' => { return self.range_token(PerlCC::NOT_WORD); },
+          's' => { return self.range_token(PerlCC::WHITESPACE); },
+          'S' => { return self.range_token(PerlCC::NOT_WHITESPACE); },
+          'N' => { return self.range_token(PerlCC::NOT_NEWLINE); },
           // no special meaning, just return character
           // TODO: this means something like \y - which isn't a valid escape
           //   sequence - would parse as "y" instead of throwing an error
@@ -253,14 +412,20 @@ impl Scanner {
             }
             match c {
               '0'..='9' | 'a'..='f' => {
-                unicode_code = unicode_code << 4;
+                unicode_code <<= 4;
                 unicode_code |= char_to_hex(*c);
                 escape_len += 1;
               },
               _ => {
-                println!("lexical error: saw '{}' while parsing unicode hex",
-                         *c);
-                return Token::new(TokenType::Error, *c);
+                let c = *c;
+                let kind = if self.index >= self.chars.len() {
+                  ErrorKind::IncompleteEscape
+                }
+                else {
+                  ErrorKind::InvalidEscape(c)
+                };
+                self.error(kind, start, self.index + 1);
+                return Token::new(TokenType::Error, c);
               },
             }
           },
@@ -291,8 +456,26 @@ impl Scanner {
               }
             }
           },
+          EscapeType::ByteHex => 'byte_hex_match: {
+            // at most two hex digits, so every code is a valid byte
+            if escape_len == 3 {
+              completed_code = true;
+              break 'byte_hex_match;
+            }
+            match c {
+              '0'..='9' | 'a'..='f' | 'A'..='F' => {
+                ascii_code <<= 4;
+                ascii_code |= char_to_hex(*c);
+                escape_len += 1;
+              },
+              // otherwise, just un-consume character and break early
+              _ => {
+                completed_code = true;
+              }
+            }
+          },
           EscapeType::AsciiDec => 'ascii_dec_match: {
-            if escape_len == 3{
+            if escape_len == 3 {
               completed_code = true;
               break 'ascii_dec_match;
             }
@@ -329,7 +512,8 @@ impl Scanner {
               return match char::from_u32(unicode_code) {
                 Some(u) => Token::new(TokenType::Character, u),
                 None => {
-                  println!("lexical error: invalid unicode escape code");
+                  self.error(ErrorKind::InvalidCodePoint(unicode_code),
+                             start, self.index + 1);
                   return Token::new(TokenType::Error, '\0');
                 },
               };
@@ -339,11 +523,19 @@ impl Scanner {
                 Some(a) => Token::new(TokenType::Character, a),
                 // invalid characters should already be caught in the loop
                 None => {
-                  println!("lexical error: invalid ascii escape code");
+                  self.error(ErrorKind::InvalidCodePoint(ascii_code),
+                             start, self.index + 1);
                   return Token::new(TokenType::Error, '\0');
                 }
               };
             },
+            EscapeType::ByteHex => {
+              // bytes are stored as the char with the same code point
+              let mut byte_token = Token::new(TokenType::Character,
+                                              char::from(ascii_code as u8));
+              byte_token.unicode = false;
+              return byte_token;
+            },
             _ => {
               // Empty - this should never happen
             },
@@ -357,18 +549,38 @@ impl Scanner {
     match c {
       '|' => Token::new(TokenType::Union, c),
       '*' => Token::new(TokenType::Star, c),
-      '(' => Token::new(TokenType::LParen, c),
+      '(' => self.scan_group_open(),
       ')' => Token::new(TokenType::RParen, c),
       '[' => Token::new(TokenType::LBracket, c),
       ']' => Token::new(TokenType::RBracket, c),
-      '^' => Token::new(TokenType::Caret, c),
+      // '^' negates a class, outside of one it and '$' are anchors
+      '^' if self.in_class => Token::new(TokenType::Caret, c),
+      '^' | '$' if !self.in_class => {
+        let mut anchor = Token::new(TokenType::Anchor, c);
+        anchor.multi_line = self.multi_line;
+        anchor
+      },
       '?' => Token::new(TokenType::Question, c),
       '+' => Token::new(TokenType::Plus, c),
       '.' => Token {
         t_type: TokenType::Range,
         image: c,
-        // TODO: in Perl this exludes '\n' by default
-        range: vec![CharRange::new(0x0000, 0xFFFF, false)],
+        // like in Perl this excludes '\n' unless dot_all is set
+        // without unicode it is any single byte (where units can be bytes)
+        range: if !self.dot_all {
+            Vec::from(PerlCC::NOT_NEWLINE)
+          }
+          else if self.unicode || !self.bytes {
+            vec![CharRange::new(0x0000, MAX_UNIT, false)]
+          }
+          else {
+            vec![CharRange::new(0x0000, 0x00FF, false)]
+          },
+        unicode: self.unicode,
+        caseless: false,
+        name: None,
+        multi_line: false,
+        span: Span::default(),
       },
       '\\' => self.handle_escape(),
       _ => Token::new(TokenType::Character, c),
@@ -389,17 +601,17 @@ mod tests {
     return tokens;
   }
 
-  fn test_token_types(tokens: &Vec<Token>, t_types: Vec<TokenType>) {
+  fn test_token_types(tokens: &[Token], t_types: Vec<TokenType>) {
     assert_eq!(tokens.len(), t_types.len());
 
     for i in 0..tokens.len() {
       let a = &tokens[i].t_type;
       let b = &t_types[i];
-      assert!(matches!(a, b));
+      assert_eq!(a, b);
     }
   }
 
-  fn test_token_images(tokens: &Vec<Token>, images: Vec<char>) {
+  fn test_token_images(tokens: &[Token], images: Vec<char>) {
     assert_eq!(tokens.len(), images.len());
 
     for i in 0..tokens.len() {
@@ -499,6 +711,53 @@ mod tests {
                       vec!['a', '\n', '\0', 'a', '\0']);
   }
 
+  #[test]
+  fn scan_byte_hex_escapes() {
+    let mut s = Scanner::new_bytes("\\xFF\\x0aZ\\x7");
+    let tokens = scan_all(&mut s);
+    test_token_types(&tokens,
+                     vec![TokenType::Character,
+                          TokenType::Character,
+                          TokenType::Character,
+                          TokenType::Character,
+                          TokenType::EOF]);
+    test_token_images(&tokens,
+                      vec!['\u{ff}', '\n', 'Z', '\x07', '\0']);
+    assert!(!tokens[0].unicode);
+    assert!(tokens[2].unicode);
+  }
+
+  #[test]
+  fn scan_unicode_flag_directives() {
+    let mut s = Scanner::new("(?-u).(?u).");
+    let tokens = scan_all(&mut s);
+    test_token_types(&tokens,
+                     vec![TokenType::Range,
+                          TokenType::Range,
+                          TokenType::EOF]);
+    assert!(!tokens[0].unicode);
+    assert!(tokens[1].unicode);
+  }
+
+  #[test]
+  fn scan_flag_directives() {
+    let mut s = Scanner::new("a(?ix) b # c\n[ ](?-i)c");
+    let tokens = scan_all(&mut s);
+    test_token_types(&tokens,
+                     vec![TokenType::Character,
+                          TokenType::Character,
+                          TokenType::LBracket,
+                          TokenType::Character,
+                          TokenType::RBracket,
+                          TokenType::Character,
+                          TokenType::EOF]);
+    test_token_images(&tokens,
+                      vec!['a', 'b', '[', ' ', ']', 'c', '\0']);
+    assert!(!tokens[0].caseless);
+    assert!(tokens[1].caseless && tokens[3].caseless);
+    assert!(!tokens[5].caseless);
+  }
+
   #[test]
   fn scan_invalid_ascii_hex_escape() {
     let mut s = Scanner::new(&String::from("\\xj"));
@@ -511,6 +770,7 @@ mod tests {
                       vec!['\0', 'j', '\0']);
   }
 
+  #[test]
   fn scan_escaped_reserved_characters() {
     let mut s = Scanner::new(&String::from("\\|\\*\\(\\)\\[\\]\\^\\?\\+"));
     let tokens = scan_all(&mut s);
@@ -524,7 +784,7 @@ mod tests {
                           TokenType::Character,
                           TokenType::Character,
                           TokenType::Character,
-                          TokenType::Character]);
+                          TokenType::EOF]);
     test_token_images(&tokens,
                       vec!['|', '*', '(', ')', '[', ']', '^', '?', '+', '\0']);
   }
@@ -566,6 +826,26 @@ mod tests {
                       vec!['(', 'a', ')', '\0']);
   }
 
+  #[test]
+  fn scan_named_groups() {
+    let mut s = Scanner::new("(?<year>a)(?P<_m2>b)(c)");
+    let tokens = scan_all(&mut s);
+    test_token_types(&tokens,
+                     vec![TokenType::LParen,
+                          TokenType::Character,
+                          TokenType::RParen,
+                          TokenType::LParen,
+                          TokenType::Character,
+                          TokenType::RParen,
+                          TokenType::LParen,
+                          TokenType::Character,
+                          TokenType::RParen,
+                          TokenType::EOF]);
+    assert_eq!(tokens[0].name.as_deref(), Some("year"));
+    assert_eq!(tokens[3].name.as_deref(), Some("_m2"));
+    assert_eq!(tokens[6].name, None);
+  }
+
   #[test]
   fn scan_brackets_with_caret() {
     let mut s = Scanner::new(&String::from("[^a]"));
@@ -580,6 +860,25 @@ mod tests {
                       vec!['[', '^', 'a', ']', '\0']);
   }
 
+  #[test]
+  fn scan_anchors() {
+    let mut s = Scanner::new("^[^$]$(?m)^");
+    let tokens = scan_all(&mut s);
+    test_token_types(&tokens,
+                     vec![TokenType::Anchor,
+                          TokenType::LBracket,
+                          TokenType::Caret,
+                          TokenType::Character,
+                          TokenType::RBracket,
+                          TokenType::Anchor,
+                          TokenType::Anchor,
+                          TokenType::EOF]);
+    test_token_images(&tokens,
+                      vec!['^', '[', '^', '$', ']', '$', '^', '\0']);
+    assert!(!tokens[0].multi_line && !tokens[5].multi_line);
+    assert!(tokens[6].multi_line);
+  }
+
   #[test]
   fn scan_question() {
     let mut s = Scanner::new(&String::from("a?"));
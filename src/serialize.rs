@@ -0,0 +1,705 @@
+use crate::ast::Anchor;
+use crate::ast::Bounds;
+use crate::ast::CharRange;
+use crate::ast::NodeType;
+use crate::ast::TreeNode;
+use crate::ast::MAX_UNIT;
+use crate::builder::Semantics;
+use crate::error::Span;
+use crate::limits::Limits;
+use crate::limits::DEFAULT_MAX_DEPTH;
+use crate::program::Inst;
+use crate::program::Program;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+/// The version of the format written by `to_bytes`, only bytes written with
+/// the same version can be loaded.
+pub const FORMAT_VERSION: u32 = 2;
+
+// what every serialised expression starts with
+const MAGIC: &[u8; 4] = b"REX\0";
+
+// the deepest tree that is loaded, whatever max_depth the bytes claim, as
+// compiling it again walks it by recursion
+const MAX_TREE_DEPTH: usize = DEFAULT_MAX_DEPTH * 4;
+
+/// Why bytes couldn't be loaded by `from_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+  /// The bytes weren't written by `to_bytes`.
+  NotRex,
+  /// Written in another version of the format.
+  Version(u32),
+  /// Written by a different type (e.g.: a `bytes::RegEx`).
+  WrongKind,
+  /// Cut short, changed since they were written, or otherwise invalid, and
+  /// which part was wrong.
+  Corrupt(&'static str),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return match self {
+      LoadError::NotRex => write!(f, "not a serialised expression"),
+      LoadError::Version(v) => {
+        write!(f, "format version {} can't be loaded by version {}", v,
+               FORMAT_VERSION)
+      },
+      LoadError::WrongKind => {
+        write!(f, "serialised as a different type of expression")
+      },
+      LoadError::Corrupt(part) => write!(f, "corrupt {}", part),
+    };
+  }
+}
+
+impl error::Error for LoadError {}
+
+// what was serialised, so one type can't load another's bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+  Text = 0,
+  Bytes = 1,
+  Set = 2,
+}
+
+// the parts of a RegEx (or bytes::RegEx) that are saved, everything else is
+// quick to work out again from them
+pub(crate) struct Parts<'a> {
+  pub expr: &'a str,
+  pub tree: &'a TreeNode,
+  pub program: &'a Program,
+  pub group_names: &'a [Option<String>],
+  pub semantics: Semantics,
+  pub limits: Limits,
+  pub onepass: bool, // whether full matches were made in one pass
+}
+
+// the same parts, loaded
+pub(crate) struct Loaded {
+  pub expr: String,
+  pub tree: TreeNode,
+  pub program: Program,
+  pub group_names: Vec<Option<String>>,
+  pub semantics: Semantics,
+  pub limits: Limits,
+  pub onepass: bool,
+}
+
+// the header, what write puts after it, and a checksum of it all
+// (numbers are little endian, and lengths come before what they count)
+pub(crate) fn save(kind: Kind, write: impl FnOnce(&mut Writer)) -> Vec<u8> {
+  let mut w = Writer { bytes: MAGIC.to_vec() };
+  w.u32(FORMAT_VERSION);
+  w.u8(kind as u8);
+  write(&mut w);
+  let sum = checksum(&w.bytes);
+  w.u64(sum);
+  return w.bytes;
+}
+
+// FNV-1a, which catches bytes that were changed or cut short
+fn checksum(bytes: &[u8]) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for b in bytes {
+    hash ^= *b as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  return hash;
+}
+
+pub(crate) struct Writer {
+  bytes: Vec<u8>,
+}
+
+impl Writer {
+  pub fn u8(&mut self, n: u8) {
+    self.bytes.push(n);
+  }
+
+  pub fn u32(&mut self, n: u32) {
+    self.bytes.extend(n.to_le_bytes());
+  }
+
+  pub fn u64(&mut self, n: u64) {
+    self.bytes.extend(n.to_le_bytes());
+  }
+
+  pub fn usize(&mut self, n: usize) {
+    self.u64(n as u64);
+  }
+
+  fn bool(&mut self, b: bool) {
+    self.u8(b as u8);
+  }
+
+  fn str(&mut self, s: &str) {
+    self.usize(s.len());
+    self.bytes.extend(s.as_bytes());
+  }
+
+  fn option<T>(&mut self, x: Option<T>, write: impl FnOnce(&mut Self, T)) {
+    self.bool(x.is_some());
+    if let Some(x) = x {
+      write(self, x);
+    }
+  }
+
+  pub fn regex(&mut self, parts: &Parts) {
+    self.str(parts.expr);
+    self.u8(match parts.semantics {
+      Semantics::LeftmostLongest => 0,
+      Semantics::LeftmostFirst => 1,
+    });
+    let limits = &parts.limits;
+    self.option(limits.max_steps, Self::u64);
+    self.option(limits.timeout, |w, t| {
+      w.u64(t.as_secs());
+      w.u32(t.subsec_nanos());
+    });
+    self.usize(limits.max_depth);
+    self.option(limits.max_size, Self::usize);
+    self.bool(parts.onepass);
+
+    self.usize(parts.group_names.len());
+    for name in parts.group_names {
+      self.option(name.as_deref(), Self::str);
+    }
+    self.tree(parts.tree);
+    self.program(parts.program);
+  }
+
+  fn tree(&mut self, node: &TreeNode) {
+    self.u8(match node.n_type {
+      NodeType::Error => 0,
+      NodeType::Empty => 1,
+      NodeType::Word => 2,
+      NodeType::Charset => 3,
+      NodeType::Union => 4,
+      NodeType::Star => 5,
+      NodeType::Group => 6,
+      NodeType::MatchGroup => 7,
+      NodeType::Anchor => 8,
+    });
+    self.usize(node.image.len());
+    for c in &node.image {
+      self.u32(*c as u32);
+    }
+    self.u32(node.repeats.min);
+    self.u32(node.repeats.max);
+    self.ranges(&node.ranges);
+    self.bool(node.unicode);
+    self.usize(node.group);
+    self.option(node.name.as_deref(), Self::str);
+    self.anchor(node.anchor);
+    self.span(node.span);
+    self.usize(node.children.len());
+    for n in &node.children {
+      self.tree(n);
+    }
+  }
+
+  fn ranges(&mut self, ranges: &[CharRange]) {
+    self.usize(ranges.len());
+    for r in ranges {
+      self.u32(r.min);
+      self.u32(r.max);
+      self.bool(r.negate);
+    }
+  }
+
+  fn span(&mut self, span: Span) {
+    self.usize(span.start);
+    self.usize(span.end);
+  }
+
+  fn anchor(&mut self, anchor: Anchor) {
+    self.u8(match anchor {
+      Anchor::Start => 0,
+      Anchor::End => 1,
+      Anchor::LineStart => 2,
+      Anchor::LineEnd => 3,
+    });
+  }
+
+  pub fn program(&mut self, program: &Program) {
+    self.usize(program.insts.len());
+    for inst in &program.insts {
+      match inst {
+        Inst::Char { c, unicode } => {
+          self.u8(0);
+          self.u32(*c);
+          self.bool(*unicode);
+        },
+        Inst::Ranges { ranges, unicode } => {
+          self.u8(1);
+          self.ranges(ranges);
+          self.bool(*unicode);
+        },
+        Inst::Split(a, b) => {
+          self.u8(2);
+          self.usize(*a);
+          self.usize(*b);
+        },
+        Inst::Jmp(to) => {
+          self.u8(3);
+          self.usize(*to);
+        },
+        Inst::Save(slot) => {
+          self.u8(4);
+          self.usize(*slot);
+        },
+        Inst::Match(n) => {
+          self.u8(5);
+          self.usize(*n);
+        },
+        Inst::Fail => self.u8(6),
+        Inst::Assert(anchor) => {
+          self.u8(7);
+          self.anchor(*anchor);
+        },
+      }
+    }
+    self.usize(program.start);
+    self.usize(program.pattern_count);
+    for span in &program.spans {
+      self.span(*span);
+    }
+  }
+}
+
+pub(crate) struct Reader<'b> {
+  bytes: &'b [u8],
+  at: usize,
+}
+
+impl<'b> Reader<'b> {
+  // check the header and the checksum, ready to read what was saved
+  pub fn new(bytes: &'b [u8], kind: Kind) -> Result<Self, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+      return Err(LoadError::NotRex);
+    }
+    let mut r = Reader { bytes, at: MAGIC.len() };
+    let version = r.u32()?;
+    if version != FORMAT_VERSION {
+      return Err(LoadError::Version(version));
+    }
+
+    let end = bytes.len().checked_sub(8)
+                         .filter(|end| *end > r.at)
+                         .ok_or(LoadError::Corrupt("header"))?;
+    let mut sum = [0; 8];
+    sum.copy_from_slice(&bytes[end..]);
+    if u64::from_le_bytes(sum) != checksum(&bytes[..end]) {
+      return Err(LoadError::Corrupt("checksum"));
+    }
+    r.bytes = &bytes[..end];
+
+    if r.u8()? != kind as u8 {
+      return Err(LoadError::WrongKind);
+    }
+    return Ok(r);
+  }
+
+  // everything should have been read
+  pub fn finish(&self) -> Result<(), LoadError> {
+    if self.at != self.bytes.len() {
+      return Err(LoadError::Corrupt("length"));
+    }
+    return Ok(());
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'b [u8], LoadError> {
+    let end = self.at.checked_add(n)
+                     .filter(|end| *end <= self.bytes.len())
+                     .ok_or(LoadError::Corrupt("length"))?;
+    let taken = &self.bytes[self.at..end];
+    self.at = end;
+    return Ok(taken);
+  }
+
+  fn u8(&mut self) -> Result<u8, LoadError> {
+    return Ok(self.take(1)?[0]);
+  }
+
+  fn u32(&mut self) -> Result<u32, LoadError> {
+    let mut n = [0; 4];
+    n.copy_from_slice(self.take(4)?);
+    return Ok(u32::from_le_bytes(n));
+  }
+
+  fn u64(&mut self) -> Result<u64, LoadError> {
+    let mut n = [0; 8];
+    n.copy_from_slice(self.take(8)?);
+    return Ok(u64::from_le_bytes(n));
+  }
+
+  pub fn usize(&mut self) -> Result<usize, LoadError> {
+    return usize::try_from(self.u64()?).map_err(|_| {
+      LoadError::Corrupt("length")
+    });
+  }
+
+  // a count of things that each take at least size bytes, which can't be
+  // more than there are bytes left for
+  fn count(&mut self, size: usize) -> Result<usize, LoadError> {
+    let n = self.usize()?;
+    if n.saturating_mul(size) > self.bytes.len() - self.at {
+      return Err(LoadError::Corrupt("length"));
+    }
+    return Ok(n);
+  }
+
+  fn bool(&mut self) -> Result<bool, LoadError> {
+    return match self.u8()? {
+      0 => Ok(false),
+      1 => Ok(true),
+      _ => Err(LoadError::Corrupt("flag")),
+    };
+  }
+
+  fn str(&mut self) -> Result<String, LoadError> {
+    let len = self.count(1)?;
+    let bytes = self.take(len)?;
+    return String::from_utf8(bytes.to_vec()).map_err(|_| {
+      LoadError::Corrupt("string")
+    });
+  }
+
+  fn option<T>(&mut self, read: impl FnOnce(&mut Self)
+                 -> Result<T, LoadError>)
+    -> Result<Option<T>, LoadError> {
+    return match self.bool()? {
+      true => Ok(Some(read(self)?)),
+      false => Ok(None),
+    };
+  }
+
+  pub fn regex(&mut self) -> Result<Loaded, LoadError> {
+    let expr = self.str()?;
+    let semantics = match self.u8()? {
+      0 => Semantics::LeftmostLongest,
+      1 => Semantics::LeftmostFirst,
+      _ => { return Err(LoadError::Corrupt("semantics")); },
+    };
+    let limits = Limits {
+      max_steps: self.option(Self::u64)?,
+      timeout: self.option(|r| {
+        let secs = r.u64()?;
+        let nanos = r.u32()?;
+        if nanos >= 1_000_000_000 {
+          return Err(LoadError::Corrupt("limits"));
+        }
+        return Ok(Duration::new(secs, nanos));
+      })?,
+      max_depth: self.usize()?,
+      max_size: self.option(Self::usize)?,
+    };
+    let onepass = self.bool()?;
+
+    let groups = self.count(1)?;
+    let mut group_names = vec![];
+    for _ in 0..groups {
+      group_names.push(self.option(Self::str)?);
+    }
+    if group_names.is_empty() {
+      return Err(LoadError::Corrupt("groups"));
+    }
+
+    // the parser stops groups being nested deeper than max_depth, and each
+    // level of them adds at most a few levels of nodes
+    let depth = limits.max_depth.saturating_add(1).saturating_mul(4)
+                  .min(MAX_TREE_DEPTH);
+    let tree = self.tree(depth, groups)?;
+    let program = self.program(groups)?;
+    if program.pattern_count != 1 {
+      return Err(LoadError::Corrupt("program"));
+    }
+
+    return Ok(Loaded {
+      expr,
+      tree,
+      program,
+      group_names,
+      semantics,
+      limits,
+      onepass,
+    });
+  }
+
+  // a node and everything below it, at most depth levels deep, with group
+  // numbers less than groups
+  //
+  // read with a stack of its own rather than by recursion, so that a deep
+  // tree is rejected instead of overflowing the stack
+  fn tree(&mut self, depth: usize, groups: usize)
+    -> Result<TreeNode, LoadError> {
+    let corrupt = LoadError::Corrupt("tree");
+    // nodes whose children are still being read, and how many are left
+    let mut open: Vec<(TreeNode, usize)> = vec![];
+    loop {
+      if open.len() >= depth {
+        return Err(corrupt);
+      }
+      let mut node = self.node()?;
+      let children = self.count(1)?;
+      if children > 0 {
+        open.push((node, children));
+        continue;
+      }
+
+      // the node is complete, so it goes to its parent, which may be
+      // complete in turn
+      loop {
+        let valid = match node.n_type {
+          // only the repetitions the parser makes (?, * and +), which
+          // bounds how many copies of its child are compiled
+          NodeType::Star => {
+            node.children.len() == 1 && node.repeats.min <= 1 &&
+            node.repeats.max <= 1 &&
+            (node.repeats.max == 0 || node.repeats.min <= node.repeats.max)
+          },
+          NodeType::MatchGroup => node.group > 0 && node.group < groups,
+          _ => true,
+        };
+        if !valid {
+          return Err(corrupt);
+        }
+        match open.last_mut() {
+          Some((parent, left)) => {
+            parent.children.push(node);
+            *left -= 1;
+            if *left > 0 {
+              break;
+            }
+          },
+          None => { return Ok(node); },
+        }
+        node = open.pop().unwrap().0;
+      }
+    }
+  }
+
+  // a node without its children
+  fn node(&mut self) -> Result<TreeNode, LoadError> {
+    let corrupt = LoadError::Corrupt("tree");
+    let mut node = TreeNode::new(match self.u8()? {
+      1 => NodeType::Empty,
+      2 => NodeType::Word,
+      3 => NodeType::Charset,
+      4 => NodeType::Union,
+      5 => NodeType::Star,
+      6 => NodeType::Group,
+      7 => NodeType::MatchGroup,
+      8 => NodeType::Anchor,
+      // a tree with errors is never compiled
+      _ => { return Err(corrupt); },
+    });
+    let len = self.count(4)?;
+    for _ in 0..len {
+      node.image.push(char::from_u32(self.u32()?).ok_or(corrupt)?);
+    }
+    node.repeats = Bounds { min: self.u32()?, max: self.u32()? };
+    node.ranges = self.ranges()?;
+    node.unicode = self.bool()?;
+    node.group = self.usize()?;
+    node.name = self.option(Self::str)?;
+    node.anchor = self.anchor()?;
+    node.span = self.span()?;
+    return Ok(node);
+  }
+
+  fn ranges(&mut self) -> Result<Vec<CharRange>, LoadError> {
+    let len = self.count(9)?;
+    let mut ranges = vec![];
+    for _ in 0..len {
+      let (min, max, negate) = (self.u32()?, self.u32()?, self.bool()?);
+      if min > max || max > MAX_UNIT {
+        return Err(LoadError::Corrupt("ranges"));
+      }
+      ranges.push(CharRange::new(min, max, negate));
+    }
+    return Ok(ranges);
+  }
+
+  fn span(&mut self) -> Result<Span, LoadError> {
+    return Ok(Span::new(self.usize()?, self.usize()?));
+  }
+
+  fn anchor(&mut self) -> Result<Anchor, LoadError> {
+    return match self.u8()? {
+      0 => Ok(Anchor::Start),
+      1 => Ok(Anchor::End),
+      2 => Ok(Anchor::LineStart),
+      3 => Ok(Anchor::LineEnd),
+      _ => Err(LoadError::Corrupt("anchor")),
+    };
+  }
+
+  // a program that never goes to an instruction it doesn't have, or saves
+  // to a slot that isn't there for the groups
+  pub fn program(&mut self, groups: usize) -> Result<Program, LoadError> {
+    let len = self.count(1)?;
+    let mut insts = vec![];
+    for _ in 0..len {
+      insts.push(match self.u8()? {
+        0 => {
+          let c = self.u32()?;
+          Inst::Char { c, unicode: self.bool()? }
+        },
+        1 => {
+          let ranges = self.ranges()?;
+          Inst::Ranges { ranges, unicode: self.bool()? }
+        },
+        2 => Inst::Split(self.usize()?, self.usize()?),
+        3 => Inst::Jmp(self.usize()?),
+        4 => Inst::Save(self.usize()?),
+        5 => Inst::Match(self.usize()?),
+        6 => Inst::Fail,
+        7 => Inst::Assert(self.anchor()?),
+        _ => { return Err(LoadError::Corrupt("program")); },
+      });
+    }
+    let start = self.usize()?;
+    let pattern_count = self.usize()?;
+    let mut spans = vec![];
+    for _ in 0..len {
+      spans.push(self.span()?);
+    }
+
+    let valid = start < len && insts.iter().enumerate().all(|(pc, inst)| {
+      return match inst {
+        Inst::Char { c, .. } => *c <= MAX_UNIT && pc + 1 < len,
+        Inst::Ranges { .. } => pc + 1 < len,
+        Inst::Split(a, b) => *a < len && *b < len,
+        Inst::Jmp(to) => *to < len,
+        Inst::Save(slot) => *slot < groups * 2 && pc + 1 < len,
+        Inst::Assert(_) => pc + 1 < len,
+        Inst::Match(n) => *n < pattern_count,
+        Inst::Fail => true,
+      };
+    });
+    if !valid {
+      return Err(LoadError::Corrupt("program"));
+    }
+    // the automata for unions of literals aren't saved, a RegEx takes them
+    // from its tree once it has checked the program is the tree's
+    return Ok(Program {
+      insts,
+      start,
+      pattern_count,
+      spans,
+      literals: BTreeMap::new(),
+    });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::regex::RegEx;
+  use super::*;
+
+  #[test]
+  fn load_rejects_headers() {
+    let bytes = RegEx::new("a(b)c").unwrap().to_bytes();
+    assert_eq!(RegEx::from_bytes(b"").err(), Some(LoadError::NotRex));
+    assert_eq!(RegEx::from_bytes(b"regex").err(), Some(LoadError::NotRex));
+
+    let mut newer = bytes.clone();
+    newer[4] = 3;
+    assert_eq!(RegEx::from_bytes(&newer).err(), Some(LoadError::Version(3)));
+    assert_eq!(RegEx::from_bytes(&bytes[..10]).err(),
+               Some(LoadError::Corrupt("header")));
+    assert_eq!(RegEx::from_bytes(&bytes[..30]).err(),
+               Some(LoadError::Corrupt("checksum")));
+  }
+
+  #[test]
+  fn load_rejects_changes() {
+    let bytes = RegEx::new("(a|b)*c[x-z]").unwrap().to_bytes();
+    for i in 0..bytes.len() {
+      let mut changed = bytes.clone();
+      changed[i] ^= 0x10;
+      assert!(RegEx::from_bytes(&changed).is_err());
+    }
+  }
+
+  // bytes for a text RegEx of tree and program, that pass the checksum
+  fn save_parts(tree: &TreeNode, insts: Vec<Inst>) -> Vec<u8> {
+    let program = Program {
+      spans: vec![Span::default(); insts.len()],
+      insts,
+      start: 0,
+      pattern_count: 1,
+      literals: BTreeMap::new(),
+    };
+    let parts = Parts {
+      expr: "",
+      tree,
+      program: &program,
+      group_names: &[None],
+      semantics: Semantics::LeftmostLongest,
+      limits: Limits::default(),
+      onepass: false,
+    };
+    return save(Kind::Text, |w| w.regex(&parts));
+  }
+
+  #[test]
+  fn load_rejects_invalid_programs() {
+    let mut group = TreeNode::new(NodeType::Group);
+    group.children.push(TreeNode::new(NodeType::Empty));
+    assert!(RegEx::from_bytes(&save_parts(&group, vec![Inst::Match(0)]))
+                  .is_ok());
+
+    // jumping out of the program
+    let bytes = save_parts(&group, vec![Inst::Jmp(5), Inst::Match(0)]);
+    assert_eq!(RegEx::from_bytes(&bytes).err(),
+               Some(LoadError::Corrupt("program")));
+    // a valid program, but not the one the tree compiles to
+    let bytes = save_parts(&group, vec![Inst::Split(0, 0), Inst::Match(0)]);
+    assert_eq!(RegEx::from_bytes(&bytes).err(),
+               Some(LoadError::Corrupt("program")));
+
+    // more repetitions than the parser makes
+    let mut star = TreeNode::new(NodeType::Star);
+    star.children.push(group);
+    star.repeats = Bounds { min: 5, max: 0 };
+    assert_eq!(RegEx::from_bytes(&save_parts(&star, vec![Inst::Match(0)]))
+                    .err(),
+               Some(LoadError::Corrupt("tree")));
+  }
+
+  #[test]
+  fn load_rejects_deep_trees() {
+    // the limits in the bytes can't raise the depth that is read
+    let program = Program {
+      insts: vec![Inst::Match(0)],
+      start: 0,
+      pattern_count: 1,
+      spans: vec![Span::default()],
+      literals: BTreeMap::new(),
+    };
+    let mut tree = TreeNode::new(NodeType::Empty);
+    for _ in 0..MAX_TREE_DEPTH {
+      let mut group = TreeNode::new(NodeType::Group);
+      group.children.push(tree);
+      tree = group;
+    }
+    let parts = Parts {
+      expr: "",
+      tree: &tree,
+      program: &program,
+      group_names: &[None],
+      semantics: Semantics::LeftmostLongest,
+      limits: Limits { max_depth: usize::MAX, ..Limits::default() },
+      onepass: false,
+    };
+    let bytes = save(Kind::Text, |w| w.regex(&parts));
+    assert_eq!(RegEx::from_bytes(&bytes).err(),
+               Some(LoadError::Corrupt("tree")));
+  }
+}
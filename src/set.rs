@@ -0,0 +1,241 @@
+use crate::error::Error;
+use crate::pikevm::PikeVM;
+use crate::program::Program;
+use crate::regex::Match;
+use crate::regex::RegEx;
+use crate::serialize;
+use crate::serialize::Kind;
+use crate::serialize::LoadError;
+
+/// Many expressions that are matched against a haystack together.
+///
+/// All of the expressions are compiled into a single automaton, so finding
+/// out which of them match takes one pass over the haystack no matter how
+/// many there are. A pattern matches if its language occurs anywhere in the
+/// haystack.
+pub struct RegExSet {
+  regexes: Vec<RegEx>,
+  prog: Program,
+}
+
+/// Which patterns of a `RegExSet` matched, by index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetMatches {
+  matched: Vec<bool>,
+}
+
+impl SetMatches {
+  /// Whether pattern i matched.
+  pub fn matched(&self, i: usize) -> bool {
+    return self.matched[i];
+  }
+
+  /// Whether any pattern matched.
+  pub fn matched_any(&self) -> bool {
+    return self.matched.contains(&true);
+  }
+
+  /// Number of patterns in the set (not the number that matched).
+  pub fn len(&self) -> usize {
+    return self.matched.len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.matched.is_empty();
+  }
+
+  /// The index of every pattern that matched, in order.
+  pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+    return self.matched.iter()
+                       .enumerate()
+                       .filter(|(_, m)| **m)
+                       .map(|(i, _)| i);
+  }
+}
+
+/// A match of one pattern of a `RegExSet`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetMatch<'h> {
+  /// Index of the pattern that matched.
+  pub pattern: usize,
+  pub m: Match<'h>,
+}
+
+impl RegExSet {
+  /// Parse every expression, or return the first problem with any of them.
+  pub fn new<I, S>(exprs: I) -> Result<Self, Error>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+    let regexes = exprs.into_iter()
+                       .map(|e| RegEx::new(e.as_ref()))
+                       .collect::<Result<Vec<RegEx>, Error>>()?;
+    let trees: Vec<_> = regexes.iter().map(|r| &r.inner.tree).collect();
+    let prog = Program::compile_many(&trees);
+    return Ok(RegExSet { regexes, prog });
+  }
+
+  /// Every pattern and the program that matches them all as bytes, see
+  /// `RegEx::to_bytes`.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    return serialize::save(Kind::Set, |w| {
+      w.usize(self.regexes.len());
+      for r in &self.regexes {
+        w.regex(&r.parts());
+      }
+      w.program(&self.prog);
+    });
+  }
+
+  /// Load a set saved by `to_bytes`, checking the bytes are valid.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+    let mut r = serialize::Reader::new(bytes, Kind::Set)?;
+    let len = r.usize()?;
+    let mut regexes = vec![];
+    for _ in 0..len {
+      regexes.push(RegEx::load(r.regex()?)?);
+    }
+    // a Save for a group of any of the patterns
+    let groups = regexes.iter().map(|r| r.captures_len()).max().unwrap_or(0);
+    let mut prog = r.program(groups)?;
+    r.finish()?;
+    let trees: Vec<_> = regexes.iter().map(|r| &r.inner.tree).collect();
+    if !prog.check(&trees) {
+      return Err(LoadError::Corrupt("program"));
+    }
+    return Ok(RegExSet { regexes, prog });
+  }
+
+  /// Number of patterns in the set.
+  pub fn len(&self) -> usize {
+    return self.regexes.len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.regexes.is_empty();
+  }
+
+  /// The RegEx for pattern i.
+  pub fn get(&self, i: usize) -> &RegEx {
+    return &self.regexes[i];
+  }
+
+  /// Whether any pattern matches. Stops as soon as one does.
+  pub fn is_match(&self, haystack: &str) -> bool {
+    let mut matched = vec![false; self.len()];
+    PikeVM::new(&self.prog).which_match(haystack, &mut matched, true);
+    return matched.contains(&true);
+  }
+
+  /// Which patterns match, found in a single pass over the haystack.
+  pub fn matches(&self, haystack: &str) -> SetMatches {
+    let mut matched = vec![false; self.len()];
+    PikeVM::new(&self.prog).which_match(haystack, &mut matched, false);
+    return SetMatches { matched };
+  }
+
+  /// Every match of the chosen patterns, ordered by where they begin (and by
+  /// pattern index for matches that begin at the same place). Each pattern
+  /// is searched on its own, so only ask for the patterns you need
+  /// (e.g.: the ones given by `matches`).
+  pub fn find_subset<'h, I>(&self, haystack: &'h str, patterns: I)
+    -> Vec<SetMatch<'h>>
+    where I: IntoIterator<Item = usize> {
+    let mut found = vec![];
+    for pattern in patterns {
+      for m in self.regexes[pattern].find_iter(haystack) {
+        found.push(SetMatch { pattern, m });
+      }
+    }
+
+    found.sort_by_key(|s| (s.m.start(), s.pattern));
+    return found;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn matches_which_patterns() {
+    let set = RegExSet::new(["ERROR", "WARN(ING)?", "\\d+ms", "panic"]).unwrap();
+    let m = set.matches("WARNING: took 250ms");
+
+    assert_eq!(m.len(), 4);
+    assert!(!m.matched(0));
+    assert!(m.matched(1));
+    assert!(m.matched(2));
+    assert!(!m.matched(3));
+    assert_eq!(m.iter().collect::<Vec<usize>>(), vec![1, 2]);
+  }
+
+  #[test]
+  fn matches_nothing() {
+    let set = RegExSet::new(["a+", "b"]).unwrap();
+    let m = set.matches("xyz");
+    assert!(!m.matched_any());
+    assert!(!set.is_match("xyz"));
+    assert!(set.is_match("xxbxx"));
+  }
+
+  #[test]
+  fn matches_empty_patterns_and_haystack() {
+    let set = RegExSet::new(["x*", "y"]).unwrap();
+    let m = set.matches("");
+    assert!(m.matched(0));
+    assert!(!m.matched(1));
+  }
+
+  #[test]
+  fn matches_unicode() {
+    let set = RegExSet::new(["\\u2603+", "[^a]", "a"]).unwrap();
+    let m = set.matches("\u{e9}\u{2603}");
+    assert_eq!(m.iter().collect::<Vec<usize>>(), vec![0, 1]);
+  }
+
+  #[test]
+  fn matches_anchors() {
+    let set = RegExSet::new(["^b", "a$", "(?m)^b", "(?m)a$", "^$"]).unwrap();
+    let m = set.matches("ab\nba");
+    assert_eq!(m.iter().collect::<Vec<usize>>(), vec![1, 2, 3]);
+    assert!(set.matches("").matched(4));
+  }
+
+  #[test]
+  fn matches_many_patterns() {
+    let words: Vec<String> = (0..200).map(|i| format!("w{}x", i)).collect();
+    let set = RegExSet::new(&words).unwrap();
+    let m = set.matches("__w17x__w199x__");
+    assert_eq!(m.iter().collect::<Vec<usize>>(), vec![17, 199]);
+  }
+
+  #[test]
+  fn empty_set() {
+    let set = RegExSet::new(Vec::<String>::new()).unwrap();
+    assert!(set.is_empty());
+    assert!(!set.is_match("abc"));
+  }
+
+  #[test]
+  fn find_subset_positions() {
+    let set = RegExSet::new(["\\d+", "[a-z]+", "!"]).unwrap();
+    let found = set.find_subset("ab 12 cd!", set.matches("ab 12 cd!").iter()
+                                                .filter(|i| *i != 2));
+    let found: Vec<(usize, &str)> = found.iter()
+                                         .map(|s| (s.pattern, s.m.as_str()))
+                                         .collect();
+    assert_eq!(found, vec![(1, "ab"), (0, "12"), (1, "cd")]);
+  }
+
+  #[test]
+  fn to_bytes_round_trip() {
+    let set = RegExSet::new(["ERROR", "WARN(ING)?", "\\d+ms"]).unwrap();
+    let loaded = RegExSet::from_bytes(&set.to_bytes()).unwrap();
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded.matches("WARNING: took 250ms").iter()
+                     .collect::<Vec<usize>>(), vec![1, 2]);
+    assert_eq!(loaded.get(1).find("WARN!").unwrap().as_str(), "WARN");
+    assert_eq!(RegEx::from_bytes(&set.to_bytes()).err(),
+               Some(LoadError::WrongKind));
+  }
+}
@@ -0,0 +1,548 @@
+use crate::regex::Captures;
+use crate::regex::RegEx;
+use crate::replace::Replacer;
+use std::error;
+use std::fmt;
+
+/// A replacement template that has been parsed and checked against a RegEx
+/// once, so it can be applied to many matches.
+///
+/// On top of `$1`, `${1}`, `${name}` and `$$` (see `replace::expand`):
+///
+/// - `\U`, `\L`: upper/lower case everything up to the next `\E`
+/// - `\u`, `\l`: upper/lower case the next character
+/// - `${1:+present:absent}`: `present` if group 1 participated in the match,
+///   otherwise `absent` (which may be left out: `${1:+present}`)
+/// - `${1:-default}`: group 1, or `default` if it didn't participate
+/// - `${1:>8}`: group 1 padded to 8 characters, aligned `<` (left),
+///   `>` (right) or `^` (center), with an optional fill character first
+///   (e.g.: `${1:0>8}`)
+/// - `\\`, `\$`, `\:`, `\}`: literal characters
+///
+/// Conditional and default texts are templates themselves. Referencing a
+/// group that the expression doesn't have is an error.
+#[derive(Debug)]
+pub struct Template {
+  pieces: Vec<Piece>,
+}
+
+#[derive(Debug)]
+enum Piece {
+  Literal(String),
+  Group(usize, Format),
+  // group, text if it participated, text if it didn't
+  Conditional(usize, Vec<Piece>, Vec<Piece>),
+  Case(CaseOp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaseOp {
+  Upper,     // \U
+  Lower,     // \L
+  End,       // \E
+  UpperNext, // \u
+  LowerNext, // \l
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+  Left,
+  Right,
+  Center,
+}
+
+#[derive(Debug)]
+struct Format {
+  fill: char,
+  align: Align,
+  width: usize,
+}
+
+impl Format {
+  fn none() -> Self {
+    return Format { fill: ' ', align: Align::Left, width: 0 };
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateErrorKind {
+  /// A group number the expression doesn't have.
+  UnknownGroup(usize),
+  /// A group name the expression doesn't have.
+  UnknownGroupName(String),
+  /// `${` that isn't followed by a group number or name.
+  InvalidReference,
+  /// `${` without a closing `}`.
+  UnclosedReference,
+  /// A width/padding specifier that can't be understood.
+  InvalidFormat,
+  /// A backslash followed by a character with no meaning.
+  InvalidEscape(char),
+  /// A backslash at the very end of the template.
+  TrailingBackslash,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+  pub kind: TemplateErrorKind,
+  /// Byte offset in the template where the problem begins.
+  pub position: usize,
+}
+
+impl fmt::Display for TemplateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.kind {
+      TemplateErrorKind::UnknownGroup(n) => {
+        write!(f, "template refers to group {}, which doesn't exist", n)?;
+      },
+      TemplateErrorKind::UnknownGroupName(name) => {
+        write!(f, "template refers to group '{}', which doesn't exist",
+               name)?;
+      },
+      TemplateErrorKind::InvalidReference => {
+        write!(f, "expected a group number or name after '${{'")?;
+      },
+      TemplateErrorKind::UnclosedReference => {
+        write!(f, "missing '}}' to close '${{'")?;
+      },
+      TemplateErrorKind::InvalidFormat => {
+        write!(f, "invalid width or padding")?;
+      },
+      TemplateErrorKind::InvalidEscape(c) => {
+        write!(f, "invalid escape '\\{}'", c)?;
+      },
+      TemplateErrorKind::TrailingBackslash => {
+        write!(f, "template ends with '\\'")?;
+      },
+    }
+    return write!(f, " at position {}", self.position);
+  }
+}
+
+impl error::Error for TemplateError {}
+
+struct TemplateParser<'t> {
+  template: &'t str,
+  index: usize,
+  group_names: Vec<Option<&'t str>>,
+}
+
+impl<'t> TemplateParser<'t> {
+  fn peek(&self) -> Option<char> {
+    return self.template[self.index..].chars().next();
+  }
+
+  fn error(&self, kind: TemplateErrorKind, position: usize) -> TemplateError {
+    return TemplateError { kind, position };
+  }
+
+  // parse pieces until the end of the template, or one of the characters in
+  // stop (which is left unconsumed)
+  fn parse_pieces(&mut self, stop: &[char])
+    -> Result<Vec<Piece>, TemplateError> {
+    let mut pieces = vec![];
+    let mut literal = String::new();
+
+    while let Some(c) = self.peek() {
+      if stop.contains(&c) {
+        break;
+      }
+
+      let piece = match c {
+        '$' => self.parse_reference()?,
+        '\\' => self.parse_escape()?,
+        _ => {
+          self.index += c.len_utf8();
+          literal.push(c);
+          continue;
+        },
+      };
+
+      // keep runs of literal text together
+      match piece {
+        Piece::Literal(s) => { literal.push_str(&s); },
+        _ => {
+          if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+          }
+          pieces.push(piece);
+        },
+      }
+    }
+
+    if !literal.is_empty() {
+      pieces.push(Piece::Literal(literal));
+    }
+    return Ok(pieces);
+  }
+
+  fn parse_escape(&mut self) -> Result<Piece, TemplateError> {
+    let start = self.index;
+    self.index += 1; // skip '\'
+
+    let c = match self.peek() {
+      Some(c) => c,
+      None => {
+        return Err(self.error(TemplateErrorKind::TrailingBackslash, start));
+      },
+    };
+    self.index += c.len_utf8();
+
+    return match c {
+      'U' => Ok(Piece::Case(CaseOp::Upper)),
+      'L' => Ok(Piece::Case(CaseOp::Lower)),
+      'E' => Ok(Piece::Case(CaseOp::End)),
+      'u' => Ok(Piece::Case(CaseOp::UpperNext)),
+      'l' => Ok(Piece::Case(CaseOp::LowerNext)),
+      '\\' | '$' | ':' | '}' => Ok(Piece::Literal(String::from(c))),
+      _ => Err(self.error(TemplateErrorKind::InvalidEscape(c), start)),
+    };
+  }
+
+  fn parse_reference(&mut self) -> Result<Piece, TemplateError> {
+    let start = self.index;
+    self.index += 1; // skip '$'
+
+    match self.peek() {
+      // $$
+      Some('$') => {
+        self.index += 1;
+        return Ok(Piece::Literal(String::from("$")));
+      },
+      // ${...}
+      Some('{') => {
+        self.index += 1;
+        return self.parse_braced(start);
+      },
+      // $n
+      Some(c) if c.is_ascii_digit() => {
+        let digits = self.take_word(|c| c.is_ascii_digit());
+        let group = self.resolve(digits, start)?;
+        return Ok(Piece::Group(group, Format::none()));
+      },
+      // not a reference, keep the '$'
+      _ => {
+        return Ok(Piece::Literal(String::from("$")));
+      },
+    }
+  }
+
+  fn parse_braced(&mut self, start: usize) -> Result<Piece, TemplateError> {
+    let word = self.take_word(|c| c.is_ascii_alphanumeric() || c == '_');
+    if word.is_empty() {
+      return Err(self.error(TemplateErrorKind::InvalidReference, start));
+    }
+    let group = self.resolve(word, start)?;
+
+    let piece = match self.peek() {
+      Some('}') => Piece::Group(group, Format::none()),
+      Some(':') => {
+        self.index += 1;
+        match self.peek() {
+          // ${n:+present:absent}
+          Some('+') => {
+            self.index += 1;
+            let present = self.parse_pieces(&[':', '}'])?;
+            let mut absent = vec![];
+            if self.peek() == Some(':') {
+              self.index += 1;
+              absent = self.parse_pieces(&['}'])?;
+            }
+            Piece::Conditional(group, present, absent)
+          },
+          // ${n:-default}
+          Some('-') => {
+            self.index += 1;
+            let default = self.parse_pieces(&['}'])?;
+            Piece::Conditional(group,
+                               vec![Piece::Group(group, Format::none())],
+                               default)
+          },
+          // ${n:spec}
+          _ => Piece::Group(group, self.parse_format()?),
+        }
+      },
+      Some(_) => {
+        return Err(self.error(TemplateErrorKind::InvalidReference, start));
+      },
+      None => {
+        return Err(self.error(TemplateErrorKind::UnclosedReference, start));
+      },
+    };
+
+    if self.peek() != Some('}') {
+      return Err(self.error(TemplateErrorKind::UnclosedReference, start));
+    }
+    self.index += 1;
+
+    return Ok(piece);
+  }
+
+  // [[fill]align]width
+  fn parse_format(&mut self) -> Result<Format, TemplateError> {
+    let start = self.index;
+    let to_align = |c: char| match c {
+      '<' => Some(Align::Left),
+      '>' => Some(Align::Right),
+      '^' => Some(Align::Center),
+      _ => None,
+    };
+
+    let mut format = Format::none();
+    let mut chars = self.template[self.index..].chars();
+    let first = chars.next();
+    let second = chars.next();
+    match (first, second) {
+      (Some(fill), Some(a)) if to_align(a).is_some() && fill != '}' => {
+        format.fill = fill;
+        format.align = to_align(a).unwrap();
+        self.index += fill.len_utf8() + 1;
+      },
+      (Some(a), _) if to_align(a).is_some() => {
+        format.align = to_align(a).unwrap();
+        self.index += 1;
+      },
+      _ => {
+        // no fill or alignment, only a width
+      },
+    }
+
+    let digits = self.take_word(|c| c.is_ascii_digit());
+    format.width = match digits.parse::<usize>() {
+      Ok(w) => w,
+      Err(_) => {
+        return Err(self.error(TemplateErrorKind::InvalidFormat, start));
+      },
+    };
+
+    return Ok(format);
+  }
+
+  fn take_word(&mut self, f: impl Fn(char) -> bool) -> &'t str {
+    let rest = &self.template[self.index..];
+    let len = rest.find(|c: char| !f(c)).unwrap_or(rest.len());
+    self.index += len;
+    return &rest[..len];
+  }
+
+  // turn a group number or name into a group number the expression has
+  fn resolve(&self, word: &str, position: usize)
+    -> Result<usize, TemplateError> {
+    if let Ok(n) = word.parse::<usize>() {
+      if n >= self.group_names.len() {
+        return Err(self.error(TemplateErrorKind::UnknownGroup(n), position));
+      }
+      return Ok(n);
+    }
+
+    return match self.group_names.iter().position(|n| *n == Some(word)) {
+      Some(n) => Ok(n),
+      None => {
+        let kind = TemplateErrorKind::UnknownGroupName(String::from(word));
+        Err(self.error(kind, position))
+      },
+    };
+  }
+}
+
+// tracks \U, \L, \u and \l while the replacement is written
+struct CaseWriter<'d> {
+  dst: &'d mut String,
+  mode: Option<CaseOp>,
+  next: Option<CaseOp>,
+}
+
+impl<'d> CaseWriter<'d> {
+  fn apply(&mut self, op: CaseOp) {
+    match op {
+      CaseOp::Upper | CaseOp::Lower => { self.mode = Some(op); },
+      CaseOp::End => { self.mode = None; },
+      CaseOp::UpperNext | CaseOp::LowerNext => { self.next = Some(op); },
+    }
+  }
+
+  fn push_str(&mut self, s: &str) {
+    for c in s.chars() {
+      let op = self.next.take().or(self.mode);
+      match op {
+        Some(CaseOp::Upper) | Some(CaseOp::UpperNext) => {
+          self.dst.extend(c.to_uppercase());
+        },
+        Some(CaseOp::Lower) | Some(CaseOp::LowerNext) => {
+          self.dst.extend(c.to_lowercase());
+        },
+        _ => { self.dst.push(c); },
+      }
+    }
+  }
+}
+
+fn pad(s: &str, format: &Format) -> String {
+  let len = s.chars().count();
+  if len >= format.width {
+    return String::from(s);
+  }
+
+  let padding = format.width - len;
+  let (before, after) = match format.align {
+    Align::Left => (0, padding),
+    Align::Right => (padding, 0),
+    Align::Center => (padding / 2, padding - padding / 2),
+  };
+
+  let mut padded = String::new();
+  padded.extend(std::iter::repeat_n(format.fill, before));
+  padded.push_str(s);
+  padded.extend(std::iter::repeat_n(format.fill, after));
+  return padded;
+}
+
+impl Template {
+  /// Parse a template and check its group references against re.
+  pub fn new(re: &RegEx, template: &str) -> Result<Template, TemplateError> {
+    let mut parser = TemplateParser {
+      template,
+      index: 0,
+      group_names: re.capture_names().collect(),
+    };
+    let pieces = parser.parse_pieces(&[])?;
+    return Ok(Template { pieces });
+  }
+
+  /// Expand the template with caps and append the result to dst.
+  pub fn expand(&self, caps: &Captures<'_>, dst: &mut String) {
+    let mut writer = CaseWriter { dst, mode: None, next: None };
+    Template::expand_pieces(&self.pieces, caps, &mut writer);
+  }
+
+  fn expand_pieces(pieces: &[Piece], caps: &Captures<'_>,
+                   writer: &mut CaseWriter<'_>) {
+    for piece in pieces {
+      match piece {
+        Piece::Literal(s) => { writer.push_str(s); },
+        Piece::Group(group, format) => {
+          let s = caps.get(*group).map_or("", |m| m.as_str());
+          if format.width > 0 {
+            writer.push_str(&pad(s, format));
+          }
+          else {
+            writer.push_str(s);
+          }
+        },
+        Piece::Conditional(group, present, absent) => {
+          let branch = if caps.get(*group).is_some() { present } else { absent };
+          Template::expand_pieces(branch, caps, writer);
+        },
+        Piece::Case(op) => { writer.apply(*op); },
+      }
+    }
+  }
+}
+
+impl Replacer for &Template {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    self.expand(caps, dst);
+  }
+}
+
+impl Replacer for Template {
+  fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
+    self.expand(caps, dst);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn replace_all(expr: &str, template: &str, haystack: &str) -> String {
+    let r = RegEx::new(expr).unwrap();
+    let t = Template::new(&r, template).unwrap();
+    return r.replace_all(haystack, &t).into_owned();
+  }
+
+  fn template_error(expr: &str, template: &str) -> TemplateError {
+    let r = RegEx::new(expr).unwrap();
+    return Template::new(&r, template).unwrap_err();
+  }
+
+  #[test]
+  fn expand_plain_references() {
+    assert_eq!(replace_all("(\\w)(?<d>\\d)", "$2${1}${d}$$", "a1 b2"),
+               "1a1$ 2b2$");
+  }
+
+  #[test]
+  fn expand_case_ranges() {
+    assert_eq!(replace_all("(\\w+) (\\w+)", "\\U$1\\E-\\L$2 X\\E", "ab CD"),
+               "AB-cd x");
+  }
+
+  #[test]
+  fn expand_case_next_character() {
+    assert_eq!(replace_all("([a-z]+)_([a-z]+)", "$1\\u$2", "snake_case"),
+               "snakeCase");
+    assert_eq!(replace_all("\\w+", "\\l$0", "Hello World"), "hello world");
+    // a one character modifier wins over a range modifier
+    assert_eq!(replace_all("\\w+", "\\L\\u$0", "hELLO"), "Hello");
+  }
+
+  #[test]
+  fn expand_conditionals() {
+    let expr = "(a)?(b)";
+    assert_eq!(replace_all(expr, "${1:+yes:no}", "ab b"), "yes no");
+    assert_eq!(replace_all(expr, "${1:+[$1$2]}", "ab b"), "[ab] ");
+    assert_eq!(replace_all(expr, "${1:-none}", "ab b"), "a none");
+  }
+
+  #[test]
+  fn expand_nested_conditionals_with_escapes() {
+    assert_eq!(replace_all("(a)?(b)?c", "${1:+${2:+both\\:1\\}2:one}:none}",
+                           "abc ac bc"),
+               "both:1}2 one none");
+  }
+
+  #[test]
+  fn expand_padding() {
+    assert_eq!(replace_all("\\d+", "[${0:5}]", "42"), "[42   ]");
+    assert_eq!(replace_all("\\d+", "[${0:>5}]", "42"), "[   42]");
+    assert_eq!(replace_all("\\d+", "[${0:0>5}]", "42"), "[00042]");
+    assert_eq!(replace_all("\\d+", "[${0:*^5}]", "42"), "[*42**]");
+    assert_eq!(replace_all("\\d+", "[${0:>1}]", "42"), "[42]");
+  }
+
+  #[test]
+  fn error_unknown_groups() {
+    let e = template_error("(a)", "x$2");
+    assert_eq!(e.kind, TemplateErrorKind::UnknownGroup(2));
+    assert_eq!(e.position, 1);
+
+    let e = template_error("(?<a>a)", "${a}${b}");
+    assert_eq!(e.kind, TemplateErrorKind::UnknownGroupName(String::from("b")));
+    assert_eq!(e.position, 4);
+
+    let e = template_error("(a)", "${1:+$3}");
+    assert_eq!(e.kind, TemplateErrorKind::UnknownGroup(3));
+  }
+
+  #[test]
+  fn error_bad_syntax() {
+    assert_eq!(template_error("a", "${}").kind,
+               TemplateErrorKind::InvalidReference);
+    assert_eq!(template_error("a", "${0").kind,
+               TemplateErrorKind::UnclosedReference);
+    assert_eq!(template_error("a", "${0:x}").kind,
+               TemplateErrorKind::InvalidFormat);
+    assert_eq!(template_error("a", "\\q").kind,
+               TemplateErrorKind::InvalidEscape('q'));
+    assert_eq!(template_error("a", "a\\").kind,
+               TemplateErrorKind::TrailingBackslash);
+  }
+
+  #[test]
+  fn error_message() {
+    let e = template_error("(a)", "$1$7");
+    assert_eq!(e.to_string(),
+               "template refers to group 7, which doesn't exist at position 2");
+  }
+}
@@ -21,19 +21,33 @@ pub struct TextStyle<'a> {
 }
 
 impl TextStyle<'static> {
+  // escape sequence that returns text to the terminal's default style
+  pub const RESET: &'static str = "\x1b[0m";
+
   pub fn apply(&self) {
-    print!("\x1b[");
+    print!("{}", self.code());
+  }
+
+  // the escape sequence for this style, for text that isn't printed directly
+  pub fn code(&self) -> String {
+    let mut code = String::from("\x1b[");
 
     // make bold (if applicable)
     if self.bold {
-      print!("1;");
+      code.push_str("1;");
     }
 
     // set colors
-    print!("{}", self.foreground);
-    // print!(";");
+    code.push_str(self.foreground);
+    // code.push(';');
+
+    // code.push_str(self.background);
+    code.push('m');
+    return code;
+  }
 
-    // print!("{}", self.background);
-    print!("m");
+  // wrap text in this style, then reset it
+  pub fn paint(&self, text: &str) -> String {
+    return format!("{}{}{}", self.code(), text, Self::RESET);
   }
 }